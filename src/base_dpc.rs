@@ -42,4 +42,21 @@ pub trait DPC {
         output_path: &P,
     ) -> Result<(Vec<u32>, Vec<u32>)>;
     fn fmt_create<P: AsRef<Path>>(&self, input_path: &P, output_path: &P) -> Result<()>;
+    fn export<P: AsRef<Path>>(
+        &mut self,
+        input_path: &P,
+        output_path: &P,
+        export_type: &str,
+        crc32s: &[u32],
+        name_paths: &[&OsStr],
+    ) -> Result<()>;
+    fn font<P: AsRef<Path>>(
+        &mut self,
+        input_path: &P,
+        output_path: &P,
+        crc32: u32,
+        font_path: &P,
+        point_size: f32,
+        characters: &str,
+    ) -> Result<()>;
 }
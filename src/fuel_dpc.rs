@@ -37,7 +37,12 @@ use base_dpc::DPC;
 
 use crate::base_dpc;
 use crate::fuel_fmt;
+use crate::crc32::{AsoboCRC32, CRC32};
+use crate::fuel_fmt::common::{FUELObjectFormatTrait, FormatOptions};
+use crate::fuel_fmt::fonts::FontAtlas;
+use crate::fuel_fmt::database::ObjectDatabase;
 use crate::lz;
+use crate::names::NameDatabase;
 
 fn calculate_padded_size(unpadded_size: u32) -> u32 {
     return (unpadded_size + 0x7ff) & 0xfffff800;
@@ -117,7 +122,7 @@ impl Manifest {
 
 #[derive(NomLE, BinWrite, Clone, Copy, Debug, PartialEq, Eq)]
 #[binwrite(little)]
-struct ObjectHeader {
+pub(crate) struct ObjectHeader {
     data_size: u32,
     class_object_size: u32,
     decompressed_size: u32,
@@ -126,6 +131,39 @@ struct ObjectHeader {
     crc32: u32,
 }
 
+pub(crate) fn read_object<P: AsRef<Path>>(input_path: &P) -> Result<(ObjectHeader, Vec<u8>, Vec<u8>)> {
+    let mut input_file = File::open(input_path)?;
+
+    let mut object_header_buffer = [0; 24];
+    input_file.read(&mut object_header_buffer)?;
+
+    let object_header = match ObjectHeader::parse(&object_header_buffer) {
+        Ok((_, h)) => h,
+        Err(error) => panic!("{}", error),
+    };
+
+    let mut header = vec![0; object_header.class_object_size as usize];
+    input_file.read(&mut header)?;
+
+    let mut data = vec![0; object_header.decompressed_size as usize];
+
+    if object_header.compressed_size != 0 {
+        let mut compresssed_data = vec![0; object_header.compressed_size as usize];
+        input_file.read(&mut compresssed_data)?;
+        lz::lzrs_decompress(
+            &compresssed_data[..],
+            object_header.compressed_size as usize,
+            &mut data[..],
+            object_header.decompressed_size as usize,
+            false,
+        )?;
+    } else {
+        input_file.read(&mut data)?;
+    }
+
+    Ok((object_header, header, data))
+}
+
 #[derive(Serialize, NomLE, BinWrite, Clone, Copy, Debug, PartialEq, Eq)]
 #[binwrite(little)]
 struct PoolManifestHeader {
@@ -219,11 +257,123 @@ pub struct FuelDPC {
     unoptimized_pool: bool,
     no_pool: bool,
     sound_sample_rate: u32,
+    bitmap_png: bool,
+    bitmap_png_mips: bool,
+    node_transforms: bool,
+    node_sphere: bool,
+    node_check: bool,
+    probe_formats: bool,
     effective_version_string: String,
     version_lookup: HashMap<String, (u32, u32, u32)>,
     version: String,
 }
 
+impl FuelDPC {
+    fn format_version(&self) -> &String {
+        if self.version_lookup.contains_key(&self.version) {
+            &self.version
+        } else if self.options.is_unsafe {
+            &self.effective_version_string
+        } else {
+            panic!("Recursive option used with unsupported version. Use -u/--unsafe");
+        }
+    }
+
+    fn format_options(&self) -> FormatOptions {
+        FormatOptions {
+            bitmap_png: self.bitmap_png,
+            bitmap_png_mips: self.bitmap_png_mips,
+            sound_sample_rate: self.sound_sample_rate,
+            node_transforms: self.node_transforms,
+            node_sphere: self.node_sphere,
+            node_check: self.node_check,
+        }
+    }
+
+    // Formats are tried newest version first, a wrong variant may fail to parse or
+    // parse without packing back to the same bytes. Each format is tried once however many versions share it
+    // and the configured version's format is used when none of them round trips.
+    fn probe_format(
+        &self,
+        class_crc32: u32,
+        header: &[u8],
+        data: &[u8],
+        output_path: &Path,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        let format_options = self.format_options();
+        let mut tried: HashSet<&'static str> = HashSet::new();
+
+        for version in fuel_fmt::FORMAT_VERSIONS.iter() {
+            let formats = fuel_fmt::get_formats(&version.to_string());
+            let fuel_object_format = match formats.get(&class_crc32) {
+                Some(fuel_object_format) => *fuel_object_format,
+                None => continue,
+            };
+            if !tried.insert(fuel_object_format.format_name()) {
+                continue;
+            }
+
+            if let Ok(true) = round_trips(fuel_object_format, header, data, &format_options) {
+                let references = fuel_object_format.unpack(header, data, output_path, &format_options)?;
+                record_format_version(output_path, version)?;
+                return Ok(references);
+            }
+        }
+
+        match fuel_fmt::get_formats(self.format_version()).get(&class_crc32) {
+            Some(fuel_object_format) => {
+                if !self.options.is_quiet {
+                    println!("Warn: no format packs the object back to the same bytes, using the configured version's");
+                }
+                fuel_object_format.unpack(header, data, output_path, &format_options)
+            }
+            None => Err(Error::other("unsupported format")),
+        }
+    }
+}
+
+fn round_trips(
+    fuel_object_format: &dyn FUELObjectFormatTrait,
+    header: &[u8],
+    data: &[u8],
+    format_options: &FormatOptions,
+) -> Result<bool> {
+    let tmp_dir = TempDir::new("dpc")?;
+    fuel_object_format.unpack(header, data, tmp_dir.path(), format_options)?;
+    let mut packed_header: Vec<u8> = Vec::new();
+    let mut packed_body: Vec<u8> = Vec::new();
+    fuel_object_format.pack(tmp_dir.path(), &mut packed_header, &mut packed_body, format_options)?;
+    Ok(packed_header == header && packed_body == data)
+}
+
+fn record_format_version(object_path: &Path, version: &str) -> Result<()> {
+    let json_path = object_path.join("object.json");
+    let mut object: serde_json::Value = serde_json::from_reader(File::open(&json_path)?)?;
+    match object.as_object_mut() {
+        Some(object) => {
+            object.insert("format_version".to_string(), serde_json::Value::from(version));
+        }
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} isn't a JSON object", json_path.display()),
+            ))
+        }
+    }
+    fs::write(json_path, serde_json::to_string_pretty(&object)?)
+}
+
+fn recorded_format_version(object_path: &Path) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Object {
+        format_version: Option<String>,
+    }
+
+    let json_file = File::open(object_path.join("object.json")).ok()?;
+    let object: Object = serde_json::from_reader(json_file).ok()?;
+    object.format_version
+}
+
 impl DPC for FuelDPC {
     fn new(options: &Options, custom_args: &Vec<&OsStr>) -> FuelDPC {
         let matches = App::new("fuel dpc backend")
@@ -246,12 +396,46 @@ impl DPC for FuelDPC {
                 Arg::with_name("SOUND-SAMPLE-RATE")
                     .short("s")
                     .long("sound-sample-rate")
+                    .takes_value(true)
                     .help("Default sample rate to use for sounds"),
             )
+            .arg(
+                Arg::with_name("BITMAP-PNG")
+                    .long("bitmap-png")
+                    .help("Also write bitmaps as PNG when extracting, cube map faces and volume slices get their own PNG"),
+            )
+            .arg(
+                Arg::with_name("BITMAP-PNG-MIPS")
+                    .long("bitmap-png-mips")
+                    .requires("BITMAP-PNG")
+                    .help("Write every mip level of a bitmap as its own PNG"),
+            )
+            .arg(
+                Arg::with_name("NODE-TRANSFORMS")
+                    .long("node-transforms")
+                    .help("Recompute the reciprocal scale and world transform of nodes when creating"),
+            )
+            .arg(
+                Arg::with_name("NODE-SPHERE")
+                    .long("node-sphere")
+                    .requires("NODE-TRANSFORMS")
+                    .help("Also recompute the bounding sphere of nodes from their meshes"),
+            )
+            .arg(
+                Arg::with_name("NODE-CHECK")
+                    .long("node-check")
+                    .help("Report nodes whose stored transforms don't match their local transform when creating"),
+            )
+            .arg(
+                Arg::with_name("PROBE-FORMATS")
+                    .long("probe-formats")
+                    .help("Extract each object with the first version's format that packs it back to the same bytes and record that version in its object.json"),
+            )
             .arg(
                 Arg::with_name("EFFECTIVE-VERSION-STRING")
                     .short("T")
                     .long("effective-version-string")
+                    .takes_value(true)
                     .help("Version string to compare against"),
             )
             .settings(&[AppSettings::NoBinaryName])
@@ -295,6 +479,12 @@ impl DPC for FuelDPC {
             unoptimized_pool: matches.is_present("UNOPTIMIZED-POOL"),
             no_pool: matches.is_present("NO-POOL"),
             sound_sample_rate: matches.value_of("SOUND-SAMPLE-RATE").unwrap_or("44100").parse::<u32>().unwrap_or(44100),
+            bitmap_png: matches.is_present("BITMAP-PNG"),
+            bitmap_png_mips: matches.is_present("BITMAP-PNG-MIPS"),
+            node_transforms: matches.is_present("NODE-TRANSFORMS"),
+            node_sphere: matches.is_present("NODE-SPHERE"),
+            node_check: matches.is_present("NODE-CHECK"),
+            probe_formats: matches.is_present("PROBE-FORMATS"),
             effective_version_string: matches.value_of("EFFECTIVE-VERSION-STRING").unwrap_or("v1.381.67.09 - Asobo Studio - Internal Cross Technology").to_string(),
             version_lookup: version_lookup,
             version: String::from("v1.381.67.09 - Asobo Studio - Internal Cross Technology"),
@@ -1665,49 +1855,25 @@ impl DPC for FuelDPC {
     ) -> Result<(Vec<u32>, Vec<u32>)> {
         fs::create_dir_all(output_path)?;
 
-        let mut input_file = File::open(input_path)?;
-
-        let mut object_header_buffer = [0; 24];
-        input_file.read(&mut object_header_buffer)?;
-
-        let object_header = match ObjectHeader::parse(&object_header_buffer) {
-            Ok((_, h)) => h,
-            Err(error) => panic!("{}", error),
-        };
+        let (object_header, header, data) = read_object(input_path)?;
 
         println!("{}", &object_header.crc32);
 
-        let use_version = if self.version_lookup.contains_key(&self.version) {
-            &self.version
-        } else if self.options.is_unsafe {
-            &self.effective_version_string
-        } else {
-            panic!("Recursive option used with unsupported version. Use -u/--unsafe");
-        };
+        if self.probe_formats {
+            return self.probe_format(object_header.class_crc32, &header[..], &data[..], output_path.as_ref());
+        }
+
+        let use_version = self.format_version();
 
         if let Some(fuel_object_format) =
             fuel_fmt::get_formats(use_version).get(&object_header.class_crc32)
         {
-            let mut header = vec![0; object_header.class_object_size as usize];
-            input_file.read(&mut header)?;
-
-            let mut data = vec![0; object_header.decompressed_size as usize];
-
-            if object_header.compressed_size != 0 {
-                let mut compresssed_data = vec![0; object_header.compressed_size as usize];
-                input_file.read(&mut compresssed_data)?;
-                lz::lzrs_decompress(
-                    &compresssed_data[..],
-                    object_header.compressed_size as usize,
-                    &mut data[..],
-                    object_header.decompressed_size as usize,
-                    false,
-                )?;
-            } else {
-                input_file.read(&mut data)?;
-            }
-
-            return fuel_object_format.unpack(&header[..], &data[..], output_path.as_ref());
+            return fuel_object_format.unpack(
+                &header[..],
+                &data[..],
+                output_path.as_ref(),
+                &self.format_options(),
+            );
         }
 
         return Err(Error::new(ErrorKind::Other, "unsupported format"));
@@ -1782,28 +1948,32 @@ impl DPC for FuelDPC {
             class_crc32 = class_name.parse::<u32>().unwrap();
         }
 
-        let use_version = if self.version_lookup.contains_key(&self.version) {
-            &self.version
-        } else if self.options.is_unsafe {
-            &self.effective_version_string
-        } else {
-            panic!("Recursive option used with unsupported version. Use -u/--unsafe");
+        // Probed objects are packed with the version they were extracted with
+        let recorded_version = recorded_format_version(input_path.as_ref());
+        let use_version = match &recorded_version {
+            Some(version) => version,
+            None => self.format_version(),
         };
 
         if let Some(fuel_object_format) = fuel_fmt::get_formats(use_version).get(&class_crc32) {
             let mut header: Vec<u8> = Vec::new();
             let mut body: Vec<u8> = Vec::new();
-            let res = fuel_object_format.pack(input_path.as_ref(), &mut header, &mut body);
-            if res.is_err() {
+            let res = fuel_object_format.pack(
+                input_path.as_ref(),
+                &mut header,
+                &mut body,
+                &self.format_options(),
+            );
+            if let Err(error) = res {
                 if !self.options.is_unsafe {
                     panic!(
-                        "Object parser failed. run again with -u/--unsafe to skip errors. {}",
-                        crc32
+                        "Object parser failed. run again with -u/--unsafe to skip errors. {}: {}",
+                        crc32, error
                     );
                 }
 
                 if !self.options.is_quiet {
-                    println!("Warn: object parser failed {}", crc32);
+                    println!("Warn: object parser failed {}: {}", crc32, error);
                 }
             }
 
@@ -1825,6 +1995,132 @@ impl DPC for FuelDPC {
 
         Ok(())
     }
+
+    fn export<P: AsRef<Path>>(
+        &mut self,
+        input_path: &P,
+        output_path: &P,
+        export_type: &str,
+        crc32s: &[u32],
+        name_paths: &[&OsStr],
+    ) -> Result<()> {
+        let manifest_file = File::open(input_path.as_ref().join("manifest.json"))?;
+        let manifest_json: Manifest = serde_json::from_reader(manifest_file)?;
+
+        self.version = manifest_json.header.version_string;
+        // Bail out early on versions the object formats don't support
+        self.format_version();
+
+        let mut names = NameDatabase::new();
+        for name_path in name_paths.iter() {
+            names.load(name_path)?;
+        }
+
+        let database = ObjectDatabase::new(
+            &input_path.as_ref().join("objects"),
+            names,
+        )?;
+
+        fuel_fmt::export(&database, export_type, crc32s, output_path.as_ref())
+    }
+
+    fn font<P: AsRef<Path>>(
+        &mut self,
+        input_path: &P,
+        output_path: &P,
+        crc32: u32,
+        font_path: &P,
+        point_size: f32,
+        characters: &str,
+    ) -> Result<()> {
+        let manifest_file = File::open(input_path.as_ref().join("manifest.json"))?;
+        let manifest_json: Manifest = serde_json::from_reader(manifest_file)?;
+
+        self.version = manifest_json.header.version_string.clone();
+        self.format_version();
+
+        let database = ObjectDatabase::new(
+            &input_path.as_ref().join("objects"),
+            NameDatabase::new(),
+        )?;
+
+        let atlas = FontAtlas::rasterize(font_path.as_ref(), point_size, characters)?;
+
+        // The new objects are unpacked copies of the template font's so they keep its version and settings
+        let (template_material_crc32, template_bitmap_crc32) =
+            fuel_fmt::fonts::template_crc32s(&database, crc32)?;
+
+        let name = match font_path.as_ref().file_stem().and_then(|x| x.to_str()) {
+            Some(name) => name.to_lowercase(),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Can't name the font after {}", font_path.as_ref().display()),
+                ))
+            }
+        };
+        let material_name = format!("{}_material", name);
+        let bitmap_name = format!("{}_bitmap", name);
+        let material_crc32 = AsoboCRC32 {}.hash(material_name.as_bytes());
+        let bitmap_crc32 = AsoboCRC32 {}.hash(bitmap_name.as_bytes());
+
+        // The Fonts_Z replaces the template so everything referencing the template uses the new font
+        let font_file_name = match database.path(crc32)?.file_name().and_then(|x| x.to_str()) {
+            Some(file_name) => file_name.to_string(),
+            None => return Err(Error::new(ErrorKind::InvalidData, format!("Bad path for crc32: {}", crc32))),
+        };
+        let objects = [
+            (crc32, font_file_name),
+            (template_material_crc32, format!("{}_{}.Material_Z", material_crc32, material_name)),
+            (template_bitmap_crc32, format!("{}_{}.Bitmap_Z", bitmap_crc32, bitmap_name)),
+        ];
+
+        let tmp_dir = TempDir::new("dpc").expect("Failed to create temp_dir");
+        let mut object_paths: Vec<PathBuf> = Vec::new();
+        for (template_crc32, file_name) in objects.iter() {
+            let object_path = tmp_dir.path().join(format!("{}.d", file_name));
+            self.fmt_extract(database.path(*template_crc32)?, &object_path)?;
+            object_paths.push(object_path);
+        }
+
+        atlas.write_bitmap(&object_paths[2])?;
+        fuel_fmt::fonts::retarget_material(&object_paths[1], bitmap_crc32)?;
+        atlas.write_fonts(&object_paths[0], material_crc32)?;
+
+        let objects_path = output_path.as_ref().join("objects");
+        fs::create_dir_all(&objects_path)?;
+        for (object_path, (_, file_name)) in object_paths.iter().zip(objects.iter()) {
+            self.fmt_create(object_path, &objects_path.join(file_name))?;
+        }
+
+        // The new material and bitmap are packed in the template font's blocks, before the font that uses them
+        let manifest_path = output_path.as_ref().join("manifest.json");
+        let mut manifest_json: Manifest = match File::open(&manifest_path) {
+            Ok(manifest_file) => serde_json::from_reader(manifest_file)?,
+            Err(_) => manifest_json,
+        };
+        for block in manifest_json.blocks.iter_mut() {
+            let font_index = match block.objects.iter().position(|x| x.crc32 == crc32) {
+                Some(font_index) => font_index,
+                None => continue,
+            };
+            let font_compress = block.objects[font_index].compress;
+            for (template_crc32, new_crc32) in [(template_material_crc32, material_crc32), (template_bitmap_crc32, bitmap_crc32)] {
+                if block.objects.iter().any(|x| x.crc32 == new_crc32) {
+                    continue;
+                }
+                let compress = block
+                    .objects
+                    .iter()
+                    .find(|x| x.crc32 == template_crc32)
+                    .map_or(font_compress, |x| x.compress);
+                block.objects.insert(font_index, ObjectDescription { crc32: new_crc32, compress });
+            }
+        }
+        File::create(manifest_path)?.write_all(serde_json::to_string_pretty(&manifest_json)?.as_bytes())?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1840,11 +2136,11 @@ mod test {
 
     use crate::base_dpc::Options;
     use crate::base_dpc::DPC;
-    use crate::fuel_dpc::{FuelDPC, ObjectHeader};
+    use crate::fuel_dpc::{record_format_version, recorded_format_version, FuelDPC, ObjectHeader};
     use nom_derive::Parse;
     use std::fs;
     use std::fs::File;
-    use std::io::{Read, Seek, SeekFrom};
+    use std::io::{ErrorKind, Read, Seek, SeekFrom};
 
     #[test_resources("D:/SteamLibrary/steamapps/common/FUEL/**/*.DPC")]
     fn test_fuel_dpc_validate(path: &str) {
@@ -2098,4 +2394,70 @@ mod test {
 
         tmp_dir.close().expect("Failed to delete temp_dir");
     }
+
+    #[test_resources("D:/SteamLibrary/steamapps/common/FUEL/**/*.DPC")]
+    fn test_fuel_dpc_fmt(path: &str) {
+        let mut dpc = FuelDPC::new(
+            &Options {
+                is_quiet: true,
+                is_force: true,
+                is_unsafe: false,
+                is_lz: true,
+                is_optimization: false,
+                is_recursive: false,
+            },
+            &vec![],
+        );
+
+        let tmp_dir = TempDir::new("dpc").expect("Failed to create temp_dir");
+
+        let dpc_file = Path::new(path);
+        let dpc_directory = tmp_dir.path().join("TEMP");
+        let object_file_2 = tmp_dir.path().join("TEMP.object");
+
+        dpc.extract(&dpc_file, &dpc_directory.as_path()).unwrap();
+
+        // Every object with a format packs back to the same bytes on its own
+        for entry in fs::read_dir(dpc_directory.join("objects")).unwrap() {
+            let object_file = entry.unwrap().path();
+            let mut object_directory = object_file.clone().into_os_string();
+            object_directory.push(".d");
+            let object_directory = Path::new(&object_directory).to_path_buf();
+
+            match dpc.fmt_extract(&object_file, &object_directory) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::Other => continue,
+                Err(e) => panic!("{}: {}", object_file.display(), e),
+            }
+            dpc.fmt_create(&object_directory, &object_file_2).unwrap();
+
+            assert_eq!(
+                hash_file(object_file.as_path(), Algorithm::SHA1),
+                hash_file(object_file_2.as_path(), Algorithm::SHA1),
+                "{}",
+                object_file.display()
+            );
+        }
+
+        tmp_dir.close().expect("Failed to delete temp_dir");
+    }
+
+    #[test]
+    fn test_record_format_version() {
+        let tmp_dir = TempDir::new("dpc").expect("Failed to create temp_dir");
+        let json = r#"{ "header": { "crc32s": [1, 2] }, "body": { "text": "{ not the start" } }"#;
+        fs::write(tmp_dir.path().join("object.json"), json).unwrap();
+
+        record_format_version(tmp_dir.path(), "v1.381.67.09 - Asobo Studio - Internal Cross Technology").unwrap();
+        assert_eq!(
+            recorded_format_version(tmp_dir.path()).as_deref(),
+            Some("v1.381.67.09 - Asobo Studio - Internal Cross Technology")
+        );
+        let object: serde_json::Value =
+            serde_json::from_reader(File::open(tmp_dir.path().join("object.json")).unwrap()).unwrap();
+        assert_eq!(object["body"]["text"], "{ not the start");
+        assert_eq!(object["header"]["crc32s"][1], 2);
+
+        tmp_dir.close().expect("Failed to delete temp_dir");
+    }
 }
@@ -1,10 +1,25 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
 use binwrite::BinWrite;
-use nom_derive::NomLE;
+use glam::Quat as GlamQuat;
+use gltf_json::accessor::Type;
+use gltf_json::animation::{Channel, Interpolation, Property, Sampler, Target};
+use gltf_json::validation::Checked;
+use gltf_json::{Animation, Index, Node};
+use nom_derive::{NomLE, Parse};
 use serde::{Deserialize, Serialize};
 
 use crate::fuel_fmt::common::{
-    FUELObjectFormat, FixedVec, HasReferences, PascalArray, ResourceObjectZ, Vec3i32,
+    FUELObjectFormat, FUELObjectFormatTrait, FixedVec, FormatOptions, HasReferences, MaybeF32,
+    PascalArray, ResourceObjectZ, Vec3i32, Vec4,
 };
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf;
+use crate::fuel_fmt::gltf::GltfBuilder;
+use crate::fuel_fmt::skel::SkelZ;
+use crate::File;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -28,23 +43,51 @@ struct AnimationZUnknown2 {
     unknowns: FixedVec<AnimationZUnknown, 3>,
 }
 
+// One axis of a vector key, each axis carries its own time
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct AnimationZUnknown1 {
-    unknown0: u32,
-    unknown1: u32,
-    unknown2: u32,
-    unknown3: u32,
-    unknown4: u32,
+struct AnimationZAxisKey {
+    time: MaybeF32,
+    value: MaybeF32,
 }
 
+// Same size as the other three word pair keyframes, read as an x, y and z key. What the vector
+// keyframers animate isn't known.
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct AnimationZUnknown4 {
-    unknown0: u32,
-    unknown1s: PascalArray<AnimationZUnknown1>,
+struct AnimationZKeyframeVec3 {
+    x: AnimationZAxisKey,
+    y: AnimationZAxisKey,
+    z: AnimationZAxisKey,
+}
+
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(Serialize, Deserialize, NomLE)]
+struct AnimationZKeyframeRot {
+    time: MaybeF32,
+    rotation: Vec4<MaybeF32>,
+}
+
+// bone is the crc32 of the Skel_Z bone name, older skeletons without names use the bone index
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(Serialize, Deserialize, NomLE)]
+struct AnimationZRotationTrack {
+    bone: u32,
+    keyframes: PascalArray<AnimationZKeyframeRot>,
+}
+
+// The flags word precedes the keyframes it applies to, zero when the keyframer is unused.
+// What its bits mean isn't known, it is kept as is.
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(Serialize, Deserialize, NomLE)]
+struct AnimationZKeyframer<T: BinWrite> {
+    flags: u16,
+    keyframes: PascalArray<T>,
 }
 
 #[derive(BinWrite)]
@@ -69,11 +112,78 @@ struct AnimationZUnknown12 {
     unknown6: u32,
 }
 
+// Only the rotation tracks are decoded. The two vector keyframers before them, the keyframer flags
+// and vectors are kept as they are until they are checked against the game.
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
+#[serde(from = "AnimationZShadow")]
 #[nom(Exact)]
 pub struct AnimationZ {
+    a: u32,
+    b: u32,
+    c: u16,
+    d: u16,
+    vectors: PascalArray<Vec3i32>,
+    unknown0s: PascalArray<AnimationZUnknown0>,
+    unknown2s: AnimationZKeyframer<AnimationZKeyframeVec3>,
+    unknown3s: AnimationZKeyframer<AnimationZKeyframeVec3>,
+    rotation_tracks: PascalArray<AnimationZRotationTrack>,
+    unknown5s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown6s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown7s: AnimationZKeyframer<AnimationZUnknown2>,
+    unknown8s: AnimationZKeyframer<AnimationZUnknown2>,
+    unknown9s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown10s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown11s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown12s: PascalArray<AnimationZUnknown12>,
+    unknown13s: PascalArray<AnimationZUnknown12>,
+    unknown14s: PascalArray<AnimationZUnknown5>,
+    unknown15s: PascalArray<AnimationZUnknown5>,
+}
+
+#[derive(Deserialize)]
+struct AnimationZFields {
+    a: u32,
+    b: u32,
+    c: u16,
+    d: u16,
+    vectors: PascalArray<Vec3i32>,
+    unknown0s: PascalArray<AnimationZUnknown0>,
+    unknown2s: AnimationZKeyframer<AnimationZKeyframeVec3>,
+    unknown3s: AnimationZKeyframer<AnimationZKeyframeVec3>,
+    rotation_tracks: PascalArray<AnimationZRotationTrack>,
+    unknown5s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown6s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown7s: AnimationZKeyframer<AnimationZUnknown2>,
+    unknown8s: AnimationZKeyframer<AnimationZUnknown2>,
+    unknown9s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown10s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown11s: AnimationZKeyframer<AnimationZUnknown5>,
+    unknown12s: PascalArray<AnimationZUnknown12>,
+    unknown13s: PascalArray<AnimationZUnknown12>,
+    unknown14s: PascalArray<AnimationZUnknown5>,
+    unknown15s: PascalArray<AnimationZUnknown5>,
+}
+
+#[derive(Deserialize)]
+struct AnimationZLegacyKey {
+    unknown0: u32,
+    unknown1: u32,
+    unknown2: u32,
+    unknown3: u32,
+    unknown4: u32,
+}
+
+#[derive(Deserialize)]
+struct AnimationZLegacyTrack {
+    unknown0: u32,
+    unknown1s: PascalArray<AnimationZLegacyKey>,
+}
+
+// object.json files written before the keyframers were decoded
+#[derive(Deserialize)]
+struct AnimationZLegacy {
     a: u32,
     b: u32,
     c: u16,
@@ -84,7 +194,7 @@ pub struct AnimationZ {
     unknown2s: PascalArray<AnimationZUnknown2>,
     unknown3flag: u16,
     unknown3s: PascalArray<AnimationZUnknown2>,
-    unknown4s: PascalArray<AnimationZUnknown4>,
+    unknown4s: PascalArray<AnimationZLegacyTrack>,
     unknown5flag: u16,
     unknown5s: PascalArray<AnimationZUnknown5>,
     unknown6flag: u16,
@@ -105,6 +215,257 @@ pub struct AnimationZ {
     unknown15s: PascalArray<AnimationZUnknown5>,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnimationZShadow {
+    Fields(AnimationZFields),
+    Legacy(AnimationZLegacy),
+}
+
+fn keyframer<T: BinWrite>(flags: u16, keyframes: PascalArray<T>) -> AnimationZKeyframer<T> {
+    AnimationZKeyframer { flags, keyframes }
+}
+
+fn legacy_vec3_keyframer(flags: u16, keyframes: PascalArray<AnimationZUnknown2>) -> AnimationZKeyframer<AnimationZKeyframeVec3> {
+    let axis = |x: &AnimationZUnknown| AnimationZAxisKey {
+        time: MaybeF32 { bits: x.unknown0 },
+        value: MaybeF32 { bits: x.unknown1 },
+    };
+    keyframer(flags, PascalArray {
+        data: keyframes
+            .data
+            .iter()
+            .map(|x| AnimationZKeyframeVec3 {
+                x: axis(&x.unknowns.data[0]),
+                y: axis(&x.unknowns.data[1]),
+                z: axis(&x.unknowns.data[2]),
+            })
+            .collect(),
+    })
+}
+
+impl From<AnimationZShadow> for AnimationZ {
+    fn from(shadow: AnimationZShadow) -> Self {
+        match shadow {
+            AnimationZShadow::Fields(x) => AnimationZ {
+                a: x.a,
+                b: x.b,
+                c: x.c,
+                d: x.d,
+                vectors: x.vectors,
+                unknown0s: x.unknown0s,
+                unknown2s: x.unknown2s,
+                unknown3s: x.unknown3s,
+                rotation_tracks: x.rotation_tracks,
+                unknown5s: x.unknown5s,
+                unknown6s: x.unknown6s,
+                unknown7s: x.unknown7s,
+                unknown8s: x.unknown8s,
+                unknown9s: x.unknown9s,
+                unknown10s: x.unknown10s,
+                unknown11s: x.unknown11s,
+                unknown12s: x.unknown12s,
+                unknown13s: x.unknown13s,
+                unknown14s: x.unknown14s,
+                unknown15s: x.unknown15s,
+            },
+            AnimationZShadow::Legacy(x) => AnimationZ {
+                a: x.a,
+                b: x.b,
+                c: x.c,
+                d: x.d,
+                vectors: x.vectors,
+                unknown0s: x.unknown0s,
+                unknown2s: legacy_vec3_keyframer(x.unknown2flag, x.unknown2s),
+                unknown3s: legacy_vec3_keyframer(x.unknown3flag, x.unknown3s),
+                rotation_tracks: PascalArray {
+                    data: x
+                        .unknown4s
+                        .data
+                        .into_iter()
+                        .map(|track| AnimationZRotationTrack {
+                            bone: track.unknown0,
+                            keyframes: PascalArray {
+                                data: track
+                                    .unknown1s
+                                    .data
+                                    .iter()
+                                    .map(|key| AnimationZKeyframeRot {
+                                        time: MaybeF32 { bits: key.unknown0 },
+                                        rotation: Vec4 {
+                                            data: [key.unknown1, key.unknown2, key.unknown3, key.unknown4]
+                                                .iter()
+                                                .map(|x| MaybeF32 { bits: *x })
+                                                .collect(),
+                                        },
+                                    })
+                                    .collect(),
+                            },
+                        })
+                        .collect(),
+                },
+                unknown5s: keyframer(x.unknown5flag, x.unknown5s),
+                unknown6s: keyframer(x.unknown6flag, x.unknown6s),
+                unknown7s: keyframer(x.unknown7flag, x.unknown7s),
+                unknown8s: keyframer(x.unknown8flag, x.unknown8s),
+                unknown9s: keyframer(x.unknown9flag, x.unknown9s),
+                unknown10s: keyframer(x.unknown10flag, x.unknown10s),
+                unknown11s: keyframer(x.unknown11flag, x.unknown11s),
+                unknown12s: x.unknown12s,
+                unknown13s: x.unknown13s,
+                unknown14s: x.unknown14s,
+                unknown15s: x.unknown15s,
+            },
+        }
+    }
+}
+
+// The editable keys of an animation, written to and read from keyframes.json
+#[derive(Serialize, Deserialize)]
+struct AnimationZKeyframes {
+    unknown2s: AnimationZKeyframer<AnimationZKeyframeVec3>,
+    unknown3s: AnimationZKeyframer<AnimationZKeyframeVec3>,
+    rotation_tracks: PascalArray<AnimationZRotationTrack>,
+}
+
+fn strictly_increasing(times: &[f32]) -> bool {
+    times.windows(2).all(|x| x[0] < x[1])
+}
+
+// Keys are sorted by time, two keys at the same time can't be told apart
+fn sort_keys<T>(keys: &mut [T], time: fn(&T) -> f32, track: &str) -> Result<()> {
+    keys.sort_by(|a, b| time(a).total_cmp(&time(b)));
+    let times: Vec<f32> = keys.iter().map(time).collect();
+    if !strictly_increasing(&times) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Keyframes of {} must have distinct times", track),
+        ));
+    }
+    Ok(())
+}
+
+impl AnimationZ {
+    fn keyframes(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Keyframes<'a> {
+            unknown2s: &'a AnimationZKeyframer<AnimationZKeyframeVec3>,
+            unknown3s: &'a AnimationZKeyframer<AnimationZKeyframeVec3>,
+            rotation_tracks: &'a PascalArray<AnimationZRotationTrack>,
+        }
+
+        Ok(serde_json::to_string_pretty(&Keyframes {
+            unknown2s: &self.unknown2s,
+            unknown3s: &self.unknown3s,
+            rotation_tracks: &self.rotation_tracks,
+        })?)
+    }
+
+    fn set_keyframes(&mut self, mut keyframes: AnimationZKeyframes) -> Result<()> {
+        // Vector keys sort by the time of their x axis
+        let vec3_time = |x: &AnimationZKeyframeVec3| f32::from_bits(x.x.time.bits);
+        sort_keys(&mut keyframes.unknown2s.keyframes.data, vec3_time, "unknown2s")?;
+        sort_keys(&mut keyframes.unknown3s.keyframes.data, vec3_time, "unknown3s")?;
+        for track in keyframes.rotation_tracks.data.iter_mut() {
+            let name = format!("bone {}", track.bone);
+            sort_keys(&mut track.keyframes.data, |x| f32::from_bits(x.time.bits), &name)?;
+        }
+
+        self.unknown2s = keyframes.unknown2s;
+        self.unknown3s = keyframes.unknown3s;
+        self.rotation_tracks = keyframes.rotation_tracks;
+        Ok(())
+    }
+
+    fn push_gltf_animation(
+        &self,
+        builder: &mut GltfBuilder,
+        name: String,
+        skel: &SkelZ,
+        joints: &[Index<Node>],
+    ) -> Result<Index<Animation>> {
+        let mut channels: Vec<Channel> = Vec::new();
+        let mut samplers: Vec<Sampler> = Vec::new();
+
+        for track in self.rotation_tracks.data.iter() {
+            if track.keyframes.data.is_empty() {
+                continue;
+            }
+
+            let joint = match skel.joint(track.bone) {
+                Some(joint) => joint as usize,
+                None if (track.bone as usize) < joints.len() => track.bone as usize,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("No bone {} in the Skel_Z", track.bone),
+                    ))
+                }
+            };
+
+            // glTF requires strictly increasing key times
+            let times: Vec<f32> = track.keyframes.data.iter().map(|x| f32::from_bits(x.time.bits)).collect();
+            if !strictly_increasing(&times) {
+                println!("Warn: bone {} rotation keys aren't in strictly increasing time, skipped", track.bone);
+                continue;
+            }
+
+            let rotations: Vec<f32> = track
+                .keyframes
+                .data
+                .iter()
+                .flat_map(|x| {
+                    let rotation: Vec<f32> = x.rotation.data.iter().map(|x| f32::from_bits(x.bits)).collect();
+                    GlamQuat::from_slice(&rotation).normalize().to_array()
+                })
+                .collect();
+
+            push_gltf_channel(builder, &mut channels, &mut samplers, &times, &rotations, Type::Vec4, joints[joint], Property::Rotation);
+        }
+
+        Ok(builder.root.push(Animation {
+            extensions: None,
+            extras: Default::default(),
+            channels,
+            name: Some(name),
+            samplers,
+        }))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn push_gltf_channel(
+    builder: &mut GltfBuilder,
+    channels: &mut Vec<Channel>,
+    samplers: &mut Vec<Sampler>,
+    times: &[f32],
+    values: &[f32],
+    value_type: Type,
+    node: Index<Node>,
+    path: Property,
+) {
+    let sampler = Index::new(samplers.len() as u32);
+    samplers.push(Sampler {
+        extensions: None,
+        extras: Default::default(),
+        input: builder.push_bounded_accessor(times, Type::Scalar, None),
+        interpolation: Checked::Valid(Interpolation::Linear),
+        output: builder.push_accessor(values, value_type, None),
+    });
+
+    channels.push(Channel {
+        sampler,
+        target: Target {
+            extensions: None,
+            extras: Default::default(),
+            node,
+            path: Checked::Valid(path),
+        },
+        extensions: None,
+        extras: Default::default(),
+    });
+}
+
 impl HasReferences for AnimationZ {
     fn hard_links(&self) -> Vec<u32> {
         vec![]
@@ -115,4 +476,203 @@ impl HasReferences for AnimationZ {
     }
 }
 
-pub type AnimationObjectFormat = FUELObjectFormat<ResourceObjectZ, AnimationZ>;
+pub struct AnimationObjectFormat;
+
+impl AnimationObjectFormat {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for AnimationObjectFormat {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // keyframes.json in the object directory replaces the keys in object.json
+        let keyframes_path = input_path.join("keyframes.json");
+        if !keyframes_path.exists() {
+            return FUELObjectFormat::<ResourceObjectZ, AnimationZ>::new()
+                .pack(input_path, header, body, options);
+        }
+
+        FUELObjectFormat::<ResourceObjectZ, AnimationZ>::new().pack_with(input_path, header, body, |_, body| {
+            body.set_keyframes(serde_json::from_str(&fs::read_to_string(&keyframes_path)?)?)
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ResourceObjectZ, AnimationZ>::new().unpack(header, body, output_path, options)
+    }
+}
+
+// .json writes the keyframes read back on import, .gltf plays them on the Skel_Z they target
+pub fn export(
+    database: &ObjectDatabase,
+    crc32: u32,
+    skel_crc32: Option<u32>,
+    output_path: &Path,
+) -> Result<()> {
+    let (_, animation): (ResourceObjectZ, AnimationZ) = database.read(crc32)?;
+
+    match (output_path.extension().and_then(|x| x.to_str()), skel_crc32) {
+        (Some("json"), _) => {
+            let mut output_file = File::create(output_path)?;
+            output_file.write_all(animation.keyframes()?.as_bytes())?;
+            Ok(())
+        }
+        (Some("gltf"), Some(skel_crc32)) => {
+            let (_, skel): (ResourceObjectZ, SkelZ) = database.read(skel_crc32)?;
+
+            let mut builder = GltfBuilder::new();
+            let (skin, roots) = skel.push_gltf_skin(&mut builder, database.names());
+            let joints = builder.root.skins[skin.value()].joints.clone();
+
+            let skeleton = builder.root.push(Node {
+                children: Some(roots),
+                ..gltf::node(database.names().name(skel_crc32))
+            });
+            builder.push_scene(vec![skeleton]);
+
+            animation.push_gltf_animation(&mut builder, database.names().name(crc32), &skel, &joints)?;
+
+            builder.write(output_path)
+        }
+        (Some("gltf"), None) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "animation .gltf exports also take the crc32 of the Skel_Z",
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "animation exports are written as .gltf or .json",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::animation::{AnimationZ, AnimationZKeyframes};
+
+    fn animation_bytes(rotation_times: &[f32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // a, b, c, d, vectors, unknown0s
+        bytes.extend_from_slice(&[0u8; 12]);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        // unknown2s with one key, every axis at time 0.5
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        for value in [1f32, 2f32, 3f32].iter() {
+            bytes.extend_from_slice(&0.5f32.to_le_bytes());
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        // unknown3s
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        // One rotation track for bone 7
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&(rotation_times.len() as u32).to_le_bytes());
+        for time in rotation_times.iter() {
+            bytes.extend_from_slice(&time.to_le_bytes());
+            for value in [0f32, 0f32, 0f32, 1f32].iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        // Seven empty keyframers and four empty arrays
+        for _ in 0..7 {
+            bytes.extend_from_slice(&0u16.to_le_bytes());
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[0u8; 16]);
+        bytes
+    }
+
+    #[test]
+    fn test_animation_round_trip() {
+        let bytes = animation_bytes(&[0f32, 1f32]);
+        let (_, animation) = AnimationZ::parse(&bytes).unwrap();
+        assert_eq!(animation.unknown2s.keyframes.data[0].y.time.bits, 0.5f32.to_bits());
+        assert_eq!(animation.unknown2s.keyframes.data[0].z.value.bits, 3f32.to_bits());
+
+        let mut written = Vec::new();
+        animation.write(&mut written).unwrap();
+        assert_eq!(bytes, written);
+    }
+
+    #[test]
+    fn test_animation_rotation_words_survive_json() {
+        let mut bytes = animation_bytes(&[0f32, 1f32]);
+        // The time and x words of the first rotation key become a NaN and a denormal
+        let key = 12 + 4 + 4 + 6 + 4 * 6 + 6 + 4 + 4 + 4;
+        bytes[key..key + 4].copy_from_slice(&0x7fc00001u32.to_le_bytes());
+        bytes[key + 4..key + 8].copy_from_slice(&1u32.to_le_bytes());
+
+        let (_, animation) = AnimationZ::parse(&bytes).unwrap();
+        let animation: AnimationZ = serde_json::from_str(&serde_json::to_string(&animation).unwrap()).unwrap();
+        let mut written = Vec::new();
+        animation.write(&mut written).unwrap();
+        assert_eq!(bytes, written);
+    }
+
+    #[test]
+    fn test_animation_legacy_json() {
+        let (_, animation) = AnimationZ::parse(&animation_bytes(&[0f32, 1f32])).unwrap();
+        let json = serde_json::to_string(&animation).unwrap();
+        let animation: AnimationZ = serde_json::from_str(&json).unwrap();
+        let mut written = Vec::new();
+        animation.write(&mut written).unwrap();
+        assert_eq!(animation_bytes(&[0f32, 1f32]), written);
+
+        let half = 0.5f32.to_bits();
+        let (one, two, three) = (1f32.to_bits(), 2f32.to_bits(), 3f32.to_bits());
+        let identity = [0, 0, 0, 1f32.to_bits()];
+        let key = |time: f32| {
+            format!(
+                "{{\"unknown0\":{},\"unknown1\":{},\"unknown2\":{},\"unknown3\":{},\"unknown4\":{}}}",
+                time.to_bits(), identity[0], identity[1], identity[2], identity[3]
+            )
+        };
+        let empty = "\"unknown5flag\":0,\"unknown5s\":[],\"unknown6flag\":0,\"unknown6s\":[],\"unknown7flag\":0,\"unknown7s\":[],\"unknown8flag\":0,\"unknown8s\":[],\"unknown9flag\":0,\"unknown9s\":[],\"unknown10flag\":0,\"unknown10s\":[],\"unknown11flag\":0,\"unknown11s\":[],\"unknown12s\":[],\"unknown13s\":[],\"unknown14s\":[],\"unknown15s\":[]";
+        let legacy = format!(
+            "{{\"a\":0,\"b\":0,\"c\":0,\"d\":0,\"vectors\":[],\"unknown0s\":[],\"unknown2flag\":1,\"unknown2s\":[{{\"unknowns\":[{{\"unknown0\":{h},\"unknown1\":{}}},{{\"unknown0\":{h},\"unknown1\":{}}},{{\"unknown0\":{h},\"unknown1\":{}}}]}}],\"unknown3flag\":0,\"unknown3s\":[],\"unknown4s\":[{{\"unknown0\":7,\"unknown1s\":[{},{}]}}],{}}}",
+            one, two, three, key(0f32), key(1f32), empty, h = half
+        );
+        let animation: AnimationZ = serde_json::from_str(&legacy).unwrap();
+        let mut written = Vec::new();
+        animation.write(&mut written).unwrap();
+        assert_eq!(animation_bytes(&[0f32, 1f32]), written);
+    }
+
+    #[test]
+    fn test_animation_keyframes_sorted_on_import() {
+        let (_, mut animation) = AnimationZ::parse(&animation_bytes(&[0f32, 1f32])).unwrap();
+        let keyframes: AnimationZKeyframes = serde_json::from_str(&animation.keyframes().unwrap()).unwrap();
+        animation.set_keyframes(keyframes).unwrap();
+
+        let mut written = Vec::new();
+        animation.write(&mut written).unwrap();
+        assert_eq!(animation_bytes(&[0f32, 1f32]), written);
+
+        let json = animation.keyframes().unwrap().replacen("\"time\": 0.0", "\"time\": 2.0", 1);
+        animation.set_keyframes(serde_json::from_str(&json).unwrap()).unwrap();
+        let mut written = Vec::new();
+        animation.write(&mut written).unwrap();
+        assert_eq!(animation_bytes(&[1f32, 2f32]), written);
+
+        let json = animation.keyframes().unwrap().replacen("\"time\": 1.0", "\"time\": 2.0", 1);
+        assert!(animation.set_keyframes(serde_json::from_str(&json).unwrap()).is_err());
+    }
+}
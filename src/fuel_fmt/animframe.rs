@@ -0,0 +1,24 @@
+use binwrite::BinWrite;
+use nom_derive::NomLE;
+use serde::{Deserialize, Serialize};
+
+use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ResourceObjectZ, Remainder};
+
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(Serialize, Deserialize, NomLE)]
+pub struct AnimFrameZ {
+    data: Remainder,
+}
+
+impl HasReferences for AnimFrameZ {
+    fn hard_links(&self) -> Vec<u32> {
+        vec![]
+    }
+
+    fn soft_links(&self) -> Vec<u32> {
+        vec![]
+    }
+}
+
+pub type AnimFrameObjectFormat = FUELObjectFormat<ResourceObjectZ, AnimFrameZ>;
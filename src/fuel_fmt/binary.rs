@@ -6,7 +6,7 @@ use binwrite::BinWrite;
 use nom_derive::Parse;
 use serde::{Deserialize, Serialize};
 
-use crate::fuel_fmt::common::{FUELObjectFormatTrait, HasReferences, ResourceObjectZ};
+use crate::fuel_fmt::common::{FUELObjectFormatTrait, FormatOptions, HasReferences, ResourceObjectZ};
 use std::fs;
 use zerocopy::AsBytes;
 
@@ -24,6 +24,7 @@ impl FUELObjectFormatTrait for BinaryObjectFormat {
         input_path: &Path,
         header: &mut Vec<u8>,
         body: &mut Vec<u8>,
+        _options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = input_path.join("object.json");
         let json_file = File::open(json_path)?;
@@ -55,6 +56,7 @@ impl FUELObjectFormatTrait for BinaryObjectFormat {
         header: &[u8],
         body: &[u8],
         output_path: &Path,
+        _options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = output_path.join("object.json");
         let mut output_file = File::create(json_path)?;
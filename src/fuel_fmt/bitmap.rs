@@ -1,15 +1,21 @@
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Cursor, Error, ErrorKind, Write};
 use std::path::Path;
 
 use binwrite::BinWrite;
 use byteorder::ByteOrder;
 use byteorder::LittleEndian;
+use image::codecs::dxt::{DXTVariant, DxtDecoder, DxtEncoder};
+use image::imageops::FilterType;
+use gltf_json::{Index, Texture};
+use image::{imageops, DynamicImage, ImageBuffer, ImageDecoder, RgbaImage};
 use nom_derive::{NomLE, Parse};
 use serde::{Deserialize, Serialize};
 
-use crate::fuel_fmt::common::{write_option, FUELObjectFormatTrait, HasReferences};
+use crate::fuel_fmt::common::{write_option, FUELObjectFormatTrait, FormatOptions, HasReferences};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf::GltfBuilder;
 use crate::File;
-use ddsfile::{D3DFormat, Dds};
+use ddsfile::{Caps, Caps2, D3DFormat, Dds, NewD3dParams};
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -38,6 +44,94 @@ struct BitmapZHeader {
     u4: u8,
 }
 
+// dw_caps2 is the low word of the DDS dwCaps2, which holds the cube map flags. DDSCAPS2_VOLUME
+// doesn't fit in it and no header field is known to hold a depth.
+const DW_CAPS2_CUBEMAP_FLAGS: u16 = 0xFE00;
+
+enum BitmapLayout {
+    Flat,
+    CubeMap(Caps2),
+    Volume(u32),
+}
+
+// 7 and 14 are the codes the original parser told apart. 15 and 16 for DXT3 and DXT5 are assumed
+// from DXT1 being 14, any other code is reported instead of being read as DXT5.
+const BITMAP_FORMATS: [(u8, D3DFormat); 4] = [
+    (7, D3DFormat::A8L8),
+    (14, D3DFormat::DXT1),
+    (15, D3DFormat::DXT3),
+    (16, D3DFormat::DXT5),
+];
+
+fn bitmap_format(code: u8) -> Option<D3DFormat> {
+    BITMAP_FORMATS
+        .iter()
+        .find(|(x, _)| *x == code)
+        .map(|(_, format)| *format)
+}
+
+fn unknown_format(code: u8) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("Unknown bitmap format code {}", code),
+    )
+}
+
+fn dds_error(error: ddsfile::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, error.to_string())
+}
+
+// The DDS container doesn't need the right FourCC to carry the data back, so unknown formats are
+// still written out and labeled DXT5 like they always have been
+fn dds_format(format: Option<D3DFormat>, code: u8) -> D3DFormat {
+    match format {
+        Some(format) => format,
+        None => {
+            println!("Warn: unknown bitmap format code {}, data.dds is labeled DXT5", code);
+            D3DFormat::DXT5
+        }
+    }
+}
+
+impl BitmapZHeader {
+    fn d3d_format(&self) -> Option<D3DFormat> {
+        bitmap_format(self.dxt_version1)
+    }
+
+    // The cube map flags in dw_caps2 decide cube maps. No value of bitmap_type has been checked
+    // against a volume so it is carried through as is, and a volume is only inferred when data_size
+    // isn't a flat mip chain of any length but is exactly that many slices of one.
+    fn layout(&self, format: D3DFormat) -> BitmapLayout {
+        let caps2 = Caps2::from_bits_truncate(self.dw_caps2 as u32);
+        if caps2.contains(Caps2::CUBEMAP) {
+            return BitmapLayout::CubeMap(caps2);
+        }
+
+        let levels = (self.mip_map_count as u32).max(1);
+        let data_size = self.data_size as usize;
+        let slice_size = match mip_size(format, self.width, self.height) {
+            Some(size) if size != 0 => size,
+            _ => return BitmapLayout::Flat,
+        };
+
+        // The mip count may be 0 or 1 for a bitmap that still stores its chain
+        if data_size == slice_size || chain_levels(format, self.width, self.height, data_size) > 1 {
+            return BitmapLayout::Flat;
+        }
+
+        // Every slice adds at least its top level so the depth can't exceed this
+        for depth in 2..=(data_size / slice_size) as u32 {
+            match volume_size(format, self.width, self.height, depth, levels) {
+                Some(size) if size == data_size => return BitmapLayout::Volume(depth),
+                Some(size) if size > data_size => break,
+                _ => (),
+            }
+        }
+
+        BitmapLayout::Flat
+    }
+}
+
 impl HasReferences for BitmapZHeader {
     fn hard_links(&self) -> Vec<u32> {
         vec![]
@@ -78,6 +172,12 @@ struct BitmapZHeaderAlternate {
     zero1: u16,
 }
 
+impl BitmapZHeaderAlternate {
+    fn d3d_format(&self) -> Option<D3DFormat> {
+        bitmap_format(self.dxt_version0)
+    }
+}
+
 impl HasReferences for BitmapZHeaderAlternate {
     fn hard_links(&self) -> Vec<u32> {
         vec![]
@@ -101,7 +201,7 @@ struct BitmapZAlternate {
     height: u32,
     zero0: u32,
     unknown0: u32,
-    #[nom(Cond = "LittleEndian::read_u32(&i[0..4]) == 0")]
+    #[nom(Cond = "i.get(0..4).map_or(false, |x| LittleEndian::read_u32(x) == 0)")]
     #[binwrite(with(write_option))]
     zero1: Option<u32>,
     unknown1: u16,
@@ -124,6 +224,415 @@ impl HasReferences for BitmapZAlternate {
 struct BitmapObjectAlternate {
     bitmap_header: BitmapZHeaderAlternate,
     bitmap: BitmapZAlternate,
+    // Not stored in the object, worked out on extraction
+    #[serde(default)]
+    mip_map_count: u32,
+}
+
+// Bytes in one mip level, DXT levels are padded out to whole 4x4 blocks
+fn mip_size(format: D3DFormat, width: u32, height: u32) -> Option<usize> {
+    let blocks = (width.div_ceil(4) * height.div_ceil(4)) as usize;
+    match format {
+        D3DFormat::DXT1 => Some(blocks * 8),
+        D3DFormat::DXT3 | D3DFormat::DXT5 => Some(blocks * 16),
+        D3DFormat::A8L8 => Some((width * height * 2) as usize),
+        _ => None,
+    }
+}
+
+// Each mip level of a volume halves its depth along with the width and height
+fn volume_size(format: D3DFormat, width: u32, height: u32, depth: u32, levels: u32) -> Option<usize> {
+    let mut size = 0;
+    for level in 0..levels {
+        let slices = (depth >> level).max(1) as usize;
+        size += mip_size(format, (width >> level).max(1), (height >> level).max(1))? * slices;
+    }
+    Some(size)
+}
+
+fn decode_mip(format: D3DFormat, width: u32, height: u32, data: &[u8]) -> Result<RgbaImage, Error> {
+    let variant = match format {
+        D3DFormat::DXT1 => DXTVariant::DXT1,
+        D3DFormat::DXT3 => DXTVariant::DXT3,
+        D3DFormat::DXT5 => DXTVariant::DXT5,
+        D3DFormat::A8L8 => {
+            return match ImageBuffer::from_raw(width, height, data.to_vec()) {
+                Some(image) => Ok(DynamicImage::ImageLumaA8(image).to_rgba8()),
+                None => Err(Error::new(ErrorKind::InvalidData, "Bitmap data too short")),
+            }
+        }
+        _ => {
+            return Err(Error::other(format!("Unsupported bitmap format {:?}", format)))
+        }
+    };
+
+    let padded_width = width.div_ceil(4) * 4;
+    let padded_height = height.div_ceil(4) * 4;
+
+    let decoder = match DxtDecoder::new(Cursor::new(data), padded_width, padded_height, variant) {
+        Ok(decoder) => decoder,
+        Err(error) => return Err(Error::new(ErrorKind::InvalidData, error.to_string())),
+    };
+
+    let mut pixels = vec![0; decoder.total_bytes() as usize];
+    if let Err(error) = decoder.read_image(&mut pixels) {
+        return Err(Error::new(ErrorKind::InvalidData, error.to_string()));
+    }
+
+    let image = match variant {
+        DXTVariant::DXT1 => ImageBuffer::from_raw(padded_width, padded_height, pixels)
+            .map(|x| DynamicImage::ImageRgb8(x).to_rgba8())
+            .map(|mut x| {
+                dxt1_decode_alpha(&mut x, data);
+                x
+            }),
+        _ => ImageBuffer::from_raw(padded_width, padded_height, pixels),
+    };
+
+    match image {
+        Some(image) => Ok(imageops::crop_imm(&image, 0, 0, width, height).to_image()),
+        None => Err(Error::new(ErrorKind::InvalidData, "Bitmap data too short")),
+    }
+}
+
+// The image crate decodes DXT1 as RGB. Blocks whose first color isn't greater than the second
+// use index 3 for transparent black.
+fn dxt1_decode_alpha(image: &mut RgbaImage, data: &[u8]) {
+    let blocks_wide = image.width() / 4;
+    for (i, block) in data.chunks_exact(8).enumerate() {
+        let (bx, by) = (i as u32 % blocks_wide, i as u32 / blocks_wide);
+        if by * 4 >= image.height() {
+            break;
+        }
+        if LittleEndian::read_u16(&block[0..2]) > LittleEndian::read_u16(&block[2..4]) {
+            continue;
+        }
+        let indices = LittleEndian::read_u32(&block[4..8]);
+        for pixel in 0..16 {
+            if (indices >> (pixel * 2)) & 3 == 3 {
+                image.get_pixel_mut(bx * 4 + pixel % 4, by * 4 + pixel / 4).0 = [0, 0, 0, 0];
+            }
+        }
+    }
+}
+
+fn rgb565(color: [u8; 3]) -> u16 {
+    ((color[0] as u16 >> 3) << 11) | ((color[1] as u16 >> 2) << 5) | (color[2] as u16 >> 3)
+}
+
+fn rgb888(color: u16) -> [i32; 3] {
+    let (r, g, b) = ((color >> 11) & 0x1F, (color >> 5) & 0x3F, color & 0x1F);
+    [(r * 255 / 31) as i32, (g * 255 / 63) as i32, (b * 255 / 31) as i32]
+}
+
+// A DXT1 block in its three color mode, pixels with alpha under 128 get the transparent index
+fn dxt1_encode_transparent_block(pixels: &[[u8; 4]; 16]) -> [u8; 8] {
+    let opaque: Vec<&[u8; 4]> = pixels.iter().filter(|x| x[3] >= 128).collect();
+    let mut min = [255u8; 3];
+    let mut max = [0u8; 3];
+    for pixel in opaque.iter() {
+        for channel in 0..3 {
+            min[channel] = min[channel].min(pixel[channel]);
+            max[channel] = max[channel].max(pixel[channel]);
+        }
+    }
+
+    let (a, b) = (rgb565(min), rgb565(max));
+    let (color0, color1) = (a.min(b), a.max(b));
+    let (c0, c1) = (rgb888(color0), rgb888(color1));
+    let palette = [c0, c1, [(c0[0] + c1[0]) / 2, (c0[1] + c1[1]) / 2, (c0[2] + c1[2]) / 2]];
+
+    let mut indices: u32 = 0;
+    for (i, pixel) in pixels.iter().enumerate() {
+        let index = if pixel[3] < 128 {
+            3
+        } else {
+            let distance = |color: &[i32; 3]| -> i32 {
+                (0..3).map(|x| (color[x] - pixel[x] as i32).pow(2)).sum()
+            };
+            (0..3).min_by_key(|x| distance(&palette[*x])).unwrap() as u32
+        };
+        indices |= index << (i * 2);
+    }
+
+    let mut block = [0u8; 8];
+    LittleEndian::write_u16(&mut block[0..2], color0);
+    LittleEndian::write_u16(&mut block[2..4], color1);
+    LittleEndian::write_u32(&mut block[4..8], indices);
+    block
+}
+
+// Where one face or slice of one mip level is in the data
+struct Surface {
+    name: String,
+    level: u32,
+    width: u32,
+    height: u32,
+    offset: usize,
+    size: usize,
+}
+
+// Cube maps store every level of a face before the next face, volumes store every slice of a level
+// before the next level. levels is None when the header doesn't record it, the chain then runs
+// down to 1x1 and stops wherever the data does.
+fn surfaces(
+    format: D3DFormat,
+    width: u32,
+    height: u32,
+    layout: &BitmapLayout,
+    levels: Option<u32>,
+) -> Result<Vec<Surface>, Error> {
+    let full_chain = full_mip_chain(width, height);
+    let levels = levels.unwrap_or(full_chain).clamp(1, full_chain);
+    let (faces, slices) = match layout {
+        BitmapLayout::Flat => (1, 1),
+        BitmapLayout::CubeMap(caps2) => {
+            let faces = [
+                Caps2::CUBEMAP_POSITIVEX,
+                Caps2::CUBEMAP_NEGATIVEX,
+                Caps2::CUBEMAP_POSITIVEY,
+                Caps2::CUBEMAP_NEGATIVEY,
+                Caps2::CUBEMAP_POSITIVEZ,
+                Caps2::CUBEMAP_NEGATIVEZ,
+            ];
+            (faces.iter().filter(|x| caps2.contains(**x)).count() as u32, 1)
+        }
+        BitmapLayout::Volume(depth) => (1, *depth),
+    };
+    let kind = match layout {
+        BitmapLayout::Volume(_) => "slice",
+        _ => "face",
+    };
+
+    let mut surfaces = Vec::new();
+    let mut offset: usize = 0;
+    for face in 0..faces {
+        for level in 0..levels {
+            let (level_width, level_height) = ((width >> level).max(1), (height >> level).max(1));
+            let size = match mip_size(format, level_width, level_height) {
+                Some(size) => size,
+                None => {
+                    return Err(Error::other(format!("Unsupported bitmap format {:?}", format)))
+                }
+            };
+            for slice in 0..(slices >> level).max(1) {
+                let surface = face.max(slice);
+                let mut name = String::from("data");
+                if surface != 0 {
+                    name += &format!(".{}{}", kind, surface);
+                }
+                if level != 0 {
+                    name += &format!(".{}", level);
+                }
+                surfaces.push(Surface {
+                    name: name + ".png",
+                    level,
+                    width: level_width,
+                    height: level_height,
+                    offset,
+                    size,
+                });
+                offset += size;
+            }
+        }
+    }
+
+    Ok(surfaces)
+}
+
+// The top level is written to data.png and the smaller levels to data.1.png, data.2.png, ...
+// Other faces and slices go to data.face1.png, data.slice1.png, ... with the same level suffixes.
+fn write_pngs(
+    format: D3DFormat,
+    surfaces: &[Surface],
+    data: &[u8],
+    output_path: &Path,
+    all_mips: bool,
+) -> Result<(), Error> {
+    for surface in surfaces.iter() {
+        if surface.level != 0 && !all_mips {
+            continue;
+        }
+
+        if surface.offset + surface.size > data.len() {
+            if surface.offset == 0 {
+                return Err(Error::new(ErrorKind::InvalidData, "Bitmap data too short"));
+            }
+            break;
+        }
+
+        let image = decode_mip(
+            format,
+            surface.width,
+            surface.height,
+            &data[surface.offset..surface.offset + surface.size],
+        )?;
+
+        if let Err(error) = image.save(output_path.join(&surface.name)) {
+            return Err(Error::other(error.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_mip(format: D3DFormat, image: &RgbaImage) -> Result<Vec<u8>, Error> {
+    let variant = match format {
+        D3DFormat::DXT1 => DXTVariant::DXT1,
+        D3DFormat::DXT3 => DXTVariant::DXT3,
+        D3DFormat::DXT5 => DXTVariant::DXT5,
+        D3DFormat::A8L8 => {
+            return Ok(DynamicImage::ImageRgba8(image.clone())
+                .to_luma_alpha8()
+                .into_raw())
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unsupported bitmap format {:?}", format),
+            ))
+        }
+    };
+
+    // Levels smaller than a block repeat their edge pixels to fill it
+    let (width, height) = image.dimensions();
+    let padded_width = width.div_ceil(4) * 4;
+    let padded_height = height.div_ceil(4) * 4;
+    let padded: RgbaImage = ImageBuffer::from_fn(padded_width, padded_height, |x, y| {
+        *image.get_pixel(x.min(width - 1), y.min(height - 1))
+    });
+
+    let pixels = match variant {
+        DXTVariant::DXT1 => DynamicImage::ImageRgba8(padded.clone()).to_rgb8().into_raw(),
+        _ => padded.clone().into_raw(),
+    };
+
+    let mut data: Vec<u8> = Vec::new();
+    if let Err(error) =
+        DxtEncoder::new(&mut data).encode(&pixels, padded_width, padded_height, variant)
+    {
+        return Err(Error::new(ErrorKind::InvalidData, error.to_string()));
+    }
+
+    // The image crate only encodes opaque DXT1, blocks with transparent pixels are redone
+    if let DXTVariant::DXT1 = variant {
+        for (i, block) in data.chunks_exact_mut(8).enumerate() {
+            let (bx, by) = (i as u32 % (padded_width / 4), i as u32 / (padded_width / 4));
+            let mut pixels = [[0u8; 4]; 16];
+            for (pixel, x) in pixels.iter_mut().enumerate() {
+                *x = padded.get_pixel(bx * 4 + pixel as u32 % 4, by * 4 + pixel as u32 / 4).0;
+            }
+            if pixels.iter().any(|x| x[3] < 128) {
+                block.copy_from_slice(&dxt1_encode_transparent_block(&pixels));
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+fn encode_mips(format: D3DFormat, image: &RgbaImage, mip_map_count: u32) -> Result<Vec<u8>, Error> {
+    let mut data = encode_mip(format, image)?;
+
+    let (mut width, mut height) = image.dimensions();
+    for _ in 1..mip_map_count {
+        width = (width / 2).max(1);
+        height = (height / 2).max(1);
+        let mip = imageops::resize(image, width, height, FilterType::Triangle);
+        data.append(&mut encode_mip(format, &mip)?);
+    }
+
+    Ok(data)
+}
+
+// data.dds packs back as it is. Deleting it packs data.png or data.tga instead, timestamps aren't
+// used because copying or checking out an extracted tree can reorder them.
+fn edited_image(input_path: &Path) -> Result<Option<RgbaImage>, Error> {
+    if input_path.join("data.dds").exists() {
+        return Ok(None);
+    }
+
+    for file_name in ["data.png", "data.tga"].iter() {
+        let image_path = input_path.join(file_name);
+        if image_path.exists() {
+            return match image::open(image_path) {
+                Ok(image) => Ok(Some(image.to_rgba8())),
+                Err(error) => Err(Error::new(ErrorKind::InvalidData, error.to_string())),
+            };
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::NotFound,
+        "No data.dds, data.png or data.tga for bitmap",
+    ))
+}
+
+// Levels in a full chain down to 1x1
+fn full_mip_chain(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+// The Alt header doesn't record the mip count, it is worked out from the data length
+fn chain_levels(format: D3DFormat, width: u32, height: u32, data_len: usize) -> u32 {
+    let mut size = 0;
+    for level in 0..full_mip_chain(width, height) {
+        size += mip_size(format, (width >> level).max(1), (height >> level).max(1)).unwrap_or(0);
+        if size >= data_len {
+            return if size == data_len { level + 1 } else { 1 };
+        }
+    }
+    1
+}
+
+// Decodes the top mip level, the header size tells the two bitmap versions apart
+pub fn decode(database: &ObjectDatabase, crc32: u32) -> Result<RgbaImage, Error> {
+    let (header, body) = database.read_bytes(crc32)?;
+
+    if let Ok((_, bitmap_header)) = BitmapZHeader::parse(&header) {
+        let format = match bitmap_header.d3d_format() {
+            Some(format) => format,
+            None => return Err(unknown_format(bitmap_header.dxt_version1)),
+        };
+        return decode_mip(
+            format,
+            bitmap_header.width,
+            bitmap_header.height,
+            &body,
+        );
+    }
+
+    let bitmap_header = BitmapZHeaderAlternate::parse(&header);
+    let bitmap = BitmapZAlternate::parse(&body);
+    match (bitmap_header, bitmap) {
+        (Ok((_, bitmap_header)), Ok((_, bitmap))) => match bitmap_header.d3d_format() {
+            Some(format) => decode_mip(format, bitmap.width, bitmap.height, &bitmap.data),
+            None => Err(unknown_format(bitmap_header.dxt_version0)),
+        },
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Object parser failed {}", crc32),
+        )),
+    }
+}
+
+// Bitmaps that aren't in the DPC are left out of the document
+pub fn push_gltf_texture(builder: &mut GltfBuilder, database: &ObjectDatabase, crc32: u32) -> Result<Option<Index<Texture>>, Error> {
+    if let Some(texture) = builder.textures.get(&crc32) {
+        return Ok(Some(*texture));
+    }
+
+    let image = match decode(database, crc32) {
+        Ok(image) => image,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) if error.kind() == ErrorKind::Unsupported => {
+            println!("Warn: bitmap {} left out, {}", crc32, error);
+            return Ok(None);
+        }
+        Err(error) => return Err(error),
+    };
+
+    Ok(Some(builder.push_texture(crc32, database.names().name(crc32), image)))
 }
 
 pub struct BitmapObjectFormat;
@@ -140,20 +649,80 @@ impl FUELObjectFormatTrait for BitmapObjectFormat {
         input_path: &Path,
         header: &mut Vec<u8>,
         body: &mut Vec<u8>,
+        _options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = input_path.join("object.json");
         let json_file = File::open(json_path)?;
 
-        let dds_path = input_path.join("data.dds");
-        let mut dds_file = File::open(dds_path)?;
+        let mut object: BitmapObject = serde_json::from_reader(json_file)?;
 
-        let dds = Dds::read(&mut dds_file).unwrap();
+        let data = match edited_image(input_path)? {
+            None => {
+                let mut dds_file = File::open(input_path.join("data.dds"))?;
+
+                let dds = match Dds::read(&mut dds_file) {
+                    Ok(dds) => dds,
+                    Err(error) => return Err(Error::new(ErrorKind::InvalidData, error.to_string())),
+                };
+
+                // width and height aren't in object.json, the DDS always sets them
+                object.bitmap_header.width = dds.get_width();
+                object.bitmap_header.height = dds.get_height();
+
+                // The rest of the header is only rewritten for a DDS with a different shape, so an
+                // unedited DDS packs back byte for byte even when data_size doesn't match the data.
+                // A DDS without a mip count has the one level, which the header may record as 0 or 1.
+                let cube_map_flags = dds.header.caps2.bits() as u16 & DW_CAPS2_CUBEMAP_FLAGS;
+                let levels = dds.get_num_mipmap_levels();
+                let header_levels = (object.bitmap_header.mip_map_count as u32).max(1);
+                if levels != header_levels
+                    || cube_map_flags != object.bitmap_header.dw_caps2 & DW_CAPS2_CUBEMAP_FLAGS
+                    || dds.get_depth() > 1
+                {
+                    object.bitmap_header.dw_caps2 =
+                        (object.bitmap_header.dw_caps2 & !DW_CAPS2_CUBEMAP_FLAGS) | cube_map_flags;
+                    object.bitmap_header.data_size = dds.data.len() as u32;
+                    if levels > 1 || object.bitmap_header.mip_map_count > 1 {
+                        object.bitmap_header.mip_map_count = levels as u8;
+                    }
+                }
+                dds.data
+            }
+            Some(image) => {
+                let format = match object.bitmap_header.d3d_format() {
+                    Some(format) => format,
+                    None => return Err(unknown_format(object.bitmap_header.dxt_version1)),
+                };
+
+                // width and height aren't in object.json, a volume is recognised by data_size holding
+                // several slices of an image this size
+                object.bitmap_header.width = image.width();
+                object.bitmap_header.height = image.height();
+                let volume = matches!(object.bitmap_header.layout(format), BitmapLayout::Volume(_));
+                if volume || object.bitmap_header.dw_caps2 & DW_CAPS2_CUBEMAP_FLAGS != 0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Cube maps and volumes can only be packed from data.dds",
+                    ));
+                }
+
+                // Bitmaps without mips like UI textures stay that way
+                let mip_map_count = if object.bitmap_header.mip_map_count > 1 {
+                    full_mip_chain(image.width(), image.height())
+                } else {
+                    1
+                };
+
+                let data = encode_mips(format, &image, mip_map_count)?;
+
+                object.bitmap_header.data_size = data.len() as u32;
+                object.bitmap_header.mip_map_count = mip_map_count as u8;
+                data
+            }
+        };
 
-        let mut object: BitmapObject = serde_json::from_reader(json_file)?;
-        object.bitmap_header.width = dds.get_width();
-        object.bitmap_header.height = dds.get_height();
         object.bitmap_header.write(header)?;
-        dds.data.write(body)?;
+        data.write(body)?;
 
         Ok((
             object.bitmap_header.hard_links(),
@@ -166,6 +735,7 @@ impl FUELObjectFormatTrait for BitmapObjectFormat {
         header: &[u8],
         body: &[u8],
         output_path: &Path,
+        options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = output_path.join("object.json");
         let mut output_file = File::create(json_path)?;
@@ -175,26 +745,60 @@ impl FUELObjectFormatTrait for BitmapObjectFormat {
             Err(_) => return Err(Error::from(ErrorKind::Other)),
         };
 
+        let format = bitmap_header.d3d_format();
+        if bitmap_header.data_size as usize != body.len() {
+            println!("Warn: bitmap data_size is {} but the data is {} bytes", bitmap_header.data_size, body.len());
+        }
+        let layout = bitmap_header.layout(dds_format(format, bitmap_header.dxt_version1));
+
+        if options.bitmap_png {
+            match format {
+                Some(format) => write_pngs(
+                    format,
+                    &surfaces(
+                        format,
+                        bitmap_header.width,
+                        bitmap_header.height,
+                        &layout,
+                        Some(bitmap_header.mip_map_count as u32),
+                    )?,
+                    body,
+                    output_path,
+                    options.bitmap_png_mips,
+                )?,
+                None => println!("Warn: no PNG written, {}", unknown_format(bitmap_header.dxt_version1)),
+            }
+        }
+
         let dds_path = output_path.join("data.dds");
         let mut output_dds_file = File::create(dds_path)?;
 
-        let mut dds = Dds::new_d3d(
-            bitmap_header.height,
-            bitmap_header.width,
-            None,
-            if bitmap_header.dxt_version1 == 14 {
-                D3DFormat::DXT1
-            } else {
-                D3DFormat::DXT5
-            },
-            Some(bitmap_header.mip_map_count as u32),
-            None,
-        )
-        .unwrap();
+        let (depth, caps2) = match layout {
+            BitmapLayout::Flat => (None, None),
+            BitmapLayout::CubeMap(caps2) => (None, Some(caps2)),
+            BitmapLayout::Volume(depth) => (Some(depth), Some(Caps2::VOLUME)),
+        };
+
+        let mut dds = Dds::new_d3d(NewD3dParams {
+            height: bitmap_header.height,
+            width: bitmap_header.width,
+            depth,
+            format: dds_format(format, bitmap_header.dxt_version1),
+            mipmap_levels: Some(bitmap_header.mip_map_count as u32),
+            caps2,
+        })
+        .map_err(dds_error)?;
+
+        // Faces and slices are stored one after another just like in a DDS
+        if caps2.is_some() {
+            dds.header.caps.insert(Caps::COMPLEX);
+        }
 
         dds.data = Vec::from(body);
 
-        dds.write(&mut output_dds_file).unwrap();
+        if let Err(error) = dds.write(&mut output_dds_file) {
+            return Err(Error::new(ErrorKind::InvalidData, error.to_string()));
+        }
 
         let object = BitmapObject { bitmap_header };
 
@@ -221,6 +825,7 @@ impl FUELObjectFormatTrait for BitmapObjectFormatAlt {
         input_path: &Path,
         header: &mut Vec<u8>,
         body: &mut Vec<u8>,
+        _options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = input_path.join("object.json");
         let json_file = File::open(json_path)?;
@@ -229,18 +834,42 @@ impl FUELObjectFormatTrait for BitmapObjectFormatAlt {
 
         object.bitmap_header.write(header)?;
 
-        let dds_path = input_path.join("data.dds");
-        let mut dds_file = File::open(dds_path)?;
+        let data = match edited_image(input_path)? {
+            None => {
+                let mut dds_file = File::open(input_path.join("data.dds"))?;
 
-        let dds = Dds::read(&mut dds_file).unwrap();
+                let dds = match Dds::read(&mut dds_file) {
+                    Ok(dds) => dds,
+                    Err(error) => return Err(Error::new(ErrorKind::InvalidData, error.to_string())),
+                };
 
-        object.bitmap.width = dds.get_width();
-        object.bitmap.height = dds.get_height();
+                object.bitmap.width = dds.get_width();
+                object.bitmap.height = dds.get_height();
+                dds.data
+            }
+            Some(image) => {
+                let format = match object.bitmap_header.d3d_format() {
+                    Some(format) => format,
+                    None => return Err(unknown_format(object.bitmap_header.dxt_version0)),
+                };
+
+                // Bitmaps extracted with a full chain get a full chain for the new size
+                let mip_map_count = if object.mip_map_count > 1 {
+                    full_mip_chain(image.width(), image.height())
+                } else {
+                    1
+                };
+
+                object.bitmap.width = image.width();
+                object.bitmap.height = image.height();
+                encode_mips(format, &image, mip_map_count)?
+            }
+        };
 
         object.bitmap.data.clear();
         object.bitmap.write(body)?;
 
-        dds.data.write(body).unwrap();
+        data.write(body)?;
 
         Ok((
             object.bitmap_header.hard_links(),
@@ -253,6 +882,7 @@ impl FUELObjectFormatTrait for BitmapObjectFormatAlt {
         header: &[u8],
         body: &[u8],
         output_path: &Path,
+        options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = output_path.join("object.json");
         let mut output_file = File::create(json_path)?;
@@ -267,32 +897,43 @@ impl FUELObjectFormatTrait for BitmapObjectFormatAlt {
             Err(_) => return Err(Error::from(ErrorKind::Other)),
         };
 
+        let format = dds_format(bitmap_header.d3d_format(), bitmap_header.dxt_version0);
+        let mip_map_count = chain_levels(format, bitmap.width, bitmap.height, bitmap.data.len());
+
+        if options.bitmap_png {
+            match bitmap_header.d3d_format() {
+                Some(format) => write_pngs(
+                    format,
+                    &surfaces(format, bitmap.width, bitmap.height, &BitmapLayout::Flat, Some(mip_map_count))?,
+                    &bitmap.data,
+                    output_path,
+                    options.bitmap_png_mips,
+                )?,
+                None => println!("Warn: no PNG written, {}", unknown_format(bitmap_header.dxt_version0)),
+            }
+        }
+
         let dds_path = output_path.join("data.dds");
         let mut output_dds_file = File::create(dds_path)?;
 
-        let mut dds = Dds::new_d3d(
-            bitmap.height,
-            bitmap.width,
-            None,
-            if bitmap_header.dxt_version0 == 7 {
-                D3DFormat::A8L8
-            } else if bitmap_header.dxt_version0 == 14 {
-                D3DFormat::DXT1
-            } else {
-                D3DFormat::DXT5
-            },
-            Some(0),
-            None,
-        )
-        .unwrap();
+        let mut dds = Dds::new_d3d(NewD3dParams {
+            height: bitmap.height,
+            width: bitmap.width,
+            depth: None,
+            format,
+            mipmap_levels: Some(mip_map_count),
+            caps2: None,
+        })
+        .map_err(dds_error)?;
 
         dds.data = bitmap.data.clone();
 
-        dds.write(&mut output_dds_file).unwrap();
+        dds.write(&mut output_dds_file).map_err(dds_error)?;
 
         let object = BitmapObjectAlternate {
             bitmap_header,
             bitmap,
+            mip_map_count,
         };
 
         output_file.write(serde_json::to_string_pretty(&object)?.as_bytes())?;
@@ -303,3 +944,155 @@ impl FUELObjectFormatTrait for BitmapObjectFormatAlt {
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::ErrorKind;
+
+    use ddsfile::{Caps2, D3DFormat};
+    use image::{Rgba, RgbaImage};
+    use tempdir::TempDir;
+
+    use crate::fuel_fmt::bitmap::{
+        bitmap_format, chain_levels, decode_mip, edited_image, encode_mips, surfaces, BitmapLayout,
+        BitmapZHeader,
+    };
+
+    fn checkerboard() -> RgbaImage {
+        RgbaImage::from_fn(8, 8, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        })
+    }
+
+    #[test]
+    fn test_bitmap_dxt1_keeps_one_bit_alpha() {
+        let image = checkerboard();
+        let data = encode_mips(D3DFormat::DXT1, &image, 1).unwrap();
+        assert_eq!(data.len(), 32);
+        assert_eq!(decode_mip(D3DFormat::DXT1, 8, 8, &data).unwrap(), image);
+    }
+
+    #[test]
+    fn test_bitmap_dxt_round_trip() {
+        for format in [D3DFormat::DXT3, D3DFormat::DXT5].iter() {
+            let image = checkerboard();
+            let data = encode_mips(*format, &image, 4).unwrap();
+            assert_eq!(data.len(), 64 + 16 + 16 + 16);
+            assert_eq!(chain_levels(*format, 8, 8, data.len()), 4);
+            assert_eq!(decode_mip(*format, 8, 8, &data[..64]).unwrap(), image);
+        }
+    }
+
+    fn header(dw_caps2: u16, data_size: u32, mip_map_count: u8) -> BitmapZHeader {
+        BitmapZHeader {
+            friendly_name_crc32: 0,
+            dw_caps2,
+            width: 8,
+            height: 8,
+            data_size,
+            u1: 0,
+            bitmap_type: 0,
+            zero: 0,
+            u7: 0.0,
+            dxt_version0: 14,
+            mip_map_count,
+            u2: 0,
+            u3: 0,
+            dxt_version1: 14,
+            u4: 0,
+        }
+    }
+
+    #[test]
+    fn test_bitmap_layout_from_header() {
+        let layout = |header: BitmapZHeader| match header.layout(D3DFormat::DXT1) {
+            BitmapLayout::Flat => ("flat", 1),
+            BitmapLayout::CubeMap(_) => ("cube", 6),
+            BitmapLayout::Volume(depth) => ("volume", depth),
+        };
+        assert_eq!(layout(header(0, 56, 4)), ("flat", 1));
+        assert_eq!(layout(header(0, 152, 3)), ("volume", 4));
+        assert_eq!(layout(header(0, 64, 0)), ("volume", 2));
+        assert_eq!(layout(header(0, 60, 1)), ("flat", 1));
+        assert_eq!(layout(header(0xFE00, 336, 4)), ("cube", 6));
+
+        // A chain stored under a mip count of 0 isn't read as slices
+        assert_eq!(layout(header(0, 56, 0)), ("flat", 1));
+        let mut small = header(0, 24, 0);
+        small.width = 4;
+        small.height = 4;
+        assert_eq!(layout(small), ("flat", 1));
+    }
+
+    #[test]
+    fn test_bitmap_chain_levels() {
+        assert_eq!(chain_levels(D3DFormat::DXT1, 8, 8, 32), 1);
+        assert_eq!(chain_levels(D3DFormat::DXT1, 8, 8, 56), 4);
+        assert_eq!(chain_levels(D3DFormat::DXT1, 8, 8, 50), 1);
+    }
+
+    #[test]
+    fn test_bitmap_png_only_without_dds() {
+        let dir = TempDir::new("bitmap").unwrap();
+        assert_eq!(edited_image(dir.path()).unwrap_err().kind(), ErrorKind::NotFound);
+
+        checkerboard().save(dir.path().join("data.png")).unwrap();
+        assert_eq!(edited_image(dir.path()).unwrap(), Some(checkerboard()));
+
+        // A PNG written after data.dds still doesn't replace it
+        File::create(dir.path().join("data.dds")).unwrap();
+        checkerboard().save(dir.path().join("data.png")).unwrap();
+        assert!(edited_image(dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_bitmap_format_codes() {
+        assert_eq!(bitmap_format(14), Some(D3DFormat::DXT1));
+        assert_eq!(bitmap_format(15), Some(D3DFormat::DXT3));
+        assert_eq!(bitmap_format(16), Some(D3DFormat::DXT5));
+        assert_eq!(bitmap_format(7), Some(D3DFormat::A8L8));
+        assert_eq!(bitmap_format(3), None);
+    }
+
+    #[test]
+    fn test_bitmap_volume_surfaces() {
+        // 8x8x4 DXT1 with 3 levels: 4 slices of 32 bytes, 2 slices of 8 bytes, 1 slice of 8 bytes
+        let surfaces = surfaces(D3DFormat::DXT1, 8, 8, &BitmapLayout::Volume(4), Some(3)).unwrap();
+        let offsets: Vec<(usize, &str)> = surfaces.iter().map(|x| (x.offset, x.name.as_str())).collect();
+        assert_eq!(
+            offsets,
+            vec![
+                (0, "data.png"),
+                (32, "data.slice1.png"),
+                (64, "data.slice2.png"),
+                (96, "data.slice3.png"),
+                (128, "data.1.png"),
+                (136, "data.slice1.1.png"),
+                (144, "data.2.png"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bitmap_cube_map_surfaces() {
+        let caps2 = Caps2::CUBEMAP | Caps2::CUBEMAP_POSITIVEX | Caps2::CUBEMAP_NEGATIVEX;
+        let surfaces = surfaces(D3DFormat::DXT5, 4, 4, &BitmapLayout::CubeMap(caps2), Some(3)).unwrap();
+        let offsets: Vec<(usize, &str)> = surfaces.iter().map(|x| (x.offset, x.name.as_str())).collect();
+        assert_eq!(
+            offsets,
+            vec![
+                (0, "data.png"),
+                (16, "data.1.png"),
+                (32, "data.2.png"),
+                (48, "data.face1.png"),
+                (64, "data.face1.1.png"),
+                (80, "data.face1.2.png"),
+            ]
+        );
+    }
+}
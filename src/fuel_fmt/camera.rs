@@ -1,8 +1,17 @@
+use std::f32::consts::PI;
+use std::io::{Error, ErrorKind, Result};
+
 use binwrite::BinWrite;
+use gltf_json::camera::{Perspective, Type};
+use gltf_json::validation::Checked;
+use gltf_json::{Camera, Index};
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ObjectZ};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf::GltfBuilder;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -11,7 +20,7 @@ use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ObjectZ};
 pub struct CameraZ {
     angle_of_view: f32,
     zero: f32,
-    node_crc32: u32,
+    pub node_crc32: u32,
 }
 
 impl HasReferences for CameraZ {
@@ -24,4 +33,38 @@ impl HasReferences for CameraZ {
     }
 }
 
+pub fn read(database: &ObjectDatabase, crc32: u32) -> Result<CameraZ> {
+    Ok(database.read::<ObjectZ, CameraZ>(crc32)?.1)
+}
+
+// The angle is used as glTF's vertical field of view in radians, the stored value is kept in the extras
+pub fn push_gltf_camera(builder: &mut GltfBuilder, database: &ObjectDatabase, crc32: u32) -> Result<Index<Camera>> {
+    let camera = read(database, crc32)?;
+
+    if !(camera.angle_of_view > 0f32 && camera.angle_of_view < PI) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Camera {} angle_of_view {} isn't between 0 and pi", crc32, camera.angle_of_view),
+        ));
+    }
+
+    let extras = json!({ "angle_of_view": camera.angle_of_view });
+
+    Ok(builder.root.push(Camera {
+        name: Some(database.names().name(crc32)),
+        orthographic: None,
+        perspective: Some(Perspective {
+            aspect_ratio: None,
+            yfov: camera.angle_of_view,
+            zfar: None,
+            znear: 0.01f32,
+            extensions: None,
+            extras: Default::default(),
+        }),
+        type_: Checked::Valid(Type::Perspective),
+        extensions: None,
+        extras: Some(serde_json::value::to_raw_value(&extras)?),
+    }))
+}
+
 pub type CameraObjectFormat = FUELObjectFormat<ObjectZ, CameraZ>;
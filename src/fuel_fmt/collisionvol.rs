@@ -1,8 +1,16 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
 use binwrite::BinWrite;
+use glam::Mat4;
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
 
-use crate::fuel_fmt::common::{FUELObjectFormat, FixedVec, HasReferences, Mat4f, ObjectZ};
+use crate::fuel_fmt::common::{
+    FUELObjectFormat, FUELObjectFormatTrait, FixedVec, FormatOptions, HasReferences, Mat4f, ObjectZ,
+};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::volume::{self, Volume, VolumeShape};
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -27,4 +35,110 @@ impl HasReferences for CollisionVolZ {
     }
 }
 
-pub type CollisionVolObjectType = FUELObjectFormat<ObjectZ, CollisionVolZ>;
+impl CollisionVolZ {
+    // The inverse is kept in step with the edited transform
+    fn set_volume(&mut self, volume: &Volume) {
+        if volume.apply_to_matrix(&mut self.local_transform) {
+            let inverse = Mat4::from_cols_slice(&self.local_transform.data).inverse();
+            self.local_transform_inverse.data = inverse.to_cols_array().to_vec();
+        }
+    }
+}
+
+pub fn volume(database: &ObjectDatabase, crc32: u32) -> Result<Volume> {
+    let (_, collision_vol) = database.read::<ObjectZ, CollisionVolZ>(crc32)?;
+    // The shape of each volume_type isn't known, the volume is drawn as the box its transform spans
+    Ok(Volume {
+        volume_type: Some(collision_vol.volume_type),
+        ..Volume::from_matrix(database.names().name(crc32), VolumeShape::Box, &collision_vol.local_transform)
+    })
+}
+
+pub struct CollisionVolObjectFormat;
+
+impl CollisionVolObjectFormat {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for CollisionVolObjectFormat {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // volumes.json in the object directory replaces the transform in object.json
+        let volumes_path = input_path.join("volumes.json");
+        if !volumes_path.exists() {
+            return FUELObjectFormat::<ObjectZ, CollisionVolZ>::new()
+                .pack(input_path, header, body, options);
+        }
+
+        FUELObjectFormat::<ObjectZ, CollisionVolZ>::new().pack_with(input_path, header, body, |_, body| {
+            match &volume::read_json(&volumes_path)?[..] {
+                [volume] => {
+                    body.set_volume(volume);
+                    Ok(())
+                }
+                _ => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "A CollisionVol_Z has exactly one volume",
+                )),
+            }
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ObjectZ, CollisionVolZ>::new().unpack(header, body, output_path, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use glam::Mat4;
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::collisionvol::CollisionVolZ;
+    use crate::fuel_fmt::volume::{Volume, VolumeShape};
+
+    fn collision_vol_bytes() -> Vec<u8> {
+        let mut bytes = 3u32.to_le_bytes().to_vec();
+        for _ in 0..2 {
+            for value in Mat4::IDENTITY.to_cols_array().iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&[0u8; 28 * 4]);
+        bytes.extend_from_slice(&5u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_collision_vol_set_volume() {
+        let (_, mut collision_vol) = CollisionVolZ::parse(&collision_vol_bytes()).unwrap();
+        let mut volume = Volume::from_matrix(String::new(), VolumeShape::Box, &collision_vol.local_transform);
+        collision_vol.set_volume(&volume);
+        let mut written = Vec::new();
+        collision_vol.write(&mut written).unwrap();
+        assert_eq!(written, collision_vol_bytes());
+
+        volume.translation = [1.0, 2.0, 3.0];
+        volume.scale = [2.0, 2.0, 2.0];
+        collision_vol.set_volume(&volume);
+        let transform = Mat4::from_cols_slice(&collision_vol.local_transform.data);
+        let inverse = Mat4::from_cols_slice(&collision_vol.local_transform_inverse.data);
+        assert!((transform * inverse).abs_diff_eq(Mat4::IDENTITY, 1e-6));
+        assert_eq!(transform.w_axis.to_array(), [1.0, 2.0, 3.0, 1.0]);
+    }
+}
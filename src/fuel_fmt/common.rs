@@ -5,6 +5,7 @@ use std::path::Path;
 use std::vec::Vec;
 
 use binwrite::{BinWrite, WriterOption};
+use image::ImageError;
 pub use nom::number::complete::*;
 pub use nom::*;
 pub use nom_derive::NomLE;
@@ -75,7 +76,7 @@ impl<'de, T: BinWrite, const U: usize> Deserialize<'de> for FixedVec<T, U>
             data: Vec::deserialize(deserializer)?,
         };
         if fv.data.len() != U {
-            panic!("FixedVec size does not match");
+            return Err(serde::de::Error::invalid_length(fv.data.len(), &format!("{} elements", U).as_str()));
         }
         Ok(fv)
     }
@@ -94,6 +95,94 @@ pub type Quat = Vec4<f32>;
 
 pub type Mat4f = FixedVec<f32, 16>;
 
+// Fill and stroke colours of the map exports, indexed by region or road type
+pub const EXPORT_COLORS: [&str; 8] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#808000",
+];
+
+// Words are shown as floats when they are normal floats, anything else keeps its raw bits as a "0x" string
+#[derive(Clone, Copy)]
+pub enum FloatOrBits {
+    Raw(u32),
+    Float(f32),
+}
+
+impl Serialize for FloatOrBits {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            FloatOrBits::Raw(word) => format!("{:#010x}", word).serialize(serializer),
+            FloatOrBits::Float(float) => float.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FloatOrBitsShadow {
+    Float(f32),
+    Raw(String),
+}
+
+impl<'de> Deserialize<'de> for FloatOrBits {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match FloatOrBitsShadow::deserialize(deserializer)? {
+            FloatOrBitsShadow::Float(float) => Ok(FloatOrBits::Float(float)),
+            FloatOrBitsShadow::Raw(word) => word
+                .strip_prefix("0x")
+                .and_then(|x| u32::from_str_radix(x, 16).ok())
+                .map(FloatOrBits::Raw)
+                .ok_or_else(|| serde::de::Error::custom(format!("{} isn't a float or a 0x word", word))),
+        }
+    }
+}
+
+impl From<u32> for FloatOrBits {
+    fn from(word: u32) -> Self {
+        let float = f32::from_bits(word);
+        if float.is_normal() || word == 0 {
+            FloatOrBits::Float(float)
+        } else {
+            FloatOrBits::Raw(word)
+        }
+    }
+}
+
+impl From<FloatOrBits> for u32 {
+    fn from(value: FloatOrBits) -> Self {
+        match value {
+            FloatOrBits::Raw(word) => word,
+            FloatOrBits::Float(float) => float.to_bits(),
+        }
+    }
+}
+
+// A field thought to be a float, words that aren't survive the trip through JSON
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(Serialize, Deserialize, NomLE, Clone, Copy)]
+#[serde(from = "FloatOrBits", into = "FloatOrBits")]
+pub struct MaybeF32 {
+    pub bits: u32,
+}
+
+impl From<FloatOrBits> for MaybeF32 {
+    fn from(value: FloatOrBits) -> Self {
+        MaybeF32 { bits: value.into() }
+    }
+}
+
+impl From<MaybeF32> for FloatOrBits {
+    fn from(value: MaybeF32) -> Self {
+        FloatOrBits::from(value.bits)
+    }
+}
+
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(PartialEq, Serialize, Deserialize, NomLE)]
@@ -147,6 +236,14 @@ pub struct FadeDistances {
     pub fade_close: f32,
 }
 
+// Keeps the io error from a failed image save, anything else is an image the encoder rejected
+pub fn image_error(error: ImageError) -> Error {
+    match error {
+        ImageError::IoError(error) => error,
+        error => Error::new(ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
 pub fn write_option<W, T>(
     option: &Option<T>,
     writer: &mut W,
@@ -183,6 +280,15 @@ impl<T> PascalArray<T> {
     pub fn len(self: &Self) -> usize {
         self.data.len()
     }
+
+    // For elements parsed with ExtraArgs, the derived parser can't pass them on
+    pub fn parse_with<'a, F>(i: &'a [u8], f: F) -> IResult<&'a [u8], Self>
+    where
+        F: FnMut(&'a [u8]) -> IResult<&'a [u8], T>,
+    {
+        let (i, data) = multi::length_count(le_u32, f)(i)?;
+        Ok((i, PascalArray { data }))
+    }
 }
 
 impl<T> Serialize for PascalArray<T>
@@ -211,13 +317,23 @@ where
     }
 }
 
+// The bytes left at the end of a body whose layout isn't known yet
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(Serialize, Deserialize, NomLE)]
+#[serde(transparent)]
+pub struct Remainder {
+    #[nom(Parse = "{ |i| combinator::map(combinator::rest, |x: &[u8]| x.to_vec())(i) }")]
+    pub data: Vec<u8>,
+}
+
 #[derive(NomLE)]
 pub struct PascalString {
     #[nom(
         Map = "|x: Vec<u8>| String::from_utf8_lossy(&x[..]).to_string()",
         Parse = "|i| length_count!(i, le_u32, le_u8)"
     )]
-    data: String,
+    pub data: String,
 }
 
 impl BinWrite for PascalString {
@@ -253,7 +369,7 @@ pub struct PascalStringNULL {
         Map = "|x: Vec<u8>| String::from_utf8_lossy(&x[0..x.len() - 1]).to_string()",
         Parse = "|i| length_count!(i, le_u32, le_u8)"
     )]
-    data: String,
+    pub data: String,
 }
 
 impl BinWrite for PascalStringNULL {
@@ -287,14 +403,20 @@ impl<'de> Deserialize<'de> for PascalStringNULL {
 #[derive(NomLE)]
 pub struct FixedStringNULL<const U: usize> {
     #[nom(
-    Map = "|x: &[u8]| String::from_utf8_lossy(x.split_at(x.iter().position(|&r| r == 0u8).unwrap()).0).to_string()",
+    Map = "|x: &[u8]| String::from_utf8_lossy(x.split_at(x.iter().position(|&r| r == 0u8).unwrap_or(x.len())).0).to_string()",
     Take = "U"
     )]
-    data: String,
+    pub data: String,
 }
 
 impl<const U: usize> BinWrite for FixedStringNULL<U> {
     fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<(), Error> {
+        if self.data.len() > U {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("\"{}\" is longer than {} bytes", self.data, U),
+            ));
+        }
         BinWrite::write_options(&self.data, writer, options)?;
         BinWrite::write_options(&vec![0u8; U - self.data.len()], writer, options)
     }
@@ -364,13 +486,18 @@ pub struct VertexVectorComponent {
     pub data: u8,
 }
 
+impl VertexVectorComponent {
+    pub fn to_f32(&self) -> f32 {
+        ((self.data as f32) / 255f32) * 2f32 - 1f32
+    }
+}
+
 impl Serialize for VertexVectorComponent {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: Serializer,
     {
-        let converted: f32 = ((self.data as f32) / 255f32) * 2f32 - 1f32;
-        converted.serialize(serializer)
+        self.to_f32().serialize(serializer)
     }
 }
 
@@ -420,19 +547,36 @@ impl HasReferences for ObjectZ {
     }
 }
 
+// Backend options that change how objects are packed and unpacked
+#[derive(Clone, Copy)]
+pub struct FormatOptions {
+    pub bitmap_png: bool,
+    pub bitmap_png_mips: bool,
+    pub sound_sample_rate: u32,
+    pub node_transforms: bool,
+    pub node_sphere: bool,
+    pub node_check: bool,
+}
+
 pub trait FUELObjectFormatTrait {
     fn pack(
         self: &Self,
         input_path: &Path,
         header: &mut Vec<u8>,
         body: &mut Vec<u8>,
+        options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error>;
     fn unpack(
         self: &Self,
         header: &[u8],
         body: &[u8],
         output_path: &Path,
+        options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error>;
+    // Tells formats apart when several versions share one
+    fn format_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 pub struct FUELObjectFormat<T, U> {
@@ -449,27 +593,34 @@ impl<T, U> FUELObjectFormat<T, U> {
     }
 }
 
-impl<T, U> FUELObjectFormatTrait for FUELObjectFormat<T, U>
+#[derive(Serialize, Deserialize)]
+struct Object<T, U> {
+    header: T,
+    body: U,
+}
+
+impl<T, U> FUELObjectFormat<T, U>
 where
-    for<'a> T: Parse<&'a [u8]> + Serialize + Deserialize<'a> + BinWrite + HasReferences,
-    for<'a> U: Parse<&'a [u8]> + Serialize + Deserialize<'a> + BinWrite + HasReferences,
+    for<'a> T: Deserialize<'a> + BinWrite + HasReferences,
+    for<'a> U: Deserialize<'a> + BinWrite + HasReferences,
 {
-    fn pack(
+    // Packs object.json after edit has applied the files that override parts of it
+    pub fn pack_with<F>(
         self: &Self,
         input_path: &Path,
         header: &mut Vec<u8>,
         body: &mut Vec<u8>,
-    ) -> Result<(Vec<u32>, Vec<u32>), Error> {
+        edit: F,
+    ) -> Result<(Vec<u32>, Vec<u32>), Error>
+    where
+        F: FnOnce(&mut T, &mut U) -> Result<(), Error>,
+    {
         let json_path = input_path.join("object.json");
         let json_file = File::open(json_path)?;
 
-        #[derive(Serialize, Deserialize)]
-        struct Object<T, U> {
-            header: T,
-            body: U,
-        }
+        let mut object: Object<T, U> = serde_json::from_reader(json_file)?;
 
-        let object: Object<T, U> = serde_json::from_reader(json_file)?;
+        edit(&mut object.header, &mut object.body)?;
 
         object.header.write(header)?;
         object.body.write(body)?;
@@ -487,12 +638,29 @@ where
 
         Ok((hard_links, soft_links))
     }
+}
+
+impl<T, U> FUELObjectFormatTrait for FUELObjectFormat<T, U>
+where
+    for<'a> T: Parse<&'a [u8]> + Serialize + Deserialize<'a> + BinWrite + HasReferences,
+    for<'a> U: Parse<&'a [u8]> + Serialize + Deserialize<'a> + BinWrite + HasReferences,
+{
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        _options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>), Error> {
+        self.pack_with(input_path, header, body, |_, _| Ok(()))
+    }
 
     fn unpack(
         self: &Self,
         header: &[u8],
         body: &[u8],
         output_path: &Path,
+        _options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = output_path.join("object.json");
         let mut output_file = File::create(json_path)?;
@@ -507,12 +675,6 @@ where
             Err(_) => return Err(Error::from(ErrorKind::Other)),
         };
 
-        #[derive(Serialize, Deserialize)]
-        struct Object<T, U> {
-            header: T,
-            body: U,
-        }
-
         let object = Object { header, body };
 
         output_file.write(serde_json::to_string_pretty(&object)?.as_bytes())?;
@@ -537,16 +699,64 @@ where
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
 pub struct DynSphere {
-    sphere: SphereZ,
-    flags: u32,
-    dyn_sphere_name: u32,
+    pub sphere: SphereZ,
+    pub flags: u32,
+    pub dyn_sphere_name: u32,
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
 pub struct DynBox {
-    mat: Mat4f,
-    flags: u32,
-    dyn_box_name: u32,
+    pub mat: Mat4f,
+    pub flags: u32,
+    pub dyn_box_name: u32,
+}
+
+// Parses a body, passes it through its object.json form and checks it is written back unchanged
+#[cfg(test)]
+pub fn assert_round_trip<T>(bytes: &[u8])
+where
+    T: for<'a> Parse<&'a [u8]> + Serialize + serde::de::DeserializeOwned + BinWrite,
+{
+    let (_, body) = T::parse(bytes).unwrap();
+    let body: T = serde_json::from_str(&serde_json::to_string(&body).unwrap()).unwrap();
+    let mut written = Vec::new();
+    body.write(&mut written).unwrap();
+    assert_eq!(bytes, &written[..]);
+}
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::common::{FixedStringNULL, FixedVec, FloatOrBits, MaybeF32};
+
+    #[test]
+    fn test_float_or_bits() {
+        let words = [0u32, 1f32.to_bits(), (-2.5f32).to_bits(), 1, 0x7fc00000, 0xffffffff];
+        let json = serde_json::to_string(&words.iter().map(|x| MaybeF32 { bits: *x }).collect::<Vec<MaybeF32>>()).unwrap();
+        assert_eq!(json, r#"[0.0,1.0,-2.5,"0x00000001","0x7fc00000","0xffffffff"]"#);
+
+        let read: Vec<MaybeF32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.iter().map(|x| x.bits).collect::<Vec<u32>>(), words);
+    }
+
+    #[test]
+    fn test_float_or_bits_integers_are_floats() {
+        let read: FloatOrBits = serde_json::from_str("1").unwrap();
+        assert_eq!(u32::from(read), 1f32.to_bits());
+        assert!(serde_json::from_str::<FloatOrBits>(r#""1""#).is_err());
+    }
+
+    #[test]
+    fn test_wrong_sizes_are_errors() {
+        assert!(serde_json::from_str::<FixedVec<u32, 3>>("[1, 2]").is_err());
+
+        let (_, string) = FixedStringNULL::<4>::parse(b"abcd").unwrap();
+        assert_eq!(string.data, "abcd");
+        let mut written = Vec::new();
+        assert!(FixedStringNULL::<2> { data: string.data }.write(&mut written).is_err());
+    }
 }
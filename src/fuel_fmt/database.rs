@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use nom_derive::Parse;
+
+use crate::fuel_dpc::read_object;
+use crate::names::NameDatabase;
+
+// Index of the objects in an extracted DPC, used by the exporters to follow references
+pub struct ObjectDatabase {
+    index: HashMap<u32, PathBuf>,
+    names: NameDatabase,
+}
+
+impl ObjectDatabase {
+    pub fn new(objects_path: &Path, mut names: NameDatabase) -> Result<ObjectDatabase> {
+        let mut index: HashMap<u32, PathBuf> = HashMap::new();
+
+        for entry in fs::read_dir(objects_path)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            let stem = match path.file_stem().and_then(|x| x.to_str()) {
+                Some(stem) => stem.to_string(),
+                None => continue,
+            };
+
+            let mut split = stem.splitn(2, '_');
+            let crc32 = match split.next().unwrap().parse::<u32>() {
+                Ok(crc32) => crc32,
+                Err(_) => continue,
+            };
+
+            // Named objects are written as crc32_name.Class
+            if let Some(name) = split.next() {
+                if names.get(crc32).is_none() {
+                    names.insert(crc32, name);
+                }
+            }
+
+            if index.contains_key(&crc32) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Ambiguous files for crc32 = {}", crc32),
+                ));
+            }
+
+            index.insert(crc32, path);
+        }
+
+        Ok(ObjectDatabase {
+            index,
+            names,
+        })
+    }
+
+    pub fn names(&self) -> &NameDatabase {
+        &self.names
+    }
+
+    pub fn path(&self, crc32: u32) -> Result<&PathBuf> {
+        match self.index.get(&crc32) {
+            Some(path) => Ok(path),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No object for crc32: {}", crc32),
+            )),
+        }
+    }
+
+    // The class name is the extension of the extracted object
+    pub fn class(&self, crc32: u32) -> Option<&str> {
+        self.index
+            .get(&crc32)
+            .and_then(|x| x.extension())
+            .and_then(|x| x.to_str())
+    }
+
+    pub fn crc32s_of_class(&self, class: &str) -> Vec<u32> {
+        let mut crc32s: Vec<u32> = self
+            .index
+            .keys()
+            .copied()
+            .filter(|x| self.class(*x) == Some(class))
+            .collect();
+        crc32s.sort_unstable();
+        crc32s
+    }
+
+    pub fn read_bytes(&self, crc32: u32) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (_, header, body) = read_object(self.path(crc32)?)?;
+        Ok((header, body))
+    }
+
+    pub fn read<H, B>(&self, crc32: u32) -> Result<(H, B)>
+    where
+        for<'a> H: Parse<&'a [u8]>,
+        for<'a> B: Parse<&'a [u8]>,
+    {
+        let (header, body) = self.read_bytes(crc32)?;
+
+        let header = match H::parse(&header) {
+            Ok((_, h)) => h,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Object parser failed {}", crc32),
+                ))
+            }
+        };
+
+        let body = match B::parse(&body) {
+            Ok((_, b)) => b,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Object parser failed {}", crc32),
+                ))
+            }
+        };
+
+        Ok((header, body))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::ErrorKind;
+
+    use tempdir::TempDir;
+
+    use crate::fuel_fmt::database::ObjectDatabase;
+    use crate::names::NameDatabase;
+
+    #[test]
+    fn test_database_index() {
+        let tmp_dir = TempDir::new("dpc").unwrap();
+        let objects_path = tmp_dir.path();
+        for file_name in ["2_wheel.Mesh_Z", "1.Mesh_Z", "3_car.Skin_Z", "notes.txt"].iter() {
+            fs::write(objects_path.join(file_name), []).unwrap();
+        }
+        fs::create_dir(objects_path.join("4.Node_Z.d")).unwrap();
+
+        let mut names = NameDatabase::new();
+        names.insert(3, "named");
+        let database = ObjectDatabase::new(objects_path, names).unwrap();
+        assert_eq!(database.crc32s_of_class("Mesh_Z"), vec![1, 2]);
+        assert_eq!(database.class(3), Some("Skin_Z"));
+        assert_eq!(database.class(4), None);
+        assert_eq!(database.names().name(2), "wheel");
+        assert_eq!(database.names().name(3), "named");
+        assert_eq!(database.path(4).unwrap_err().kind(), ErrorKind::NotFound);
+
+        fs::write(objects_path.join("1_other.Mesh_Z"), []).unwrap();
+        assert!(ObjectDatabase::new(objects_path, NameDatabase::new()).is_err());
+    }
+}
@@ -0,0 +1,24 @@
+use binwrite::BinWrite;
+use nom_derive::NomLE;
+use serde::{Deserialize, Serialize};
+
+use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ResourceObjectZ, Remainder};
+
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(Serialize, Deserialize, NomLE)]
+pub struct FlareDataZ {
+    data: Remainder,
+}
+
+impl HasReferences for FlareDataZ {
+    fn hard_links(&self) -> Vec<u32> {
+        vec![]
+    }
+
+    fn soft_links(&self) -> Vec<u32> {
+        vec![]
+    }
+}
+
+pub type FlareDataObjectFormat = FUELObjectFormat<ResourceObjectZ, FlareDataZ>;
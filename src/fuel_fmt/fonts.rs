@@ -1,9 +1,25 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
 use binwrite::BinWrite;
+use image::{imageops, Rgba, RgbaImage};
 use nom_derive::NomLE;
+use rusttype::{point, Font, Scale};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, PascalArray, ResourceObjectZ};
+use crate::fuel_fmt::bitmap;
+use crate::fuel_fmt::common::{
+    image_error, FUELObjectFormat, FUELObjectFormatTrait, FormatOptions, HasReferences,
+    PascalArray, ResourceObjectZ,
+};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::material;
+use crate::File;
 
+// The glyph rectangles are texture coordinates of the material's diffuse bitmap
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
@@ -38,4 +54,504 @@ impl HasReferences for FontsZ {
     }
 }
 
-pub type FontsObjectFormat = FUELObjectFormat<ResourceObjectZ, FontsZ>;
+// Unedited pixel values keep the original coordinate so a round trip is lossless
+fn to_texture_coordinate(original: f32, pixels: u32, size: u32) -> f32 {
+    if (original * size as f32).round() as u32 == pixels {
+        original
+    } else {
+        pixels as f32 / size as f32
+    }
+}
+
+fn to_pixels(coordinate: f32, size: u32) -> u32 {
+    (coordinate * size as f32).round().max(0f32) as u32
+}
+
+// Pages are named after the .fnt, the atlas preview puts every page side by side
+fn write_bmfont(
+    fonts: &FontsZ,
+    face: &str,
+    pages: &[RgbaImage],
+    output_path: &Path,
+) -> Result<()> {
+    let stem = output_path.file_stem().and_then(|x| x.to_str()).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} has no UTF-8 file name", output_path.display()),
+        )
+    })?;
+    let page_file_name = |page: usize| format!("{}_{}.png", stem, page);
+
+    let scale_width = pages.iter().map(|x| x.width()).max().unwrap_or(0);
+    let scale_height = pages.iter().map(|x| x.height()).max().unwrap_or(0);
+
+    let mut atlas = RgbaImage::new(pages.iter().map(|x| x.width()).sum(), scale_height);
+    let mut atlas_x = 0;
+    for (i, page) in pages.iter().enumerate() {
+        page.save(output_path.with_file_name(page_file_name(i))).map_err(image_error)?;
+        imageops::replace(&mut atlas, page, atlas_x, 0);
+        atlas_x += page.width();
+    }
+    atlas.save(output_path.with_extension("png")).map_err(image_error)?;
+
+    let page_size = |material_index: u32| match pages.get(material_index as usize) {
+        Some(page) => Ok((page.width(), page.height())),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No material for material_index {}", material_index),
+        )),
+    };
+
+    let mut line_height = 0;
+    for character in fonts.characters.data.iter() {
+        let (_, height) = page_size(character.material_index)?;
+        line_height = line_height.max(to_pixels(character.height, height));
+    }
+
+    let mut fnt = String::new();
+    fnt.push_str(&format!(
+        "info face=\"{}\" size={} bold=0 italic=0 charset=\"\" unicode=1 stretchH=100 smooth=1 aa=1 padding=0,0,0,0 spacing=0,0\n",
+        face, line_height
+    ));
+    fnt.push_str(&format!(
+        "common lineHeight={} base={} scaleW={} scaleH={} pages={} packed=0\n",
+        line_height,
+        line_height,
+        scale_width,
+        scale_height,
+        pages.len()
+    ));
+    for i in 0..pages.len() {
+        fnt.push_str(&format!("page id={} file=\"{}\"\n", i, page_file_name(i)));
+    }
+    fnt.push_str(&format!("chars count={}\n", fonts.characters.data.len()));
+    for character in fonts.characters.data.iter() {
+        let (width, height) = page_size(character.material_index)?;
+        fnt.push_str(&format!(
+            "char id={} x={} y={} width={} height={} xoffset=0 yoffset=0 xadvance={} page={} chnl=15\n",
+            character.id,
+            to_pixels(character.x, width),
+            to_pixels(character.y, height),
+            to_pixels(character.width, width),
+            to_pixels(character.height, height),
+            to_pixels(character.width, width),
+            character.material_index
+        ));
+    }
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(fnt.as_bytes())?;
+
+    Ok(())
+}
+
+// Key value pairs of one line of a text .fnt, quoted values may contain spaces
+fn parse_bmfont_line(line: &str) -> (&str, HashMap<&str, &str>) {
+    let line = line.trim();
+    let (tag, mut rest) = match line.find(' ') {
+        Some(i) => (&line[..i], &line[i..]),
+        None => (line, ""),
+    };
+
+    let mut values = HashMap::new();
+    loop {
+        rest = rest.trim_start();
+        let equals = match rest.find('=') {
+            Some(equals) => equals,
+            None => break,
+        };
+        let key = &rest[..equals];
+        rest = &rest[equals + 1..];
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            rest = &quoted[(end + 1).min(quoted.len())..];
+            &quoted[..end]
+        } else {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        values.insert(key, value);
+    }
+
+    (tag, values)
+}
+
+fn bmfont_value(values: &HashMap<&str, &str>, key: &str) -> Result<u32> {
+    match values.get(key).map(|x| x.parse::<u32>()) {
+        Some(Ok(value)) => Ok(value),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Bad or missing {} in .fnt", key),
+        )),
+    }
+}
+
+// Applies the glyph rectangles of a .fnt to the matching characters, page sizes come from the page PNGs
+// The page pixels aren't imported, an edited page is packed from data.png when its Bitmap_Z has no data.dds
+fn read_bmfont(fonts: &mut FontsZ, fnt_path: &Path) -> Result<()> {
+    let fnt = fs::read_to_string(fnt_path)?;
+
+    let mut page_sizes: HashMap<u32, (u32, u32)> = HashMap::new();
+    for line in fnt.lines() {
+        let (tag, values) = parse_bmfont_line(line);
+        match tag {
+            "page" => {
+                let id = bmfont_value(&values, "id")?;
+                let file = values.get("file").ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "Missing file in .fnt page")
+                })?;
+                let size = image::image_dimensions(fnt_path.with_file_name(file))
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+                page_sizes.insert(id, size);
+            }
+            "char" => {
+                let id = bmfont_value(&values, "id")?;
+                let page = bmfont_value(&values, "page")?;
+                let (width, height) = match page_sizes.get(&page) {
+                    Some(size) => *size,
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Character {} uses undeclared page {}", id, page),
+                        ))
+                    }
+                };
+
+                let character = match fonts.characters.data.iter_mut().find(|x| x.id == id) {
+                    Some(character) => character,
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Character {} isn't in the font", id),
+                        ))
+                    }
+                };
+
+                if page as usize >= fonts.material_crc32s.data.len() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Character {} uses page {} which has no material", id, page),
+                    ));
+                }
+
+                character.material_index = page;
+                character.x = to_texture_coordinate(character.x, bmfont_value(&values, "x")?, width);
+                character.y = to_texture_coordinate(character.y, bmfont_value(&values, "y")?, height);
+                character.width =
+                    to_texture_coordinate(character.width, bmfont_value(&values, "width")?, width);
+                character.height =
+                    to_texture_coordinate(character.height, bmfont_value(&values, "height")?, height);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn export(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let (_, fonts) = database.read::<ResourceObjectZ, FontsZ>(crc32)?;
+
+    let mut pages: Vec<RgbaImage> = Vec::new();
+    for material_crc32 in fonts.material_crc32s.data.iter() {
+        let bitmap_crc32 = material::diffuse_bitmap_crc32(database, *material_crc32)?;
+        pages.push(bitmap::decode(database, bitmap_crc32)?);
+    }
+
+    write_bmfont(&fonts, &database.names().name(crc32), &pages, output_path)
+}
+
+// The material and bitmap a generated font is cloned from
+pub fn template_crc32s(database: &ObjectDatabase, crc32: u32) -> Result<(u32, u32)> {
+    let (_, fonts) = database.read::<ResourceObjectZ, FontsZ>(crc32)?;
+    let material_crc32 = match fonts.material_crc32s.data.first() {
+        Some(material_crc32) => *material_crc32,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Font {} has no material", crc32),
+            ))
+        }
+    };
+    let bitmap_crc32 = material::diffuse_bitmap_crc32(database, material_crc32)?;
+    Ok((material_crc32, bitmap_crc32))
+}
+
+// Points the unpacked material's diffuse bitmap, the bitmap diffuse_bitmap_crc32 reads, at a different bitmap
+pub fn retarget_material(material_path: &Path, bitmap_crc32: u32) -> Result<()> {
+    let json_path = material_path.join("object.json");
+    let mut object: Value = serde_json::from_reader(File::open(&json_path)?)?;
+
+    let body = &mut object["body"];
+    let field = if body.get("diffuse_bitmap_crc32").is_some() {
+        body.get_mut("diffuse_bitmap_crc32")
+    } else {
+        body.get_mut("bitmap_crc32s").and_then(|x| x.get_mut(0))
+    };
+    let template_bitmap_crc32 = match field {
+        Some(field) => std::mem::replace(field, Value::from(bitmap_crc32)),
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} has no diffuse bitmap field", json_path.display()),
+            ))
+        }
+    };
+
+    // The header lists the references too
+    if let Some(Value::Array(crc32s)) = object["header"].get_mut("crc32s") {
+        for crc32 in crc32s.iter_mut().filter(|x| **x == template_bitmap_crc32) {
+            *crc32 = Value::from(bitmap_crc32);
+        }
+    }
+
+    let mut output_file = File::create(json_path)?;
+    output_file.write_all(serde_json::to_string_pretty(&object)?.as_bytes())?;
+
+    Ok(())
+}
+
+// Glyphs rasterized from a TrueType or OpenType font, one line height tall cell per character
+pub struct FontAtlas {
+    image: RgbaImage,
+    characters: Vec<FontsZCharacter>,
+}
+
+impl FontAtlas {
+    pub fn rasterize(font_path: &Path, point_size: f32, characters: &str) -> Result<FontAtlas> {
+        let font = match Font::try_from_vec(fs::read(font_path)?) {
+            Some(font) => font,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Can't read font {}", font_path.display()),
+                ))
+            }
+        };
+
+        let scale = Scale::uniform(point_size);
+        let v_metrics = font.v_metrics(scale);
+        let cell_height = (v_metrics.ascent - v_metrics.descent).ceil().max(1f32) as u32;
+
+        let mut ids: Vec<char> = Vec::new();
+        for c in characters.chars().filter(|x| !x.is_control()) {
+            if !ids.contains(&c) {
+                ids.push(c);
+            }
+        }
+
+        let mut glyphs = Vec::new();
+        for c in ids.iter() {
+            let glyph = font.glyph(*c);
+            if glyph.id().0 == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} has no glyph for {:?}", font_path.display(), c),
+                ));
+            }
+            let glyph = glyph.scaled(scale);
+            let cell_width = glyph.h_metrics().advance_width.ceil().max(1f32) as u32;
+            glyphs.push((*c, glyph, cell_width));
+        }
+
+        // Shelf pack into the smallest power of two square that fits, padding cells so filtering doesn't bleed
+        let padding = 1;
+        let mut width = 64;
+        let mut positions: Vec<(u32, u32)>;
+        let mut height;
+        loop {
+            positions = Vec::new();
+            let (mut x, mut y) = (padding, padding);
+            for (_, _, cell_width) in glyphs.iter() {
+                if x + cell_width + padding > width {
+                    x = padding;
+                    y += cell_height + padding;
+                }
+                positions.push((x, y));
+                x += cell_width + padding;
+            }
+            height = (y + cell_height + padding).next_power_of_two();
+            if height <= width || width >= 4096 {
+                break;
+            }
+            width *= 2;
+        }
+
+        let mut image = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 0]));
+        let mut atlas_characters = Vec::new();
+        for ((c, glyph, cell_width), (x, y)) in glyphs.into_iter().zip(positions) {
+            let glyph = glyph.positioned(point(x as f32, y as f32 + v_metrics.ascent));
+            if let Some(bounding_box) = glyph.pixel_bounding_box() {
+                glyph.draw(|gx, gy, coverage| {
+                    let px = bounding_box.min.x + gx as i32;
+                    let py = bounding_box.min.y + gy as i32;
+                    // Keep overhanging glyphs out of their neighbours' cells
+                    if px >= x as i32
+                        && px < (x + cell_width) as i32
+                        && py >= y as i32
+                        && py < (y + cell_height) as i32
+                    {
+                        let alpha = (coverage * 255f32).round() as u8;
+                        image.put_pixel(px as u32, py as u32, Rgba([255, 255, 255, alpha]));
+                    }
+                });
+            }
+
+            atlas_characters.push(FontsZCharacter {
+                id: c as u32,
+                material_index: 0,
+                point: point_size,
+                height: cell_height as f32 / height as f32,
+                y: y as f32 / height as f32,
+                x: x as f32 / width as f32,
+                width: cell_width as f32 / width as f32,
+            });
+        }
+
+        Ok(FontAtlas {
+            image,
+            characters: atlas_characters,
+        })
+    }
+
+    // The bitmap format packs data.png when there is no data.dds
+    pub fn write_bitmap(&self, bitmap_path: &Path) -> Result<()> {
+        let dds_path = bitmap_path.join("data.dds");
+        if dds_path.exists() {
+            fs::remove_file(dds_path)?;
+        }
+
+        self.image
+            .save(bitmap_path.join("data.png"))
+            .map_err(image_error)
+    }
+
+    pub fn write_fonts(self, fonts_path: &Path, material_crc32: u32) -> Result<()> {
+        #[derive(Serialize, Deserialize)]
+        struct Object {
+            header: ResourceObjectZ,
+            body: FontsZ,
+        }
+
+        let json_path = fonts_path.join("object.json");
+        let mut object: Object = serde_json::from_reader(File::open(&json_path)?)?;
+
+        if let Some(crc32s) = &mut object.header.crc32s {
+            let template_material_crc32s = &object.body.material_crc32s.data;
+            crc32s.data.retain(|x| !template_material_crc32s.contains(x));
+            crc32s.data.push(material_crc32);
+        }
+        object.body.characters.data = self.characters;
+        object.body.material_crc32s.data = vec![material_crc32];
+
+        let mut output_file = File::create(json_path)?;
+        output_file.write_all(serde_json::to_string_pretty(&object)?.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+pub struct FontsObjectFormat;
+
+impl FontsObjectFormat {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for FontsObjectFormat {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // An exported font.fnt in the object directory overrides the glyphs in object.json
+        let fnt_path = input_path.join("font.fnt");
+        if !fnt_path.exists() {
+            return FUELObjectFormat::<ResourceObjectZ, FontsZ>::new()
+                .pack(input_path, header, body, options);
+        }
+
+        FUELObjectFormat::<ResourceObjectZ, FontsZ>::new().pack_with(input_path, header, body, |_, body| {
+            read_bmfont(body, &fnt_path)
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ResourceObjectZ, FontsZ>::new().unpack(header, body, output_path, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::ErrorKind;
+
+    use binwrite::BinWrite;
+    use image::RgbaImage;
+    use nom_derive::Parse;
+    use tempdir::TempDir;
+
+    use crate::fuel_fmt::fonts::{parse_bmfont_line, read_bmfont, write_bmfont, FontsZ};
+
+    fn fonts_bytes() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&65u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        for value in [0.1f32, 0.25, 0.5, 0.3, 0.125].iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_fonts_parse_bmfont_line() {
+        let (tag, values) = parse_bmfont_line(r#"info face="Some Font" size=12 padding=0,0,0,0"#);
+        assert_eq!(tag, "info");
+        assert_eq!(values["face"], "Some Font");
+        assert_eq!(values["size"], "12");
+        assert_eq!(values["padding"], "0,0,0,0");
+        assert!(parse_bmfont_line("chars").1.is_empty());
+    }
+
+    #[test]
+    fn test_fonts_bmfont_round_trip() {
+        let tmp_dir = TempDir::new("dpc").unwrap();
+        let fnt_path = tmp_dir.path().join("font.fnt");
+        let (_, fonts) = FontsZ::parse(&fonts_bytes()).unwrap();
+        write_bmfont(&fonts, "font", &[RgbaImage::new(64, 32)], &fnt_path).unwrap();
+        assert!(tmp_dir.path().join("font_0.png").is_file());
+
+        let fnt = fs::read_to_string(&fnt_path).unwrap();
+        assert!(fnt.contains("char id=65 x=19 y=16 width=8 height=8 xoffset=0 yoffset=0 xadvance=8 page=0"));
+
+        let (_, mut edited) = FontsZ::parse(&fonts_bytes()).unwrap();
+        read_bmfont(&mut edited, &fnt_path).unwrap();
+        let mut written = Vec::new();
+        edited.write(&mut written).unwrap();
+        assert_eq!(written, fonts_bytes());
+
+        fs::write(&fnt_path, fnt.replace("x=19 ", "x=16 ")).unwrap();
+        read_bmfont(&mut edited, &fnt_path).unwrap();
+        assert_eq!(edited.characters.data[0].x, 0.25);
+
+        fs::write(&fnt_path, fnt.replace("char id=65", "char id=66")).unwrap();
+        assert!(read_bmfont(&mut edited, &fnt_path).is_err());
+
+        let error = write_bmfont(&fonts, "font", &[], &tmp_dir.path().join("..")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -1,8 +1,16 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
 use binwrite::BinWrite;
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::fuel_fmt::common::{FUELObjectFormat, FixedVec, HasReferences, Mat4f, ObjectZ, PascalArray, PascalStringNULL, Quat, Vec2f, Vec3f, FixedStringNULL};
+use crate::fuel_fmt::common::{FUELObjectFormat, FUELObjectFormatTrait, FixedVec, FormatOptions, HasReferences, Mat4f, ObjectZ, PascalArray, PascalStringNULL, Quat, Vec2f, Vec3f, FixedStringNULL, EXPORT_COLORS};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::File;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -98,4 +106,458 @@ impl HasReferences for GenWorldZ {
     }
 }
 
-pub type GenWorldObjectFormat = FUELObjectFormat<ObjectZ, GenWorldZ>;
+const REGION_NAME_LENGTH: usize = 31;
+
+impl GenWorldZ {
+    // Chains the region's line segments end to end, a chain is closed when it ends where it starts
+    fn region_chains(&self, region: &Region) -> Result<Vec<Vec<[f32; 2]>>> {
+        let bad_index = |kind: &str, i: u32| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Region {} uses {} {} which doesn't exist", region.name.data, kind, i),
+            )
+        };
+
+        let mut segments: Vec<(u32, u32)> = region
+            .coords_line_segments_indices
+            .data
+            .iter()
+            .map(|x| {
+                self.coords_line_segments
+                    .data
+                    .get(*x as usize)
+                    .map(|x| (x.coords_index_a, x.coords_index_b))
+                    .ok_or_else(|| bad_index("segment", *x))
+            })
+            .collect::<Result<Vec<(u32, u32)>>>()?;
+
+        let mut chains: Vec<Vec<u32>> = Vec::new();
+        while !segments.is_empty() {
+            let (a, b) = segments.remove(0);
+            let mut chain = vec![a, b];
+            // Grow the end first then the start, segments needn't be in order or facing the same way
+            for _ in 0..2 {
+                while chain.first() != chain.last() {
+                    let end = *chain.last().unwrap();
+                    match segments.iter().position(|(a, b)| *a == end || *b == end) {
+                        Some(i) => {
+                            let (a, b) = segments.remove(i);
+                            chain.push(if a == end { b } else { a });
+                        }
+                        None => break,
+                    }
+                }
+                chain.reverse();
+            }
+            chains.push(chain);
+        }
+
+        chains
+            .iter()
+            .map(|chain| {
+                chain
+                    .iter()
+                    .map(|x| {
+                        self.coords
+                            .data
+                            .get(*x as usize)
+                            .map(|x| [x.data[0], x.data[1]])
+                            .ok_or_else(|| bad_index("coord", *x))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Replaces the coords and segments of every region, equal coords and segments are shared
+    fn set_region_chains(&mut self, region_chains: &[Vec<Vec<[f32; 2]>>]) -> Result<()> {
+        if region_chains.len() != self.regions.data.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} regions but chains for {}", self.regions.data.len(), region_chains.len()),
+            ));
+        }
+        if let Some(chain) = region_chains.iter().flatten().find(|x| x.len() < 2) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("A region chain needs at least 2 coords, not {}", chain.len()),
+            ));
+        }
+
+        let mut coords: Vec<[f32; 2]> = Vec::new();
+        let mut coord_indices: HashMap<[u32; 2], u32> = HashMap::new();
+        let mut segments: Vec<(u32, u32)> = Vec::new();
+        let mut segment_indices: HashMap<(u32, u32), u32> = HashMap::new();
+
+        for (region, chains) in self.regions.data.iter_mut().zip(region_chains.iter()) {
+            region.coords_line_segments_indices.data.clear();
+            for chain in chains.iter() {
+                let indices: Vec<u32> = chain
+                    .iter()
+                    .map(|coord| {
+                        *coord_indices
+                            .entry([coord[0].to_bits(), coord[1].to_bits()])
+                            .or_insert_with(|| {
+                                coords.push(*coord);
+                                coords.len() as u32 - 1
+                            })
+                    })
+                    .collect();
+
+                for pair in indices.windows(2) {
+                    let key = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+                    let segment_index = *segment_indices.entry(key).or_insert_with(|| {
+                        segments.push((pair[0], pair[1]));
+                        segments.len() as u32 - 1
+                    });
+                    region.coords_line_segments_indices.data.push(segment_index);
+                }
+            }
+        }
+
+        self.coords.data = coords
+            .into_iter()
+            .map(|x| FixedVec { data: x.to_vec() })
+            .collect();
+        self.coords_line_segments.data = segments
+            .into_iter()
+            .map(|(a, b)| CoordsLineSegment {
+                coords_index_a: a,
+                coords_index_b: b,
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    // The names are written NULL terminated in a fixed size field
+    fn check_region_names(&self) -> Result<()> {
+        match self.regions.data.iter().find(|x| x.name.data.len() >= REGION_NAME_LENGTH) {
+            Some(region) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Region name {} is longer than {} bytes", region.name.data, REGION_NAME_LENGTH - 1),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn region_geometry(chains: &[Vec<[f32; 2]>]) -> Value {
+    if chains.iter().all(|x| x.len() > 3 && x.first() == x.last()) {
+        json!({ "type": "Polygon", "coordinates": chains })
+    } else {
+        json!({ "type": "MultiLineString", "coordinates": chains })
+    }
+}
+
+fn geometry_chains(geometry: &Value) -> Result<Vec<Vec<[f32; 2]>>> {
+    let bad_geometry = || Error::new(ErrorKind::InvalidData, "Bad region geometry");
+    let coordinates = geometry.get("coordinates").ok_or_else(bad_geometry)?.clone();
+    match geometry.get("type").and_then(|x| x.as_str()) {
+        Some("LineString") => Ok(vec![serde_json::from_value(coordinates)?]),
+        Some("Polygon") | Some("MultiLineString") => Ok(serde_json::from_value(coordinates)?),
+        Some("MultiPolygon") => {
+            let polygons: Vec<Vec<Vec<[f32; 2]>>> = serde_json::from_value(coordinates)?;
+            Ok(polygons.into_iter().flatten().collect())
+        }
+        _ => Err(bad_geometry()),
+    }
+}
+
+fn write_regions_geojson(gen_world: &GenWorldZ, output_path: &Path) -> Result<()> {
+    let features: Vec<Value> = gen_world
+        .regions
+        .data
+        .iter()
+        .enumerate()
+        .map(|(i, region)| {
+            Ok(json!({
+                "type": "Feature",
+                "geometry": region_geometry(&gen_world.region_chains(region)?),
+                "properties": {
+                    "name": region.name.data,
+                    "fill": EXPORT_COLORS[i % EXPORT_COLORS.len()],
+                },
+            }))
+        })
+        .collect::<Result<Vec<Value>>>()?;
+
+    let collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(serde_json::to_string_pretty(&collection)?.as_bytes())?;
+    Ok(())
+}
+
+// SVG's y axis points down so y is flipped
+fn write_regions_svg(gen_world: &GenWorldZ, output_path: &Path) -> Result<()> {
+    let mut min = [f32::MAX; 2];
+    let mut max = [f32::MIN; 2];
+    for coord in gen_world.coords.data.iter() {
+        for i in 0..2 {
+            min[i] = min[i].min(coord.data[i]);
+            max[i] = max[i].max(coord.data[i]);
+        }
+    }
+    let size = [(max[0] - min[0]).max(1f32), (max[1] - min[1]).max(1f32)];
+    let stroke_width = size[0].max(size[1]) / 1000f32;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+        min[0],
+        0f32 - max[1],
+        size[0],
+        size[1]
+    );
+    for (i, region) in gen_world.regions.data.iter().enumerate() {
+        let mut d = String::new();
+        for chain in gen_world.region_chains(region)?.iter() {
+            for (j, coord) in chain.iter().enumerate() {
+                d.push_str(&format!(
+                    "{}{},{} ",
+                    if j == 0 { "M" } else { "L" },
+                    coord[0],
+                    0f32 - coord[1]
+                ));
+            }
+        }
+        svg.push_str(&format!(
+            "  <path d=\"{}\" fill=\"{}\" fill-opacity=\"0.5\" fill-rule=\"evenodd\" stroke=\"black\" stroke-width=\"{}\"><title>{}</title></path>\n",
+            d.trim_end(),
+            EXPORT_COLORS[i % EXPORT_COLORS.len()],
+            stroke_width,
+            region.name.data
+        ));
+    }
+    svg.push_str("</svg>\n");
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(svg.as_bytes())?;
+    Ok(())
+}
+
+// Replaces the shapes of the regions named in the GeoJSON, unknown names add new regions
+fn read_regions_geojson(gen_world: &mut GenWorldZ, geojson_path: &Path) -> Result<()> {
+    let collection: Value = serde_json::from_str(&fs::read_to_string(geojson_path)?)?;
+
+    let mut region_chains: Vec<Vec<Vec<[f32; 2]>>> = gen_world
+        .regions
+        .data
+        .iter()
+        .map(|x| gen_world.region_chains(x))
+        .collect::<Result<Vec<Vec<Vec<[f32; 2]>>>>>()?;
+    let mut edited = false;
+
+    let features = collection
+        .get("features")
+        .and_then(|x| x.as_array())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Expected a GeoJSON FeatureCollection"))?;
+
+    for feature in features.iter() {
+        let name = feature
+            .pointer("/properties/name")
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Region without a name"))?;
+        let chains = geometry_chains(feature.get("geometry").unwrap_or(&Value::Null))?;
+
+        match gen_world.regions.data.iter().position(|x| x.name.data == name) {
+            Some(i) => {
+                edited |= region_chains[i] != chains;
+                region_chains[i] = chains;
+            }
+            None => {
+                gen_world.regions.data.push(Region {
+                    name: FixedStringNULL {
+                        data: name.to_string(),
+                    },
+                    always_255: 255,
+                    coords_line_segments_indices: PascalArray { data: vec![] },
+                });
+                region_chains.push(chains);
+                edited = true;
+            }
+        }
+    }
+
+    // Unedited regions keep the coords and segments in their original order
+    if edited {
+        gen_world.set_region_chains(&region_chains)?;
+    }
+
+    Ok(())
+}
+
+// Node names for every category, one list per node crc32 array
+fn write_categories(gen_world: &GenWorldZ, database: &ObjectDatabase, output_path: &Path) -> Result<()> {
+    let categories: Vec<Value> = gen_world
+        .categories
+        .data
+        .iter()
+        .map(|category| {
+            let nodes: Vec<Vec<String>> = category
+                .node_crc32s_arrays
+                .data
+                .iter()
+                .map(|x| x.data.iter().map(|x| database.names().name(*x)).collect())
+                .collect();
+            json!({
+                "name": category.name.data,
+                "nodes": nodes,
+            })
+        })
+        .collect();
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(serde_json::to_string_pretty(&categories)?.as_bytes())?;
+    Ok(())
+}
+
+pub fn export_regions(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let (_, gen_world) = database.read::<ObjectZ, GenWorldZ>(crc32)?;
+
+    match output_path.extension().and_then(|x| x.to_str()) {
+        Some("geojson") | Some("json") => write_regions_geojson(&gen_world, output_path),
+        Some("svg") => write_regions_svg(&gen_world, output_path),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "region exports are written as .geojson or .svg",
+        )),
+    }
+}
+
+pub fn export_categories(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let (_, gen_world) = database.read::<ObjectZ, GenWorldZ>(crc32)?;
+    write_categories(&gen_world, database, output_path)
+}
+
+pub struct GenWorldObjectFormat;
+
+impl GenWorldObjectFormat {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for GenWorldObjectFormat {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        _options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // An exported regions.geojson in the object directory overrides the region shapes in object.json
+        let geojson_path = input_path.join("regions.geojson");
+        FUELObjectFormat::<ObjectZ, GenWorldZ>::new().pack_with(input_path, header, body, |_, body| {
+            if geojson_path.exists() {
+                read_regions_geojson(body, &geojson_path)?;
+            }
+            body.check_region_names()
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ObjectZ, GenWorldZ>::new().unpack(header, body, output_path, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+    use tempdir::TempDir;
+
+    use crate::fuel_fmt::common::{FixedStringNULL, FixedVec, PascalArray};
+    use crate::fuel_fmt::genworld::{read_regions_geojson, write_regions_geojson, CoordsLineSegment, GenWorldZ, Region};
+
+    // A square region whose segments are out of order and face both ways
+    fn gen_world() -> GenWorldZ {
+        let (_, mut gen_world) = GenWorldZ::parse(&[0u8; 56]).unwrap();
+        gen_world.coords.data = [[0f32, 0f32], [1f32, 0f32], [1f32, 1f32], [0f32, 1f32]]
+            .iter()
+            .map(|x| FixedVec { data: x.to_vec() })
+            .collect();
+        gen_world.coords_line_segments.data = [(0, 1), (2, 3), (2, 1), (3, 0)]
+            .iter()
+            .map(|(a, b)| CoordsLineSegment {
+                coords_index_a: *a,
+                coords_index_b: *b,
+            })
+            .collect();
+        gen_world.regions.data.push(Region {
+            name: FixedStringNULL {
+                data: String::from("square"),
+            },
+            always_255: 255,
+            coords_line_segments_indices: PascalArray { data: vec![0, 1, 2, 3] },
+        });
+        gen_world
+    }
+
+    fn bytes(gen_world: &GenWorldZ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        gen_world.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_region_chains() {
+        let mut gen_world = gen_world();
+        let chains = gen_world.region_chains(&gen_world.regions.data[0]).unwrap();
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0].len(), 5);
+        assert_eq!(chains[0].first(), chains[0].last());
+
+        gen_world.set_region_chains(&[chains.clone()]).unwrap();
+        assert_eq!(gen_world.coords.data.len(), 4);
+        assert_eq!(gen_world.coords_line_segments.data.len(), 4);
+        assert_eq!(gen_world.region_chains(&gen_world.regions.data[0]).unwrap(), chains);
+    }
+
+    #[test]
+    fn test_region_chains_bad_index() {
+        let mut gen_world = gen_world();
+        gen_world.regions.data[0].coords_line_segments_indices.data.push(4);
+        assert!(gen_world.region_chains(&gen_world.regions.data[0]).is_err());
+
+        let mut gen_world = self::gen_world();
+        gen_world.coords_line_segments.data[0].coords_index_b = 4;
+        assert!(gen_world.region_chains(&gen_world.regions.data[0]).is_err());
+
+        let mut gen_world = self::gen_world();
+        assert!(gen_world.set_region_chains(&[vec![vec![[0f32, 0f32]]]]).is_err());
+        assert!(gen_world.set_region_chains(&[]).is_err());
+    }
+
+    #[test]
+    fn test_region_names() {
+        let mut gen_world = gen_world();
+        assert!(gen_world.check_region_names().is_ok());
+        let original = bytes(&gen_world);
+        gen_world.regions.data[0].name.data = "x".repeat(31);
+        assert!(gen_world.check_region_names().is_err());
+        gen_world.regions.data[0].name.data = String::from("square");
+        assert_eq!(original, bytes(&gen_world));
+    }
+
+    #[test]
+    fn test_regions_geojson_unedited() {
+        let mut gen_world = gen_world();
+        let original = bytes(&gen_world);
+
+        let tmp_dir = TempDir::new("dpc").unwrap();
+        let geojson_path = tmp_dir.path().join("regions.geojson");
+        write_regions_geojson(&gen_world, &geojson_path).unwrap();
+        read_regions_geojson(&mut gen_world, &geojson_path).unwrap();
+        assert_eq!(original, bytes(&gen_world));
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use gltf_json::accessor::{ComponentType, GenericComponentType, Type};
+use gltf_json::buffer::{Target, View};
+use gltf_json::extensions::root::KhrLightsPunctual;
+use gltf_json::extensions::scene::khr_lights_punctual::Light;
+use gltf_json::validation::{Checked, USize64};
+use gltf_json::{Accessor, Asset, Buffer, Image, Index, Material, Node, Root, Scene, Texture, Value};
+use image::RgbaImage;
+
+use crate::fuel_fmt::common::image_error;
+
+pub trait AccessorComponent: Copy {
+    const COMPONENT_TYPE: ComponentType;
+    fn write_le(&self, buffer: &mut Vec<u8>);
+}
+
+impl AccessorComponent for f32 {
+    const COMPONENT_TYPE: ComponentType = ComponentType::F32;
+    fn write_le(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl AccessorComponent for u32 {
+    const COMPONENT_TYPE: ComponentType = ComponentType::U32;
+    fn write_le(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl AccessorComponent for u16 {
+    const COMPONENT_TYPE: ComponentType = ComponentType::U16;
+    fn write_le(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl AccessorComponent for u8 {
+    const COMPONENT_TYPE: ComponentType = ComponentType::U8;
+    fn write_le(&self, buffer: &mut Vec<u8>) {
+        buffer.push(*self);
+    }
+}
+
+pub fn node(name: String) -> Node {
+    Node {
+        camera: None,
+        children: None,
+        extensions: None,
+        extras: Default::default(),
+        matrix: None,
+        mesh: None,
+        name: Some(name),
+        rotation: None,
+        scale: None,
+        translation: None,
+        skin: None,
+        weights: None,
+    }
+}
+
+// Collects the glTF document and a single binary buffer written next to it as a .bin,
+// images are written next to it as <stem>_<crc32>.png
+pub struct GltfBuilder {
+    pub root: Root,
+    buffer: Vec<u8>,
+    images: Vec<(u32, RgbaImage)>,
+    // Objects shared between meshes are pushed once
+    pub materials: HashMap<u32, Index<Material>>,
+    pub textures: HashMap<u32, Index<Texture>>,
+}
+
+impl GltfBuilder {
+    pub fn new() -> GltfBuilder {
+        GltfBuilder {
+            root: Root {
+                asset: Asset {
+                    generator: Some(String::from("dpc")),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            buffer: Vec::new(),
+            images: Vec::new(),
+            materials: HashMap::new(),
+            textures: HashMap::new(),
+        }
+    }
+
+    pub fn push_texture(&mut self, crc32: u32, name: String, image: RgbaImage) -> Index<Texture> {
+        let source = self.root.push(Image {
+            buffer_view: None,
+            mime_type: None,
+            name: Some(name.clone()),
+            uri: None,
+            extensions: None,
+            extras: Default::default(),
+        });
+        self.images.push((crc32, image));
+
+        let texture = self.root.push(Texture {
+            name: Some(name),
+            sampler: None,
+            source,
+            extensions: None,
+            extras: Default::default(),
+        });
+        self.textures.insert(crc32, texture);
+        texture
+    }
+
+    pub fn push_accessor<C: AccessorComponent>(
+        &mut self,
+        data: &[C],
+        type_: Type,
+        target: Option<Target>,
+    ) -> Index<Accessor> {
+        // Vertex attributes must be 4 byte aligned
+        while !self.buffer.len().is_multiple_of(4) {
+            self.buffer.push(0);
+        }
+
+        let byte_offset = self.buffer.len();
+        for component in data.iter() {
+            component.write_le(&mut self.buffer);
+        }
+
+        let view = self.root.push(View {
+            buffer: Index::new(0),
+            byte_length: USize64::from(self.buffer.len() - byte_offset),
+            byte_offset: Some(USize64::from(byte_offset)),
+            byte_stride: None,
+            name: None,
+            target: target.map(Checked::Valid),
+            extensions: None,
+            extras: Default::default(),
+        });
+
+        self.root.push(Accessor {
+            buffer_view: Some(view),
+            byte_offset: None,
+            count: USize64::from(data.len() / type_.multiplicity()),
+            component_type: Checked::Valid(GenericComponentType(C::COMPONENT_TYPE)),
+            extensions: None,
+            extras: Default::default(),
+            type_: Checked::Valid(type_),
+            min: None,
+            max: None,
+            name: None,
+            normalized: false,
+            sparse: None,
+        })
+    }
+
+    // POSITION attributes and animation inputs are required to carry their bounds
+    pub fn push_bounded_accessor(
+        &mut self,
+        data: &[f32],
+        type_: Type,
+        target: Option<Target>,
+    ) -> Index<Accessor> {
+        let multiplicity = type_.multiplicity();
+        let mut min = vec![f32::MAX; multiplicity];
+        let mut max = vec![f32::MIN; multiplicity];
+        for element in data.chunks(multiplicity) {
+            for (i, component) in element.iter().enumerate() {
+                min[i] = min[i].min(*component);
+                max[i] = max[i].max(*component);
+            }
+        }
+
+        let accessor = self.push_accessor(data, type_, target);
+        let json_accessor = &mut self.root.accessors[accessor.value()];
+        json_accessor.min = Some(Value::from(min));
+        json_accessor.max = Some(Value::from(max));
+        accessor
+    }
+
+    pub fn push_light(&mut self, light: Light) -> Index<Light> {
+        let extension = String::from("KHR_lights_punctual");
+        if !self.root.extensions_used.contains(&extension) {
+            self.root.extensions_used.push(extension);
+        }
+
+        let lights = &mut self
+            .root
+            .extensions
+            .get_or_insert_with(Default::default)
+            .khr_lights_punctual
+            .get_or_insert_with(|| KhrLightsPunctual { lights: Vec::new() })
+            .lights;
+        lights.push(light);
+        Index::new(lights.len() as u32 - 1)
+    }
+
+    pub fn push_scene(&mut self, nodes: Vec<Index<Node>>) {
+        let scene = self.root.push(Scene {
+            extensions: None,
+            extras: Default::default(),
+            name: None,
+            nodes,
+        });
+        self.root.scene = Some(scene);
+    }
+
+    pub fn write(mut self, output_path: &Path) -> Result<()> {
+        let stem = output_path.file_stem().and_then(|x| x.to_str()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} has no UTF-8 file name", output_path.display()),
+            )
+        })?;
+
+        // The uris are relative to the .gltf
+        for (gltf_image, (crc32, image)) in self.root.images.iter_mut().zip(self.images.iter()) {
+            let image_file_name = format!("{}_{}.png", stem, crc32);
+            image.save(output_path.with_file_name(&image_file_name)).map_err(image_error)?;
+            gltf_image.uri = Some(image_file_name);
+        }
+
+        let bin_file_name = format!("{}.bin", stem);
+        self.root.push(Buffer {
+            byte_length: USize64::from(self.buffer.len()),
+            name: None,
+            uri: Some(bin_file_name.clone()),
+            extensions: None,
+            extras: Default::default(),
+        });
+
+        let mut bin_file = File::create(output_path.with_file_name(bin_file_name))?;
+        bin_file.write_all(&self.buffer)?;
+
+        let mut output_file = File::create(output_path)?;
+        output_file.write_all(serde_json::to_string_pretty(&self.root)?.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use gltf_json::accessor::Type;
+    use gltf_json::Value;
+    use image::RgbaImage;
+    use tempdir::TempDir;
+
+    use crate::fuel_fmt::gltf::GltfBuilder;
+
+    #[test]
+    fn test_gltf_accessors() {
+        let mut builder = GltfBuilder::new();
+        let indices = builder.push_accessor(&[0u8, 1, 2], Type::Scalar, None);
+        let positions = builder.push_bounded_accessor(&[0.0, 2.0, -1.0, 1.0, -3.0, 4.0], Type::Vec3, None);
+
+        let positions = &builder.root.accessors[positions.value()];
+        assert_eq!(builder.root.accessors[indices.value()].count.0, 3);
+        assert_eq!(positions.count.0, 2);
+        assert_eq!(positions.min, Some(Value::from(vec![0.0f32, -3.0, -1.0])));
+        assert_eq!(positions.max, Some(Value::from(vec![1.0f32, 2.0, 4.0])));
+        assert_eq!(builder.root.buffer_views[1].byte_offset.unwrap().0, 4);
+        assert_eq!(builder.buffer.len(), 4 + 6 * 4);
+    }
+
+    #[test]
+    fn test_gltf_write() {
+        let tmp_dir = TempDir::new("dpc").unwrap();
+        let output_path = tmp_dir.path().join("scene.gltf");
+
+        let mut builder = GltfBuilder::new();
+        builder.push_texture(42, String::from("texture"), RgbaImage::new(1, 1));
+        builder.push_accessor(&[1.0f32], Type::Scalar, None);
+        builder.push_scene(vec![]);
+        builder.write(&output_path).unwrap();
+
+        assert_eq!(std::fs::read(tmp_dir.path().join("scene.bin")).unwrap().len(), 4);
+        assert!(tmp_dir.path().join("scene_42.png").is_file());
+        let root: serde_json::Value = serde_json::from_slice(&std::fs::read(&output_path).unwrap()).unwrap();
+        assert_eq!(root["buffers"][0]["uri"], "scene.bin");
+        assert_eq!(root["images"][0]["uri"], "scene_42.png");
+
+        let error = GltfBuilder::new().write(&tmp_dir.path().join("..")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,17 +1,78 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
 use binwrite::BinWrite;
+use half::f16;
 use nom_derive::NomLE;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
 
-use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ResourceObjectZ, Vec2f};
+use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ResourceObjectZ, Vec2f, EXPORT_COLORS};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::File;
 
+// x is in the low half of encoded_vec2hf and y in the high half
 #[derive(BinWrite)]
 #[binwrite(little)]
-#[derive(Serialize, Deserialize, NomLE)]
+#[derive(NomLE)]
 struct GwRoadZPoint {
     encoded_vec2hf: u32,
     a: u8,
 }
 
+// Points that don't decode to finite floats can't be written to JSON so they stay encoded
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum GwRoadZPointShadow {
+    Decoded { x: f32, y: f32, a: u8 },
+    Encoded { encoded_vec2hf: u32, a: u8 },
+}
+
+impl GwRoadZPoint {
+    fn decode(&self) -> (f32, f32) {
+        (
+            f16::from_bits(self.encoded_vec2hf as u16).to_f32(),
+            f16::from_bits((self.encoded_vec2hf >> 16) as u16).to_f32(),
+        )
+    }
+}
+
+impl Serialize for GwRoadZPoint {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        let (x, y) = self.decode();
+        if x.is_finite() && y.is_finite() {
+            GwRoadZPointShadow::Decoded { x, y, a: self.a }
+        } else {
+            GwRoadZPointShadow::Encoded {
+                encoded_vec2hf: self.encoded_vec2hf,
+                a: self.a,
+            }
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GwRoadZPoint {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        Ok(match GwRoadZPointShadow::deserialize(deserializer)? {
+            GwRoadZPointShadow::Decoded { x, y, a } => GwRoadZPoint {
+                encoded_vec2hf: f16::from_f32(x).to_bits() as u32
+                    | (f16::from_f32(y).to_bits() as u32) << 16,
+                a,
+            },
+            GwRoadZPointShadow::Encoded { encoded_vec2hf, a } => {
+                GwRoadZPoint { encoded_vec2hf, a }
+            }
+        })
+    }
+}
+
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
@@ -98,4 +159,160 @@ impl HasReferences for GwRoadZ {
     }
 }
 
+impl GwRoadZ {
+    // Points that don't decode to finite coordinates are left out
+    fn polylines(&self) -> impl Iterator<Item = (u8, Vec<(f32, f32)>)> + '_ {
+        self.roads.iter().map(|road| {
+            let points = road
+                .points
+                .iter()
+                .map(|x| x.decode())
+                .filter(|(x, y)| x.is_finite() && y.is_finite())
+                .collect();
+            (road.road_type, points)
+        })
+    }
+}
+
+fn write_geojson(gw_road: &GwRoadZ, output_path: &Path) -> Result<()> {
+    let features: Vec<serde_json::Value> = gw_road
+        .polylines()
+        .map(|(road_type, points)| {
+            let coordinates: Vec<[f32; 2]> = points.iter().map(|(x, y)| [*x, *y]).collect();
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": coordinates,
+                },
+                "properties": {
+                    "road_type": road_type,
+                    "stroke": EXPORT_COLORS[road_type as usize % EXPORT_COLORS.len()],
+                },
+            })
+        })
+        .collect();
+
+    let collection = json!({
+        "type": "FeatureCollection",
+        "bbox": [
+            gw_road.gen_road_min.data[0],
+            gw_road.gen_road_min.data[1],
+            gw_road.gen_road_max.data[0],
+            gw_road.gen_road_max.data[1],
+        ],
+        "features": features,
+    });
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(serde_json::to_string_pretty(&collection)?.as_bytes())?;
+    Ok(())
+}
+
+// The view box is the generated road bounds, SVG's y axis points down so y is flipped
+fn write_svg(gw_road: &GwRoadZ, output_path: &Path) -> Result<()> {
+    let (min_x, min_y) = (gw_road.gen_road_min.data[0], gw_road.gen_road_min.data[1]);
+    let (max_x, max_y) = (gw_road.gen_road_max.data[0], gw_road.gen_road_max.data[1]);
+    let stroke_width = (max_x - min_x).max(max_y - min_y).max(1f32) / 1000f32;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+        min_x,
+        0f32 - max_y,
+        max_x - min_x,
+        max_y - min_y
+    );
+    for (road_type, points) in gw_road.polylines() {
+        let points: Vec<String> = points
+            .iter()
+            .map(|(x, y)| format!("{},{}", x, 0f32 - y))
+            .collect();
+        svg.push_str(&format!(
+            "  <polyline class=\"road_type_{}\" points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>\n",
+            road_type,
+            points.join(" "),
+            EXPORT_COLORS[road_type as usize % EXPORT_COLORS.len()],
+            stroke_width
+        ));
+    }
+    svg.push_str("</svg>\n");
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(svg.as_bytes())?;
+    Ok(())
+}
+
+pub fn export(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let (_, gw_road) = database.read::<ResourceObjectZ, GwRoadZ>(crc32)?;
+
+    match output_path.extension().and_then(|x| x.to_str()) {
+        Some("geojson") | Some("json") => write_geojson(&gw_road, output_path),
+        Some("svg") => write_svg(&gw_road, output_path),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "road exports are written as .geojson or .svg",
+        )),
+    }
+}
+
 pub type GwRoadObjectFormat = FUELObjectFormat<ResourceObjectZ, GwRoadZ>;
+
+#[cfg(test)]
+mod test {
+    use half::f16;
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::common::assert_round_trip;
+    use crate::fuel_fmt::gwroad::{GwRoadZ, GwRoadZPoint};
+
+    fn encode(x: f32, y: f32) -> u32 {
+        f16::from_f32(x).to_bits() as u32 | (f16::from_f32(y).to_bits() as u32) << 16
+    }
+
+    #[test]
+    fn test_gwroad_point() {
+        let point = GwRoadZPoint {
+            encoded_vec2hf: encode(1.5, -256.0),
+            a: 7,
+        };
+        assert_eq!(point.decode(), (1.5, -256.0));
+        let json = serde_json::to_string(&point).unwrap();
+        assert_eq!(json, r#"{"x":1.5,"y":-256.0,"a":7}"#);
+        let point: GwRoadZPoint = serde_json::from_str(&json).unwrap();
+        assert_eq!(point.encoded_vec2hf, encode(1.5, -256.0));
+
+        let nan = GwRoadZPoint {
+            encoded_vec2hf: 0x7E00 << 16 | 0x3C00,
+            a: 0,
+        };
+        let json = serde_json::to_string(&nan).unwrap();
+        assert_eq!(json, r#"{"encoded_vec2hf":2113944576,"a":0}"#);
+        let nan: GwRoadZPoint = serde_json::from_str(&json).unwrap();
+        assert_eq!(nan.encoded_vec2hf, 0x7E00 << 16 | 0x3C00);
+    }
+
+    #[test]
+    fn test_gwroad_round_trip() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        for value in [-1.0f32, -2.0, 3.0, 4.0].iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.push(2);
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        for (x, y, a) in [(0.5f32, 1.0f32, 1u8), (-1.0, 3.0, 0)].iter() {
+            bytes.extend_from_slice(&encode(*x, *y).to_le_bytes());
+            bytes.push(*a);
+        }
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        for value in [0.0f32, 0.0, 0.0, 0.0].iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&0x12345678u32.to_le_bytes());
+        assert_round_trip::<GwRoadZ>(&bytes);
+
+        let (_, gw_road) = GwRoadZ::parse(&bytes).unwrap();
+        let polylines: Vec<_> = gw_road.polylines().collect();
+        assert_eq!(polylines, vec![(2, vec![(0.5, 1.0), (-1.0, 3.0)])]);
+    }
+}
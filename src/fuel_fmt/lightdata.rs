@@ -27,4 +27,10 @@ impl HasReferences for LightDataZ {
     }
 }
 
+impl LightDataZ {
+    pub fn color(&self) -> [f32; 3] {
+        [self.color.data[0], self.color.data[1], self.color.data[2]]
+    }
+}
+
 pub type LightDataObjectFormat = FUELObjectFormat<ResourceObjectZ, LightDataZ>;
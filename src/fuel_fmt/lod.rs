@@ -1,8 +1,14 @@
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::path::Path;
+
 use binwrite::BinWrite;
-use nom_derive::NomLE;
+use nom_derive::{NomLE, Parse};
 use serde::{Deserialize, Serialize};
 
-use crate::fuel_fmt::common::{write_option, FUELObjectFormat, HasReferences, ObjectZ, PascalArray, DynSphere, DynBox};
+use crate::fuel_fmt::common::{write_option, FUELObjectFormat, FUELObjectFormatTrait, FormatOptions, HasReferences, ObjectZ, PascalArray, DynSphere, DynBox};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::volume::{self, Volume, VolumeShape};
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -184,6 +190,150 @@ impl HasReferences for LodZAltAlt {
     }
 }
 
-pub type LodObjectFormat = FUELObjectFormat<ObjectZ, LodZ>;
-pub type LodObjectFormatAlt = FUELObjectFormat<ObjectZ, LodZAlt>;
-pub type LodObjectFormatAltAlt = FUELObjectFormat<ObjectZ, LodZAltAlt>;
+// The Lod_Z versions differ in layout so at most one of them parses
+pub fn skin_crc32s(database: &ObjectDatabase, crc32: u32) -> Result<Vec<u32>> {
+    if let Ok((_, lod)) = database.read::<ObjectZ, LodZ>(crc32) {
+        return Ok(lod.skin_crc32s.data);
+    }
+    if let Ok((_, lod)) = database.read::<ObjectZ, LodZAlt>(crc32) {
+        return Ok(lod.skin_crc32s.data);
+    }
+    let (_, lod) = database.read::<ObjectZ, LodZAltAlt>(crc32)?;
+    Ok(lod.skin_crc32s.data)
+}
+
+fn dyn_volumes<'a>(
+    database: &ObjectDatabase,
+    dyn_spheres: impl Iterator<Item = &'a DynSphere>,
+    dyn_boxes: impl Iterator<Item = &'a DynBox>,
+) -> Vec<Volume> {
+    let names = database.names();
+    let spheres = dyn_spheres.map(|x| Volume::from_sphere(names.name(x.dyn_sphere_name), &x.sphere));
+    let boxes = dyn_boxes.map(|x| Volume::from_matrix(names.name(x.dyn_box_name), VolumeShape::Box, &x.mat));
+    spheres.chain(boxes).collect()
+}
+
+// Spheres then boxes
+pub fn volumes(database: &ObjectDatabase, crc32: u32) -> Result<Vec<Volume>> {
+    if let Ok((_, lod)) = database.read::<ObjectZ, LodZ>(crc32) {
+        return Ok(dyn_volumes(database, lod.dyn_spheres.data.iter(), lod.dyn_boxes.data.iter()));
+    }
+    if let Ok((_, lod)) = database.read::<ObjectZ, LodZAlt>(crc32) {
+        let spheres = lod.sphere_col_node_optional.iter().chain(lod.sphere_col_nodes.data.iter());
+        return Ok(dyn_volumes(database, spheres, lod.box_cols.data.iter()));
+    }
+    let (_, lod) = database.read::<ObjectZ, LodZAltAlt>(crc32)?;
+    let spheres = lod.sphere_col_node_optional.iter().chain(lod.sphere_col_nodes.data.iter());
+    Ok(dyn_volumes(database, spheres, lod.box_cols.data.iter()))
+}
+
+// Only the transforms can be edited, the volumes must match the existing ones in number and shape
+fn set_dyn_volumes(dyn_spheres: Vec<&mut DynSphere>, dyn_boxes: Vec<&mut DynBox>, volumes: &[Volume]) -> Result<()> {
+    let sphere_count = dyn_spheres.len();
+    if volumes.len() != sphere_count + dyn_boxes.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected {} spheres and {} boxes", sphere_count, dyn_boxes.len()),
+        ));
+    }
+
+    let (spheres, boxes) = volumes.split_at(sphere_count);
+    if spheres.iter().any(|x| x.shape != VolumeShape::Sphere)
+        || boxes.iter().any(|x| x.shape != VolumeShape::Box)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Volumes must be listed as spheres then boxes",
+        ));
+    }
+
+    for (dyn_sphere, volume) in dyn_spheres.into_iter().zip(spheres.iter()) {
+        volume.apply_to_sphere(&mut dyn_sphere.sphere);
+    }
+    for (dyn_box, volume) in dyn_boxes.into_iter().zip(boxes.iter()) {
+        volume.apply_to_matrix(&mut dyn_box.mat);
+    }
+    Ok(())
+}
+
+pub trait LodVolumes {
+    fn set_volumes(&mut self, volumes: &[Volume]) -> Result<()>;
+}
+
+impl LodVolumes for LodZ {
+    fn set_volumes(&mut self, volumes: &[Volume]) -> Result<()> {
+        set_dyn_volumes(
+            self.dyn_spheres.data.iter_mut().collect(),
+            self.dyn_boxes.data.iter_mut().collect(),
+            volumes,
+        )
+    }
+}
+
+impl LodVolumes for LodZAlt {
+    fn set_volumes(&mut self, volumes: &[Volume]) -> Result<()> {
+        set_dyn_volumes(
+            self.sphere_col_node_optional.iter_mut().chain(self.sphere_col_nodes.data.iter_mut()).collect(),
+            self.box_cols.data.iter_mut().collect(),
+            volumes,
+        )
+    }
+}
+
+impl LodVolumes for LodZAltAlt {
+    fn set_volumes(&mut self, volumes: &[Volume]) -> Result<()> {
+        set_dyn_volumes(
+            self.sphere_col_node_optional.iter_mut().chain(self.sphere_col_nodes.data.iter_mut()).collect(),
+            self.box_cols.data.iter_mut().collect(),
+            volumes,
+        )
+    }
+}
+
+pub struct LodVolumesObjectFormat<T> {
+    x: PhantomData<T>,
+}
+
+impl<T> LodVolumesObjectFormat<T> {
+    pub fn new<'a>() -> &'a Self {
+        &Self { x: PhantomData }
+    }
+}
+
+impl<T> FUELObjectFormatTrait for LodVolumesObjectFormat<T>
+where
+    for<'a> T: Parse<&'a [u8]> + Serialize + Deserialize<'a> + BinWrite + HasReferences + LodVolumes,
+{
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // volumes.json in the object directory replaces the dyn sphere and box transforms in object.json
+        let volumes_path = input_path.join("volumes.json");
+        if !volumes_path.exists() {
+            return FUELObjectFormat::<ObjectZ, T>::new()
+                .pack(input_path, header, body, options);
+        }
+
+        FUELObjectFormat::<ObjectZ, T>::new().pack_with(input_path, header, body, |_, body| {
+            body.set_volumes(&volume::read_json(&volumes_path)?)
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ObjectZ, T>::new().unpack(header, body, output_path, options)
+    }
+}
+
+pub type LodObjectFormat = LodVolumesObjectFormat<LodZ>;
+pub type LodObjectFormatAlt = LodVolumesObjectFormat<LodZAlt>;
+pub type LodObjectFormatAltAlt = LodVolumesObjectFormat<LodZAltAlt>;
@@ -1,10 +1,90 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
 use binwrite::BinWrite;
+use gltf_json::material::{AlphaMode, EmissiveFactor, NormalTexture, PbrBaseColorFactor, PbrMetallicRoughness, StrengthFactor};
+use gltf_json::texture::Info;
+use gltf_json::validation::Checked;
+use gltf_json::{Index, Material};
 use nom_derive::NomLE;
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Map};
 
 use crate::fuel_fmt::common::{
-    write_option, FUELObjectFormat, FixedVec, HasReferences, ResourceObjectZ, Vec3f, Vec4f,
+    write_option, FUELObjectFormat, FixedVec, FloatOrBits, HasReferences, ResourceObjectZ, Vec3f,
+    Vec4f,
 };
+use crate::fuel_fmt::bitmap;
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf::GltfBuilder;
+
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(NomLE)]
+struct VertexShaderConstants<const U: usize> {
+    #[nom(Count(U))]
+    words: Vec<u32>,
+}
+
+// None of the constants have been matched to a shader input so they are named by index
+impl<const U: usize> VertexShaderConstants<U> {
+    fn names() -> Vec<String> {
+        (0..U).map(|i| format!("unknown{}", i)).collect()
+    }
+}
+
+impl<const U: usize> Serialize for VertexShaderConstants<U> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(U))?;
+        for (name, word) in Self::names().iter().zip(self.words.iter()) {
+            map.serialize_entry(name, &FloatOrBits::from(*word))?;
+        }
+        map.end()
+    }
+}
+
+// Objects extracted before the constants were named hold the raw words
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VertexShaderConstantsShadow {
+    Named(HashMap<String, FloatOrBits>),
+    Raw(Vec<u32>),
+}
+
+impl<'de, const U: usize> Deserialize<'de> for VertexShaderConstants<U> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let words: Vec<u32> = match VertexShaderConstantsShadow::deserialize(deserializer)? {
+            VertexShaderConstantsShadow::Named(mut constants) => {
+                let words = Self::names()
+                    .iter()
+                    .map(|name| match constants.remove(name) {
+                        Some(constant) => Ok(u32::from(constant)),
+                        None => Err(D::Error::custom(format!("missing vertex shader constant {}", name))),
+                    })
+                    .collect::<Result<Vec<u32>, D::Error>>()?;
+                if let Some(name) = constants.keys().next() {
+                    return Err(D::Error::custom(format!("unknown vertex shader constant {}", name)));
+                }
+                words
+            }
+            VertexShaderConstantsShadow::Raw(words) => words,
+        };
+
+        if words.len() != U {
+            return Err(D::Error::invalid_length(words.len(), &format!("{} vertex shader constants", U).as_str()));
+        }
+
+        Ok(VertexShaderConstants { words })
+    }
+}
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -14,7 +94,7 @@ pub struct MaterialZ {
     color: Vec4f,
     emission: Vec3f,
     unknown0: i32,
-    vertex_shader_constant_fs: FixedVec<u32, 26>,
+    vertex_shader_constant_fs: VertexShaderConstants<26>,
     diffuse_bitmap_crc32: u32,
     unknown_bitmap_crc320: u32,
     metal_bitmap_crc32: u32,
@@ -34,7 +114,7 @@ pub struct MaterialZAlt {
     color: Vec4f,
     emission: Vec3f,
     unknown0: i32,
-    vertex_shader_constant_fs: FixedVec<u32, 28>,
+    vertex_shader_constant_fs: VertexShaderConstants<28>,
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     #[allow(dead_code)]
@@ -60,7 +140,7 @@ pub struct MaterialZAltAlt {
     color: Vec4f,
     emission: Vec3f,
     unknown0: i32,
-    vertex_shader_constant_fs: FixedVec<u32, 31>,
+    vertex_shader_constant_fs: VertexShaderConstants<31>,
     #[binwrite(ignore)]
     opt: u8,
     #[binwrite(postprocessor(|x: Vec<u8>| -> (u8, Vec<u8>) { if x.len() != 0 { (1u8, x) } else { (0u8, x) } }))]
@@ -115,6 +195,142 @@ impl HasReferences for MaterialZAltAlt {
     }
 }
 
+// The fields shared by every material version, bitmaps are named after the field holding them
+struct MaterialFields {
+    color: [f32; 4],
+    emission: [f32; 3],
+    bitmaps: Vec<(String, u32)>,
+}
+
+fn fixed_bitmaps(bitmap_crc32s: &FixedVec<u32, 6>) -> Vec<(String, u32)> {
+    bitmap_crc32s
+        .data
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (format!("bitmap_crc32s[{}]", i), *x))
+        .collect()
+}
+
+// The material versions differ in size so at most one of them parses
+fn read_fields(database: &ObjectDatabase, crc32: u32) -> Result<MaterialFields, Error> {
+    let fields = |color: &Vec4f, emission: &Vec3f, bitmaps| MaterialFields {
+        color: [color.data[0], color.data[1], color.data[2], color.data[3]],
+        emission: [emission.data[0], emission.data[1], emission.data[2]],
+        bitmaps,
+    };
+
+    if let Ok((_, m)) = database.read::<ResourceObjectZ, MaterialZ>(crc32) {
+        let bitmaps = vec![
+            ("diffuse_bitmap_crc32", m.diffuse_bitmap_crc32),
+            ("unknown_bitmap_crc320", m.unknown_bitmap_crc320),
+            ("metal_bitmap_crc32", m.metal_bitmap_crc32),
+            ("unknown_bitmap_crc321", m.unknown_bitmap_crc321),
+            ("grey_bitmap_crc32", m.grey_bitmap_crc32),
+            ("normal_bitmap_crc32", m.normal_bitmap_crc32),
+            ("dirt_bitmap_crc32", m.dirt_bitmap_crc32),
+            ("unknown_bitmap_crc322", m.unknown_bitmap_crc322),
+            ("unknown_bitmap_crc323", m.unknown_bitmap_crc323),
+        ];
+        let bitmaps = bitmaps.into_iter().map(|(name, x)| (name.to_string(), x)).collect();
+        return Ok(fields(&m.color, &m.emission, bitmaps));
+    }
+    if let Ok((_, m)) = database.read::<ResourceObjectZ, MaterialZAltAlt>(crc32) {
+        return Ok(fields(&m.color, &m.emission, fixed_bitmaps(&m.bitmap_crc32s)));
+    }
+    let (_, m) = database.read::<ResourceObjectZ, MaterialZAlt>(crc32)?;
+    Ok(fields(&m.color, &m.emission, fixed_bitmaps(&m.bitmap_crc32s)))
+}
+
+pub fn diffuse_bitmap_crc32(database: &ObjectDatabase, crc32: u32) -> Result<u32, Error> {
+    Ok(read_fields(database, crc32)?.bitmaps[0].1)
+}
+
+// The diffuse and normal bitmaps have a glTF slot, the other bitmaps are kept in the extras by field name
+pub fn push_gltf_material(builder: &mut GltfBuilder, database: &ObjectDatabase, crc32: u32) -> Result<Option<Index<Material>>, Error> {
+    if let Some(material) = builder.materials.get(&crc32) {
+        return Ok(Some(*material));
+    }
+
+    let fields = match read_fields(database, crc32) {
+        Ok(fields) => fields,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    let mut material = Material {
+        alpha_mode: Checked::Valid(if fields.color[3] < 1f32 { AlphaMode::Blend } else { AlphaMode::Opaque }),
+        emissive_factor: EmissiveFactor(fields.emission.map(|x| x.clamp(0f32, 1f32))),
+        name: Some(database.names().name(crc32)),
+        pbr_metallic_roughness: PbrMetallicRoughness {
+            base_color_factor: PbrBaseColorFactor(fields.color.map(|x| x.clamp(0f32, 1f32))),
+            metallic_factor: StrengthFactor(0f32),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut extras = Map::new();
+    for (i, (field, bitmap_crc32)) in fields.bitmaps.into_iter().enumerate() {
+        if bitmap_crc32 == 0 {
+            continue;
+        }
+
+        let texture = bitmap::push_gltf_texture(builder, database, bitmap_crc32)?;
+        match (i, field.as_str(), texture) {
+            (0, _, Some(index)) => {
+                material.pbr_metallic_roughness.base_color_texture = Some(Info {
+                    index,
+                    tex_coord: 0,
+                    extensions: None,
+                    extras: Default::default(),
+                });
+            }
+            (_, "normal_bitmap_crc32", Some(index)) => {
+                material.normal_texture = Some(NormalTexture {
+                    index,
+                    scale: 1f32,
+                    tex_coord: 0,
+                    extensions: None,
+                    extras: Default::default(),
+                });
+            }
+            _ => {
+                extras.insert(field, json!({ "crc32": bitmap_crc32, "texture": texture.map(|x| x.value()) }));
+            }
+        }
+    }
+
+    if !extras.is_empty() {
+        material.extras = Some(serde_json::value::to_raw_value(&extras)?);
+    }
+
+    let index = builder.root.push(material);
+    builder.materials.insert(crc32, index);
+    Ok(Some(index))
+}
+
 pub type MaterialObjectFormat = FUELObjectFormat<ResourceObjectZ, MaterialZ>;
 pub type MaterialObjectFormatAlt = FUELObjectFormat<ResourceObjectZ, MaterialZAlt>;
 pub type MaterialObjectFormatAltAlt = FUELObjectFormat<ResourceObjectZ, MaterialZAltAlt>;
+
+#[cfg(test)]
+mod test {
+    use crate::fuel_fmt::material::VertexShaderConstants;
+
+    fn constants<const U: usize>() -> VertexShaderConstants<U> {
+        VertexShaderConstants {
+            words: (0..U as u32).map(|x| (x as f32 + 1f32).to_bits()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_vertex_shader_constant_names() {
+        let json = serde_json::to_value(constants::<26>()).unwrap();
+        assert_eq!(json["unknown0"], 1f32);
+        assert_eq!(json["unknown25"], 26f32);
+
+        let read: VertexShaderConstants<26> = serde_json::from_value(json).unwrap();
+        assert_eq!(read.words, constants::<26>().words);
+        assert!(serde_json::from_value::<VertexShaderConstants<26>>(serde_json::json!({ "unknown0": 1 })).is_err());
+    }
+}
@@ -1,92 +1,325 @@
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
 use binwrite::BinWrite;
-use nom_derive::NomLE;
-use serde::{Deserialize, Serialize};
+use nom_derive::{NomLE, Parse};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::fuel_fmt::common::{
+    FUELObjectFormat, FUELObjectFormatTrait, FormatOptions, HasReferences, PascalArray,
+    ResourceObjectZ,
+};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::File;
+
+// 0xRRGGBBAA, written to JSON as #rrggbbaa
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(NomLE)]
+struct Rgba {
+    rgba: u32,
+}
+
+impl Rgba {
+    fn parse_hex(hex: &str) -> Option<Rgba> {
+        let digits = hex.strip_prefix('#')?;
+        if digits.len() != 8 {
+            return None;
+        }
+        u32::from_str_radix(digits, 16).ok().map(|rgba| Rgba { rgba })
+    }
+}
+
+impl fmt::Display for Rgba {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:08x}", self.rgba)
+    }
+}
+
+impl Serialize for Rgba {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Rgba {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match RgbaShadow::deserialize(deserializer)? {
+            RgbaShadow::Hex(hex) => Rgba::parse_hex(&hex).ok_or_else(|| {
+                serde::de::Error::custom(format!("{} isn't a #rrggbbaa colour", hex))
+            }),
+            RgbaShadow::Raw(rgba) => Ok(Rgba { rgba }),
+        }
+    }
+}
 
-use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, PascalArray, ResourceObjectZ};
+// Older object.json files hold the colour as a plain number
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RgbaShadow {
+    Hex(String),
+    Raw(u32),
+}
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct MaterialAnimZUnknown0 {
-    unknown0: f32,
-    unknown1: f32,
+struct MaterialAnimZFloatKey {
+    #[serde(alias = "unknown0")]
+    time: f32,
+    #[serde(alias = "unknown1")]
+    value: f32,
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct MaterialAnimZUnknown23 {
-    unknown0: f32,
-    unknown1: f32,
-    unknown2: f32,
+struct MaterialAnimZVec2Key {
+    #[serde(alias = "unknown0")]
+    time: f32,
+    #[serde(alias = "unknown1")]
+    u: f32,
+    #[serde(alias = "unknown2")]
+    v: f32,
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct MaterialAnimZUnknown56 {
-    unknown0: f32,
-    unknown1: f32,
-    unknown2: f32,
-    unknown3: f32,
+struct MaterialAnimZTangentKey {
+    #[serde(alias = "unknown0")]
+    time: f32,
+    #[serde(alias = "unknown1")]
+    value: f32,
+    #[serde(alias = "unknown2")]
+    tangent_in: f32,
+    #[serde(alias = "unknown3")]
+    tangent_out: f32,
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct MaterialAnimZUnknown89 {
+#[serde(from = "MaterialAnimZUnknown89KeyShadow")]
+struct MaterialAnimZUnknown89Key {
+    time: f32,
     unknown0: f32,
     unknown1: f32,
     unknown2: f32,
     unknown3: f32,
-    unknown4: f32,
+}
+
+// The old names are shifted by one so they can't be aliases
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaterialAnimZUnknown89KeyShadow {
+    Named {
+        time: f32,
+        unknown0: f32,
+        unknown1: f32,
+        unknown2: f32,
+        unknown3: f32,
+    },
+    Legacy {
+        unknown0: f32,
+        unknown1: f32,
+        unknown2: f32,
+        unknown3: f32,
+        unknown4: f32,
+    },
+}
+
+impl From<MaterialAnimZUnknown89KeyShadow> for MaterialAnimZUnknown89Key {
+    fn from(shadow: MaterialAnimZUnknown89KeyShadow) -> Self {
+        match shadow {
+            MaterialAnimZUnknown89KeyShadow::Named { time, unknown0, unknown1, unknown2, unknown3 } => {
+                MaterialAnimZUnknown89Key { time, unknown0, unknown1, unknown2, unknown3 }
+            }
+            MaterialAnimZUnknown89KeyShadow::Legacy { unknown0, unknown1, unknown2, unknown3, unknown4 } => {
+                MaterialAnimZUnknown89Key {
+                    time: unknown0,
+                    unknown0: unknown1,
+                    unknown1: unknown2,
+                    unknown2: unknown3,
+                    unknown3: unknown4,
+                }
+            }
+        }
+    }
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct MaterialAnimZUnknown1011 {
-    unknown0: f32,
-    unknown1: f32,
+struct MaterialAnimZColorKey {
+    #[serde(alias = "unknown")]
+    time: f32,
+    rgba: Rgba,
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct MaterialAnimZColor {
-    unknown: f32,
-    rgba: u32,
+struct MaterialAnimZTrack<T: BinWrite> {
+    flags: u16,
+    keys: PascalArray<T>,
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
+#[serde(from = "MaterialAnimZShadow")]
 #[nom(Exact)]
 pub struct MaterialAnimZ {
-    unknown0s: PascalArray<MaterialAnimZUnknown0>,
-    unknown2flag: u16,
-    unknown2s: PascalArray<MaterialAnimZUnknown23>,
-    unknown3flag: u16,
-    unknown3s: PascalArray<MaterialAnimZUnknown23>,
-    unknown4flag: u16,
-    unknown4s: PascalArray<MaterialAnimZColor>,
-    unknown5flag: u16,
-    unknown5s: PascalArray<MaterialAnimZUnknown56>,
-    unknown6flag: u16,
-    unknown6s: PascalArray<MaterialAnimZUnknown56>,
-    colorsflag: u16,
-    colors: PascalArray<MaterialAnimZColor>,
-    unknown8flag: u16,
-    unknown8s: PascalArray<MaterialAnimZUnknown89>,
-    unknown9flag: u16,
-    unknown9s: PascalArray<MaterialAnimZUnknown89>,
-    unknown10s: PascalArray<MaterialAnimZUnknown1011>,
-    unknown11s: PascalArray<MaterialAnimZUnknown1011>,
+    unknown0s: PascalArray<MaterialAnimZFloatKey>,
+    uv_translation: MaterialAnimZTrack<MaterialAnimZVec2Key>,
+    uv_scale: MaterialAnimZTrack<MaterialAnimZVec2Key>,
+    unknown4: MaterialAnimZTrack<MaterialAnimZColorKey>,
+    uv_rotation: MaterialAnimZTrack<MaterialAnimZTangentKey>,
+    alpha: MaterialAnimZTrack<MaterialAnimZTangentKey>,
+    color: MaterialAnimZTrack<MaterialAnimZColorKey>,
+    unknown8: MaterialAnimZTrack<MaterialAnimZUnknown89Key>,
+    unknown9: MaterialAnimZTrack<MaterialAnimZUnknown89Key>,
+    unknown10s: PascalArray<MaterialAnimZFloatKey>,
+    unknown11s: PascalArray<MaterialAnimZFloatKey>,
     material_crc32: u32,
     unknown_float: f32,
     unknown15: u8,
 }
 
+// object.json files written before the tracks were named keep each flag next to its keys
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaterialAnimZShadow {
+    Named {
+        unknown0s: PascalArray<MaterialAnimZFloatKey>,
+        uv_translation: MaterialAnimZTrack<MaterialAnimZVec2Key>,
+        uv_scale: MaterialAnimZTrack<MaterialAnimZVec2Key>,
+        unknown4: MaterialAnimZTrack<MaterialAnimZColorKey>,
+        uv_rotation: MaterialAnimZTrack<MaterialAnimZTangentKey>,
+        alpha: MaterialAnimZTrack<MaterialAnimZTangentKey>,
+        color: MaterialAnimZTrack<MaterialAnimZColorKey>,
+        unknown8: MaterialAnimZTrack<MaterialAnimZUnknown89Key>,
+        unknown9: MaterialAnimZTrack<MaterialAnimZUnknown89Key>,
+        unknown10s: PascalArray<MaterialAnimZFloatKey>,
+        unknown11s: PascalArray<MaterialAnimZFloatKey>,
+        material_crc32: u32,
+        unknown_float: f32,
+        unknown15: u8,
+    },
+    Legacy {
+        unknown0s: PascalArray<MaterialAnimZFloatKey>,
+        unknown2flag: u16,
+        unknown2s: PascalArray<MaterialAnimZVec2Key>,
+        unknown3flag: u16,
+        unknown3s: PascalArray<MaterialAnimZVec2Key>,
+        unknown4flag: u16,
+        unknown4s: PascalArray<MaterialAnimZColorKey>,
+        unknown5flag: u16,
+        unknown5s: PascalArray<MaterialAnimZTangentKey>,
+        unknown6flag: u16,
+        unknown6s: PascalArray<MaterialAnimZTangentKey>,
+        colorsflag: u16,
+        colors: PascalArray<MaterialAnimZColorKey>,
+        unknown8flag: u16,
+        unknown8s: PascalArray<MaterialAnimZUnknown89Key>,
+        unknown9flag: u16,
+        unknown9s: PascalArray<MaterialAnimZUnknown89Key>,
+        unknown10s: PascalArray<MaterialAnimZFloatKey>,
+        unknown11s: PascalArray<MaterialAnimZFloatKey>,
+        material_crc32: u32,
+        unknown_float: f32,
+        unknown15: u8,
+    },
+}
+
+impl From<MaterialAnimZShadow> for MaterialAnimZ {
+    fn from(shadow: MaterialAnimZShadow) -> Self {
+        match shadow {
+            MaterialAnimZShadow::Named {
+                unknown0s,
+                uv_translation,
+                uv_scale,
+                unknown4,
+                uv_rotation,
+                alpha,
+                color,
+                unknown8,
+                unknown9,
+                unknown10s,
+                unknown11s,
+                material_crc32,
+                unknown_float,
+                unknown15,
+            } => MaterialAnimZ {
+                unknown0s,
+                uv_translation,
+                uv_scale,
+                unknown4,
+                uv_rotation,
+                alpha,
+                color,
+                unknown8,
+                unknown9,
+                unknown10s,
+                unknown11s,
+                material_crc32,
+                unknown_float,
+                unknown15,
+            },
+            MaterialAnimZShadow::Legacy {
+                unknown0s,
+                unknown2flag,
+                unknown2s,
+                unknown3flag,
+                unknown3s,
+                unknown4flag,
+                unknown4s,
+                unknown5flag,
+                unknown5s,
+                unknown6flag,
+                unknown6s,
+                colorsflag,
+                colors,
+                unknown8flag,
+                unknown8s,
+                unknown9flag,
+                unknown9s,
+                unknown10s,
+                unknown11s,
+                material_crc32,
+                unknown_float,
+                unknown15,
+            } => MaterialAnimZ {
+                unknown0s,
+                uv_translation: MaterialAnimZTrack { flags: unknown2flag, keys: unknown2s },
+                uv_scale: MaterialAnimZTrack { flags: unknown3flag, keys: unknown3s },
+                unknown4: MaterialAnimZTrack { flags: unknown4flag, keys: unknown4s },
+                uv_rotation: MaterialAnimZTrack { flags: unknown5flag, keys: unknown5s },
+                alpha: MaterialAnimZTrack { flags: unknown6flag, keys: unknown6s },
+                color: MaterialAnimZTrack { flags: colorsflag, keys: colors },
+                unknown8: MaterialAnimZTrack { flags: unknown8flag, keys: unknown8s },
+                unknown9: MaterialAnimZTrack { flags: unknown9flag, keys: unknown9s },
+                unknown10s,
+                unknown11s,
+                material_crc32,
+                unknown_float,
+                unknown15,
+            },
+        }
+    }
+}
+
 impl HasReferences for MaterialAnimZ {
     fn hard_links(&self) -> Vec<u32> {
         vec![]
@@ -97,4 +330,503 @@ impl HasReferences for MaterialAnimZ {
     }
 }
 
-pub type MaterialAnimObjectFormat = FUELObjectFormat<ResourceObjectZ, MaterialAnimZ>;
+fn parse_f32(value: &str) -> Result<f32> {
+    value.trim().parse::<f32>().map_err(|_| {
+        Error::new(ErrorKind::InvalidData, format!("{} isn't a number", value))
+    })
+}
+
+// A key as a CSV row, time first
+trait Keyframe: Sized {
+    fn time(&self) -> f32;
+    fn to_row(&self) -> Vec<String>;
+    fn from_row(row: &[&str]) -> Result<Self>;
+}
+
+fn floats<const U: usize>(row: &[&str]) -> Result<[f32; U]> {
+    if row.len() != U {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected {} values in keyframe {}", U, row.join(",")),
+        ));
+    }
+    let mut values = [0f32; U];
+    for (value, x) in values.iter_mut().zip(row) {
+        *value = parse_f32(x)?;
+    }
+    Ok(values)
+}
+
+impl Keyframe for MaterialAnimZFloatKey {
+    fn time(&self) -> f32 {
+        self.time
+    }
+
+    fn to_row(&self) -> Vec<String> {
+        vec![self.time.to_string(), self.value.to_string()]
+    }
+
+    fn from_row(row: &[&str]) -> Result<Self> {
+        let [time, value] = floats(row)?;
+        Ok(MaterialAnimZFloatKey { time, value })
+    }
+}
+
+impl Keyframe for MaterialAnimZVec2Key {
+    fn time(&self) -> f32 {
+        self.time
+    }
+
+    fn to_row(&self) -> Vec<String> {
+        vec![self.time.to_string(), self.u.to_string(), self.v.to_string()]
+    }
+
+    fn from_row(row: &[&str]) -> Result<Self> {
+        let [time, u, v] = floats(row)?;
+        Ok(MaterialAnimZVec2Key { time, u, v })
+    }
+}
+
+impl Keyframe for MaterialAnimZTangentKey {
+    fn time(&self) -> f32 {
+        self.time
+    }
+
+    fn to_row(&self) -> Vec<String> {
+        [self.time, self.value, self.tangent_in, self.tangent_out]
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    fn from_row(row: &[&str]) -> Result<Self> {
+        let [time, value, tangent_in, tangent_out] = floats(row)?;
+        Ok(MaterialAnimZTangentKey { time, value, tangent_in, tangent_out })
+    }
+}
+
+impl Keyframe for MaterialAnimZUnknown89Key {
+    fn time(&self) -> f32 {
+        self.time
+    }
+
+    fn to_row(&self) -> Vec<String> {
+        [self.time, self.unknown0, self.unknown1, self.unknown2, self.unknown3]
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    fn from_row(row: &[&str]) -> Result<Self> {
+        let [time, unknown0, unknown1, unknown2, unknown3] = floats(row)?;
+        Ok(MaterialAnimZUnknown89Key { time, unknown0, unknown1, unknown2, unknown3 })
+    }
+}
+
+impl Keyframe for MaterialAnimZColorKey {
+    fn time(&self) -> f32 {
+        self.time
+    }
+
+    fn to_row(&self) -> Vec<String> {
+        vec![self.time.to_string(), self.rgba.to_string()]
+    }
+
+    fn from_row(row: &[&str]) -> Result<Self> {
+        let rgba = match row {
+            [_, hex] => Rgba::parse_hex(hex.trim()),
+            _ => None,
+        };
+        match rgba {
+            Some(rgba) => Ok(MaterialAnimZColorKey { time: parse_f32(row[0])?, rgba }),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Expected a time and a #rrggbbaa colour in keyframe {}", row.join(",")),
+            )),
+        }
+    }
+}
+
+// The keys of one track, independent of their type
+trait KeyframeTrack {
+    fn to_rows(&self) -> Vec<Vec<String>>;
+    fn to_json(&self) -> Result<String>;
+    fn set_rows(&mut self, rows: &[Vec<&str>]) -> Result<()>;
+    fn set_json(&mut self, keys: Value) -> Result<()>;
+    fn sort(&mut self);
+}
+
+impl<T: Keyframe + Serialize + DeserializeOwned> KeyframeTrack for PascalArray<T> {
+    fn to_rows(&self) -> Vec<Vec<String>> {
+        self.data.iter().map(|x| x.to_row()).collect()
+    }
+
+    fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.data)?)
+    }
+
+    fn set_rows(&mut self, rows: &[Vec<&str>]) -> Result<()> {
+        self.data = rows.iter().map(|x| T::from_row(x)).collect::<Result<Vec<T>>>()?;
+        Ok(())
+    }
+
+    fn set_json(&mut self, keys: Value) -> Result<()> {
+        self.data = serde_json::from_value(keys)?;
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        self.data.sort_by(|a, b| a.time().total_cmp(&b.time()));
+    }
+}
+
+impl MaterialAnimZ {
+    // Every track by field name with its flags, in the order they are stored
+    fn tracks(&self) -> Vec<(&'static str, Option<u16>, &dyn KeyframeTrack)> {
+        vec![
+            ("unknown0s", None, &self.unknown0s),
+            ("uv_translation", Some(self.uv_translation.flags), &self.uv_translation.keys),
+            ("uv_scale", Some(self.uv_scale.flags), &self.uv_scale.keys),
+            ("unknown4", Some(self.unknown4.flags), &self.unknown4.keys),
+            ("uv_rotation", Some(self.uv_rotation.flags), &self.uv_rotation.keys),
+            ("alpha", Some(self.alpha.flags), &self.alpha.keys),
+            ("color", Some(self.color.flags), &self.color.keys),
+            ("unknown8", Some(self.unknown8.flags), &self.unknown8.keys),
+            ("unknown9", Some(self.unknown9.flags), &self.unknown9.keys),
+            ("unknown10s", None, &self.unknown10s),
+            ("unknown11s", None, &self.unknown11s),
+        ]
+    }
+
+    fn tracks_mut(&mut self) -> Vec<(&'static str, Option<&mut u16>, &mut dyn KeyframeTrack)> {
+        vec![
+            ("unknown0s", None, &mut self.unknown0s),
+            ("uv_translation", Some(&mut self.uv_translation.flags), &mut self.uv_translation.keys),
+            ("uv_scale", Some(&mut self.uv_scale.flags), &mut self.uv_scale.keys),
+            ("unknown4", Some(&mut self.unknown4.flags), &mut self.unknown4.keys),
+            ("uv_rotation", Some(&mut self.uv_rotation.flags), &mut self.uv_rotation.keys),
+            ("alpha", Some(&mut self.alpha.flags), &mut self.alpha.keys),
+            ("color", Some(&mut self.color.flags), &mut self.color.keys),
+            ("unknown8", Some(&mut self.unknown8.flags), &mut self.unknown8.keys),
+            ("unknown9", Some(&mut self.unknown9.flags), &mut self.unknown9.keys),
+            ("unknown10s", None, &mut self.unknown10s),
+            ("unknown11s", None, &mut self.unknown11s),
+        ]
+    }
+
+    // A track's flags are written as a track,flags,value row before its keys
+    fn write_csv(&self) -> String {
+        let mut csv = String::from("track,time,values\n");
+        for (name, flags, track) in self.tracks() {
+            if let Some(flags) = flags {
+                csv.push_str(&format!("{},flags,{}\n", name, flags));
+            }
+            for row in track.to_rows() {
+                csv.push_str(&format!("{},{}\n", name, row.join(",")));
+            }
+        }
+        csv
+    }
+
+    // The keyframe file holds every key, a track missing from it has none. A track without a flags row keeps
+    // its flags. Keys are sorted by time.
+    fn read_csv(&mut self, csv: &str) -> Result<()> {
+        let rows: Vec<Vec<&str>> = csv
+            .lines()
+            .skip(1)
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| x.split(',').map(|x| x.trim()).collect())
+            .collect();
+
+        if let Some(row) = rows.iter().find(|row| !self.tracks().iter().any(|(name, _, _)| *name == row[0])) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown track {}", row[0]),
+            ));
+        }
+
+        for (name, flags, track) in self.tracks_mut() {
+            let (flag_rows, key_rows): (Vec<&Vec<&str>>, Vec<&Vec<&str>>) = rows
+                .iter()
+                .filter(|x| x[0] == name)
+                .partition(|x| x.get(1) == Some(&"flags"));
+
+            if let Some(row) = flag_rows.last() {
+                let value = match row.get(2) {
+                    Some(value) if row.len() == 3 => parse_flags(value)?,
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Expected one value in {}", row.join(",")),
+                        ))
+                    }
+                };
+                set_flags(name, flags, value)?;
+            }
+
+            let key_rows: Vec<Vec<&str>> = key_rows.iter().map(|x| x[1..].to_vec()).collect();
+            track.set_rows(&key_rows)?;
+            track.sort();
+        }
+
+        Ok(())
+    }
+
+    // Written by hand to keep the tracks and key fields in order. Tracks with flags are written as
+    // { "flags": ..., "keys": [...] } and the others as the array of keys.
+    fn write_json(&self) -> Result<String> {
+        let mut tracks: Vec<String> = Vec::new();
+        for (name, flags, track) in self.tracks() {
+            let keys = track.to_json()?;
+            tracks.push(match flags {
+                Some(flags) => format!(
+                    "  \"{}\": {{\n    \"flags\": {},\n    \"keys\": {}\n  }}",
+                    name,
+                    flags,
+                    keys.replace('\n', "\n    ")
+                ),
+                None => format!("  \"{}\": {}", name, keys.replace('\n', "\n  ")),
+            });
+        }
+        Ok(format!("{{\n{}\n}}\n", tracks.join(",\n")))
+    }
+
+    // A flagged track may also be given as the array of its keys, its flags are then kept. Keys are sorted by
+    // time.
+    fn read_json(&mut self, json: &str) -> Result<()> {
+        let mut tracks: Map<String, Value> = serde_json::from_str(json)?;
+
+        for (name, flags, track) in self.tracks_mut() {
+            let keys = match tracks.remove(name) {
+                Some(Value::Object(mut object)) => {
+                    if let Some(value) = object.remove("flags") {
+                        let value: u16 = serde_json::from_value(value)?;
+                        set_flags(name, flags, value)?;
+                    }
+                    let keys = object.remove("keys").unwrap_or(Value::Array(vec![]));
+                    if let Some(field) = object.keys().next() {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Unknown field {} in track {}", field, name),
+                        ));
+                    }
+                    keys
+                }
+                Some(keys) => keys,
+                None => Value::Array(vec![]),
+            };
+            track.set_json(keys)?;
+            track.sort();
+        }
+
+        match tracks.keys().next() {
+            Some(name) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown track {}", name),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_flags(value: &str) -> Result<u16> {
+    value.parse::<u16>().map_err(|_| {
+        Error::new(ErrorKind::InvalidData, format!("{} isn't a track flag value", value))
+    })
+}
+
+fn set_flags(name: &str, flags: Option<&mut u16>, value: u16) -> Result<()> {
+    match flags {
+        Some(flags) => {
+            *flags = value;
+            Ok(())
+        }
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Track {} has no flags", name),
+        )),
+    }
+}
+
+// Keyframes are written as rows of track,time,values... to .csv or as a map of tracks to .json
+pub fn export(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let (_, material_anim) = database.read::<ResourceObjectZ, MaterialAnimZ>(crc32)?;
+
+    let keyframes = match output_path.extension().and_then(|x| x.to_str()) {
+        Some("csv") => material_anim.write_csv(),
+        Some("json") => material_anim.write_json()?,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "material animation exports are written as .csv or .json",
+            ))
+        }
+    };
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(keyframes.as_bytes())?;
+    Ok(())
+}
+
+pub struct MaterialAnimObjectFormat;
+
+impl MaterialAnimObjectFormat {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for MaterialAnimObjectFormat {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // keyframes.csv or keyframes.json in the object directory replaces the keys in object.json
+        let csv_path = input_path.join("keyframes.csv");
+        let keyframes_json_path = input_path.join("keyframes.json");
+        if !csv_path.exists() && !keyframes_json_path.exists() {
+            return FUELObjectFormat::<ResourceObjectZ, MaterialAnimZ>::new()
+                .pack(input_path, header, body, options);
+        }
+
+        FUELObjectFormat::<ResourceObjectZ, MaterialAnimZ>::new().pack_with(input_path, header, body, |_, body| {
+            if csv_path.exists() {
+                body.read_csv(&fs::read_to_string(csv_path)?)?;
+            } else {
+                body.read_json(&fs::read_to_string(keyframes_json_path)?)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ResourceObjectZ, MaterialAnimZ>::new()
+            .unpack(header, body, output_path, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+    use serde_json::json;
+
+    use crate::fuel_fmt::materialanim::MaterialAnimZ;
+
+    // One float key, then eight tracks of one key each with flags 1 to 8
+    fn material_anim_bytes() -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        let push_floats = |bytes: &mut Vec<u8>, floats: &[f32]| {
+            for x in floats {
+                bytes.extend(&x.to_le_bytes());
+            }
+        };
+        bytes.extend(&1u32.to_le_bytes());
+        push_floats(&mut bytes, &[0.5, 1.0]);
+        for (flags, floats) in [(1u16, 2usize), (2, 2), (3, 0), (4, 3), (5, 3), (6, 0), (7, 4), (8, 4)].iter() {
+            bytes.extend(&flags.to_le_bytes());
+            bytes.extend(&1u32.to_le_bytes());
+            push_floats(&mut bytes, &[0.25]);
+            if *flags == 3 || *flags == 6 {
+                bytes.extend(&0x11223344u32.to_le_bytes());
+            }
+            push_floats(&mut bytes, &vec![2.0; *floats][..]);
+        }
+        bytes.extend(&0u32.to_le_bytes());
+        bytes.extend(&0u32.to_le_bytes());
+        bytes.extend(&0x12345678u32.to_le_bytes());
+        push_floats(&mut bytes, &[3.0]);
+        bytes.push(9);
+        bytes
+    }
+
+    #[test]
+    fn test_material_anim_csv() {
+        let bytes = material_anim_bytes();
+        let (_, mut material_anim) = MaterialAnimZ::parse(&bytes).unwrap();
+        let csv = material_anim.write_csv();
+        assert!(csv.contains("uv_scale,flags,2\n"));
+
+        material_anim.read_csv(&csv).unwrap();
+        let mut written = Vec::new();
+        material_anim.write(&mut written).unwrap();
+        assert_eq!(bytes, written);
+
+        let csv = csv.replace("uv_scale,flags,2", "uv_scale,flags,12") + "alpha,0.125,1,2,3\n";
+        material_anim.read_csv(&csv).unwrap();
+        assert_eq!(material_anim.uv_scale.flags, 12);
+        assert_eq!(material_anim.alpha.keys.data[0].time, 0.125);
+        assert_eq!(material_anim.alpha.keys.data[1].time, 0.25);
+
+        assert!(material_anim.read_csv("track,time,values\nunknown0s,flags,1\n").is_err());
+    }
+
+    #[test]
+    fn test_material_anim_json() {
+        let bytes = material_anim_bytes();
+        let (_, mut material_anim) = MaterialAnimZ::parse(&bytes).unwrap();
+        let json = material_anim.write_json().unwrap();
+        material_anim.read_json(&json).unwrap();
+        let mut written = Vec::new();
+        material_anim.write(&mut written).unwrap();
+        assert_eq!(bytes, written);
+
+        let keys = json!({
+            "uv_translation": { "flags": 10, "keys": [{ "time": 2.0, "u": 0.0, "v": 0.0 }, { "time": 1.0, "u": 0.0, "v": 0.0 }] },
+            "color": [{ "time": 0.0, "rgba": "#ff0000ff" }],
+        });
+        material_anim.read_json(&keys.to_string()).unwrap();
+        assert_eq!(material_anim.uv_translation.flags, 10);
+        assert_eq!(material_anim.uv_translation.keys.data[0].time, 1.0);
+        assert_eq!(material_anim.color.flags, 6);
+        assert_eq!(material_anim.color.keys.data[0].rgba.rgba, 0xff0000ff);
+        assert!(material_anim.alpha.keys.data.is_empty());
+    }
+
+    #[test]
+    fn test_material_anim_legacy_json() {
+        let track = |key: serde_json::Value| json!([key]);
+        let float_key = json!({ "unknown0": 0.5, "unknown1": 1.0 });
+        let vec2_key = json!({ "unknown0": 0.25, "unknown1": 2.0, "unknown2": 2.0 });
+        let color_key = json!({ "unknown": 0.25, "rgba": 0x11223344u32 });
+        let tangent_key = json!({ "unknown0": 0.25, "unknown1": 2.0, "unknown2": 2.0, "unknown3": 2.0 });
+        let unknown89_key = json!({ "unknown0": 0.25, "unknown1": 2.0, "unknown2": 2.0, "unknown3": 2.0, "unknown4": 2.0 });
+        let legacy = json!({
+            "unknown0s": track(float_key),
+            "unknown2flag": 1,
+            "unknown2s": track(vec2_key.clone()),
+            "unknown3flag": 2,
+            "unknown3s": track(vec2_key),
+            "unknown4flag": 3,
+            "unknown4s": track(color_key.clone()),
+            "unknown5flag": 4,
+            "unknown5s": track(tangent_key.clone()),
+            "unknown6flag": 5,
+            "unknown6s": track(tangent_key),
+            "colorsflag": 6,
+            "colors": track(color_key),
+            "unknown8flag": 7,
+            "unknown8s": track(unknown89_key.clone()),
+            "unknown9flag": 8,
+            "unknown9s": track(unknown89_key),
+            "unknown10s": [],
+            "unknown11s": [],
+            "material_crc32": 0x12345678u32,
+            "unknown_float": 3.0,
+            "unknown15": 9,
+        });
+        let material_anim: MaterialAnimZ = serde_json::from_value(legacy).unwrap();
+        let mut written = Vec::new();
+        material_anim.write(&mut written).unwrap();
+        assert_eq!(material_anim_bytes(), written);
+    }
+}
@@ -1,11 +1,22 @@
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result, Write};
 use binwrite::{BinWrite, WriterOption};
+use glam::Vec3 as GlamVec3;
+use gltf_json::accessor::Type;
+use gltf_json::buffer::Target;
+use gltf_json::mesh::{Mode, Primitive, Semantic};
+use gltf_json::validation::Checked;
+use gltf_json::{Accessor, Index, Material, Mesh};
 use nom::{count, IResult};
 use nom_derive::NomLE;
 use nom_derive::Parse;
 use serde::{Deserialize, Serialize};
 
 use crate::fuel_fmt::common::{FUELObjectFormat, FixedVec, HasReferences, Mat4f, PascalArray, Quat, Vec3f, Vec4f, Vec2f, FadeDistances, RangeBeginEnd, RangeBeginSize, PascalString, NumeratorFloat, Vec3, VertexVectorComponent, DynSphere, DynBox};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf::GltfBuilder;
+use crate::fuel_fmt::material;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -145,13 +156,145 @@ impl VertexBufferData {
                 let parse_result = count!(i, VertexLayoutPosition::parse, vertex_count)?;
                 Ok((parse_result.0, VertexBufferData::VertexLayoutPositionCase(parse_result.1)))
             }
-            _ => { panic!("Invalid vertex size") }
+            _ => Err(nom::Err::Error(nom::error::make_error(i, nom::error::ErrorKind::Switch))),
+        }
+    }
+}
+
+fn gltf_normal(normal: &VertexVector3u8) -> [f32; 3] {
+    GlamVec3::new(normal.data[0].to_f32(), normal.data[1].to_f32(), normal.data[2].to_f32())
+        .try_normalize()
+        .unwrap_or(GlamVec3::Y)
+        .to_array()
+}
+
+impl VertexBufferData {
+    // The palette maps a vertex's blend index to a joint of the glTF skin
+    fn push_gltf_attributes(
+        &self,
+        builder: &mut GltfBuilder,
+        palette: Option<&[u16]>,
+    ) -> BTreeMap<Checked<Semantic>, Index<Accessor>> {
+        let mut positions: Vec<f32> = Vec::new();
+        let mut normals: Vec<f32> = Vec::new();
+        let mut texcoords: Vec<f32> = Vec::new();
+        let mut blends: Vec<([f32; 4], [f32; 4])> = Vec::new();
+
+        match self {
+            VertexBufferData::VertexLayout4BlendCase(data) => {
+                for vertex in data.iter() {
+                    positions.extend_from_slice(&vertex.position.data);
+                    normals.extend_from_slice(&gltf_normal(&vertex.normal));
+                    texcoords.extend_from_slice(&vertex.uv.data);
+                    let indices = &vertex.blend_indies.data;
+                    let weights = &vertex.blend_weights.data;
+                    blends.push((
+                        [indices[0].index, indices[1].index, indices[2].index, indices[3].index],
+                        [weights[0], weights[1], weights[2], weights[3]],
+                    ));
+                }
+            }
+            VertexBufferData::VertexLayout1BlendCase(data) => {
+                for vertex in data.iter() {
+                    positions.extend_from_slice(&vertex.position.data);
+                    normals.extend_from_slice(&gltf_normal(&vertex.normal));
+                    texcoords.extend_from_slice(&vertex.uv.data);
+                    blends.push((
+                        [vertex.blend_index.index, 0f32, 0f32, 0f32],
+                        [vertex.blend_weight, 0f32, 0f32, 0f32],
+                    ));
+                }
+            }
+            VertexBufferData::VertexLayoutNoBlendCase(data) => {
+                for vertex in data.iter() {
+                    positions.extend_from_slice(&vertex.position.data);
+                    normals.extend_from_slice(&gltf_normal(&vertex.normal));
+                    texcoords.extend_from_slice(&vertex.uv.data);
+                }
+            }
+            VertexBufferData::VertexLayoutPositionCase(data) => {
+                for vertex in data.iter() {
+                    positions.extend_from_slice(&vertex.position.data);
+                }
+            }
+        }
+
+        let mut attributes = BTreeMap::new();
+
+        attributes.insert(
+            Checked::Valid(Semantic::Positions),
+            builder.push_bounded_accessor(&positions, Type::Vec3, Some(Target::ArrayBuffer)),
+        );
+
+        if !normals.is_empty() {
+            attributes.insert(
+                Checked::Valid(Semantic::Normals),
+                builder.push_accessor(&normals, Type::Vec3, Some(Target::ArrayBuffer)),
+            );
+        }
+
+        if !texcoords.is_empty() {
+            attributes.insert(
+                Checked::Valid(Semantic::TexCoords(0)),
+                builder.push_accessor(&texcoords, Type::Vec2, Some(Target::ArrayBuffer)),
+            );
+        }
+
+        if let Some(palette) = palette {
+            if !blends.is_empty() {
+                let mut joints: Vec<u16> = Vec::with_capacity(blends.len() * 4);
+                let mut weights: Vec<f32> = Vec::with_capacity(blends.len() * 4);
+
+                for (blend_indices, blend_weights) in blends.iter() {
+                    // glTF requires the weights of a vertex to sum to one
+                    let sum: f32 = blend_weights.iter().sum();
+                    for i in 0..4 {
+                        let weight = if sum > 0f32 {
+                            blend_weights[i] / sum
+                        } else if i == 0 {
+                            1f32
+                        } else {
+                            0f32
+                        };
+                        let joint = if weight > 0f32 {
+                            palette
+                                .get(blend_indices[i].round() as usize)
+                                .copied()
+                                .unwrap_or(0)
+                        } else {
+                            0
+                        };
+                        joints.push(joint);
+                        weights.push(weight);
+                    }
+                }
+
+                attributes.insert(
+                    Checked::Valid(Semantic::Joints(0)),
+                    builder.push_accessor(&joints, Type::Vec4, Some(Target::ArrayBuffer)),
+                );
+                attributes.insert(
+                    Checked::Valid(Semantic::Weights(0)),
+                    builder.push_accessor(&weights, Type::Vec4, Some(Target::ArrayBuffer)),
+                );
+            }
+        }
+
+        attributes
+    }
+
+    fn positions(&self) -> Vec<GlamVec3> {
+        match self {
+            VertexBufferData::VertexLayout4BlendCase(data) => data.iter().map(|x| GlamVec3::from_slice(&x.position.data)).collect(),
+            VertexBufferData::VertexLayout1BlendCase(data) => data.iter().map(|x| GlamVec3::from_slice(&x.position.data)).collect(),
+            VertexBufferData::VertexLayoutNoBlendCase(data) => data.iter().map(|x| GlamVec3::from_slice(&x.position.data)).collect(),
+            VertexBufferData::VertexLayoutPositionCase(data) => data.iter().map(|x| GlamVec3::from_slice(&x.position.data)).collect(),
         }
     }
 }
 
 impl BinWrite for VertexBufferData {
-    fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
         match self {
             VertexBufferData::VertexLayout4BlendCase(data) => { data.write(writer) }
             VertexBufferData::VertexLayout1BlendCase(data) => { data.write(writer) }
@@ -160,7 +303,7 @@ impl BinWrite for VertexBufferData {
         }
     }
 
-    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> std::io::Result<()> {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
         match self {
             VertexBufferData::VertexLayout4BlendCase(data) => { data.write_options(writer, options) }
             VertexBufferData::VertexLayout1BlendCase(data) => { data.write_options(writer, options) }
@@ -616,6 +759,174 @@ impl HasReferences for MeshZHeaderAltAlt {
     }
 }
 
+// The parts of a mesh the exporters use, whichever version it was read as
+pub struct MeshGeometry {
+    vertex_buffers: Vec<VertexBufferExt>,
+    index_buffers: Vec<IndexBufferExt>,
+    vertex_groups: Vec<VertexGroup>,
+    material_crc32s: Vec<u32>,
+}
+
+// The Mesh_Z versions differ in layout so at most one of them parses
+pub fn read(database: &ObjectDatabase, crc32: u32) -> Result<MeshGeometry> {
+    if let Ok((_, mesh)) = database.read::<MeshZHeader, MeshZ>(crc32) {
+        return Ok(MeshGeometry {
+            vertex_buffers: mesh.mesh_buffers.vertex_buffers.data,
+            index_buffers: mesh.mesh_buffers.index_buffers.data,
+            vertex_groups: mesh.mesh_buffers.vertex_groups.data,
+            material_crc32s: mesh.material_crc32s.data,
+        });
+    }
+    if let Ok((_, mesh)) = database.read::<MeshZHeaderAlt, MeshZAlt>(crc32) {
+        return Ok(MeshGeometry {
+            vertex_buffers: mesh.sub_meshes.data,
+            index_buffers: mesh.indices.data,
+            vertex_groups: mesh.unknown13s.data,
+            material_crc32s: mesh.material_crc32s.data,
+        });
+    }
+    if let Ok((_, mesh)) = database.read::<MeshZHeaderAltAlt, MeshZAltAlt>(crc32) {
+        return Ok(MeshGeometry {
+            vertex_buffers: mesh.sub_meshes.data,
+            index_buffers: mesh.indices.data,
+            vertex_groups: mesh.unknown13s.data,
+            material_crc32s: mesh.material_crc32s.data,
+        });
+    }
+    let (_, mesh) = database.read::<MeshZHeaderAltAlt, MeshZAltAltAlt>(crc32)?;
+    Ok(MeshGeometry {
+        vertex_buffers: mesh.sub_meshes.data,
+        index_buffers: mesh.indices.data,
+        vertex_groups: vec![],
+        material_crc32s: mesh.material_crc32s1.data,
+    })
+}
+
+// A triangle list drawn from one vertex buffer
+struct PrimitiveRange {
+    vertex_buffer_index: usize,
+    index_buffer_index: usize,
+    begin: usize,
+    end: usize,
+    base_vertex: u32,
+    material_index: Option<usize>,
+}
+
+impl MeshGeometry {
+    // One range per vertex group. v1.220 meshes have no vertex groups, each of their index buffers
+    // is assumed to be a triangle list over the vertex buffer of the same index.
+    fn primitive_ranges(&self) -> Vec<PrimitiveRange> {
+        if self.vertex_groups.is_empty() {
+            return self
+                .index_buffers
+                .iter()
+                .enumerate()
+                .map(|(i, x)| PrimitiveRange {
+                    vertex_buffer_index: i,
+                    index_buffer_index: i,
+                    begin: 0,
+                    end: x.indices.len() - x.indices.len() % 3,
+                    base_vertex: 0,
+                    material_index: None,
+                })
+                .collect();
+        }
+
+        self.vertex_groups
+            .iter()
+            .map(|x| PrimitiveRange {
+                vertex_buffer_index: x.vertex_buffer_index as usize,
+                index_buffer_index: x.index_buffer_index as usize,
+                begin: x.index_buffer_index_begin as usize,
+                end: x.index_buffer_index_begin as usize + x.face_count as usize * 3,
+                base_vertex: x.vertex_buffer_range_begin_or_zero,
+                // A negative index is a vertex group without a material
+                material_index: usize::try_from(x.material_index).ok(),
+            })
+            .collect()
+    }
+
+    // One primitive per vertex group, sharing the attributes of their vertex buffer
+    pub fn push_gltf_mesh(
+        &self,
+        builder: &mut GltfBuilder,
+        database: &ObjectDatabase,
+        name: String,
+        palette: Option<&[u16]>,
+    ) -> Result<Index<Mesh>> {
+        let attributes: Vec<BTreeMap<Checked<Semantic>, Index<Accessor>>> = self
+            .vertex_buffers
+            .iter()
+            .map(|x| x.vertices.push_gltf_attributes(builder, palette))
+            .collect();
+
+        let mut materials: Vec<Option<Index<Material>>> = Vec::new();
+        for material_crc32 in self.material_crc32s.iter() {
+            materials.push(material::push_gltf_material(builder, database, *material_crc32)?);
+        }
+
+        let mut primitives: Vec<Primitive> = Vec::new();
+
+        for range in self.primitive_ranges() {
+            let out_of_range = || {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Vertex group out of range in mesh {}", name),
+                )
+            };
+
+            let vertex_attributes = attributes
+                .get(range.vertex_buffer_index)
+                .ok_or_else(out_of_range)?;
+
+            let indices: Vec<u32> = self
+                .index_buffers
+                .get(range.index_buffer_index)
+                .and_then(|x| x.indices.get(range.begin..range.end))
+                .ok_or_else(out_of_range)?
+                .iter()
+                .map(|x| *x as u32 + range.base_vertex)
+                .collect();
+
+            primitives.push(Primitive {
+                attributes: vertex_attributes.clone(),
+                extensions: None,
+                extras: Default::default(),
+                indices: Some(builder.push_accessor(
+                    &indices,
+                    Type::Scalar,
+                    Some(Target::ElementArrayBuffer),
+                )),
+                material: range
+                    .material_index
+                    .and_then(|x| materials.get(x).copied().flatten()),
+                mode: Checked::Valid(Mode::Triangles),
+                targets: None,
+            });
+        }
+
+        if primitives.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("No vertex groups in mesh {}", name),
+            ));
+        }
+
+        Ok(builder.root.push(Mesh {
+            extensions: None,
+            extras: Default::default(),
+            name: Some(name),
+            primitives,
+            weights: None,
+        }))
+    }
+
+    // Vertex positions of every vertex buffer in the mesh's object space
+    pub fn positions(&self) -> Vec<GlamVec3> {
+        self.vertex_buffers.iter().flat_map(|x| x.vertices.positions()).collect()
+    }
+}
+
 impl HasReferences for MeshZ {
     fn hard_links(&self) -> Vec<u32> {
         self.material_crc32s.data.clone()
@@ -664,3 +975,72 @@ pub type MeshObjectFormat = FUELObjectFormat<MeshZHeader, MeshZ>;
 pub type MeshObjectFormatAlt = FUELObjectFormat<MeshZHeaderAlt, MeshZAlt>;
 pub type MeshObjectFormatAltAlt = FUELObjectFormat<MeshZHeaderAltAlt, MeshZAltAlt>;
 pub type MeshObjectFormatAltAltAlt = FUELObjectFormat<MeshZHeaderAltAlt, MeshZAltAltAlt>;
+
+#[cfg(test)]
+mod test {
+    use crate::fuel_fmt::mesh::{gltf_normal, IndexBufferExt, MeshGeometry, VertexGroup, VertexVector3u8};
+
+    fn vertex_group(index_buffer_index_begin: u32, face_count: u32, material_index: i16) -> VertexGroup {
+        serde_json::from_value(serde_json::json!({
+            "vertex_buffer_index": 1,
+            "index_buffer_index": 0,
+            "quad_range": {"begin": 0, "size": 0},
+            "flags": 0,
+            "vertex_buffer_range": {"begin": 0, "end": 0},
+            "vertex_count": 0,
+            "index_buffer_index_begin": index_buffer_index_begin,
+            "face_count": face_count,
+            "zero": 0,
+            "vertex_buffer_range_begin_or_zero": 8,
+            "vertex_size": 0,
+            "material_index": material_index,
+            "unuseds1": []
+        }))
+        .unwrap()
+    }
+
+    fn index_buffer(index_count: u16) -> IndexBufferExt {
+        serde_json::from_value(serde_json::json!({
+            "index_buffer_id": 0,
+            "indices": (0..index_count).collect::<Vec<u16>>()
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_mesh_primitive_ranges() {
+        let mut geometry = MeshGeometry {
+            vertex_buffers: vec![],
+            index_buffers: vec![index_buffer(7), index_buffer(3)],
+            vertex_groups: vec![],
+            material_crc32s: vec![],
+        };
+        let ranges = geometry.primitive_ranges();
+        assert_eq!(
+            ranges.iter().map(|x| (x.vertex_buffer_index, x.begin, x.end)).collect::<Vec<_>>(),
+            vec![(0, 0, 6), (1, 0, 3)]
+        );
+
+        geometry.vertex_groups = vec![vertex_group(0, 1, 0), vertex_group(3, 2, -1)];
+        let ranges = geometry.primitive_ranges();
+        assert_eq!(
+            ranges
+                .iter()
+                .map(|x| (x.vertex_buffer_index, x.index_buffer_index, x.begin, x.end, x.base_vertex, x.material_index))
+                .collect::<Vec<_>>(),
+            vec![(1, 0, 0, 3, 8, Some(0)), (1, 0, 3, 9, 8, None)]
+        );
+    }
+
+    #[test]
+    fn test_mesh_gltf_normal() {
+        let normal: VertexVector3u8 = serde_json::from_str("[1.0, 0.0, 0.0]").unwrap();
+        let [x, y, z] = gltf_normal(&normal);
+        assert!((x - 1.0).abs() < 0.001 && y.abs() < 0.01 && z.abs() < 0.01);
+
+        // Quantized normals are renormalized so glTF validators accept them
+        let normal: VertexVector3u8 = serde_json::from_str("[0.5, 0.5, 0.0]").unwrap();
+        let [x, y, z] = gltf_normal(&normal);
+        assert!((x * x + y * y + z * z - 1.0).abs() < 1e-5);
+    }
+}
@@ -1,15 +1,26 @@
 use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
 
 use crate::fuel_fmt::animation::AnimationObjectFormat;
+use crate::fuel_fmt::animframe::AnimFrameObjectFormat;
 use crate::fuel_fmt::binary::BinaryObjectFormat;
 use crate::fuel_fmt::bitmap::{BitmapObjectFormat, BitmapObjectFormatAlt};
 use crate::fuel_fmt::camera::CameraObjectFormat;
-use crate::fuel_fmt::collisionvol::CollisionVolObjectType;
+use crate::fuel_fmt::camerazone::CameraZoneObjectFormat;
+use crate::fuel_fmt::collisionvol::CollisionVolObjectFormat;
 use crate::fuel_fmt::common::FUELObjectFormatTrait;
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::flare::FlareObjectFormat;
+use crate::fuel_fmt::flaredata::FlareDataObjectFormat;
 use crate::fuel_fmt::fonts::FontsObjectFormat;
 use crate::fuel_fmt::gameobj::GameObjObjectFormat;
 use crate::fuel_fmt::genworld::GenWorldObjectFormat;
+use crate::fuel_fmt::graph::GraphObjectFormat;
 use crate::fuel_fmt::gwroad::GwRoadObjectFormat;
+use crate::fuel_fmt::hfog::HFogObjectFormat;
+use crate::fuel_fmt::hfogdata::HFogDataObjectFormat;
+use crate::fuel_fmt::light::LightObjectFormat;
 use crate::fuel_fmt::lightdata::LightDataObjectFormat;
 use crate::fuel_fmt::lod::{LodObjectFormat, LodObjectFormatAlt, LodObjectFormatAltAlt};
 use crate::fuel_fmt::loddata::LodDataObjectFormat;
@@ -23,6 +34,7 @@ use crate::fuel_fmt::mesh::{
 };
 use crate::fuel_fmt::meshdata::MeshDataObjectFormat;
 use crate::fuel_fmt::node::{NodeObjectFormat, NodeObjectFormatAlt};
+use crate::fuel_fmt::occluder::OccluderObjectFormat;
 use crate::fuel_fmt::omni::OmniObjectFormat;
 use crate::fuel_fmt::particles::{ParticlesObjectFormat, ParticlesObjectFormatAlt};
 use crate::fuel_fmt::particlesdata::ParticlesDataObjectFormat;
@@ -42,15 +54,25 @@ use crate::fuel_fmt::world::WorldObjectFormat;
 use crate::fuel_fmt::worldref::WorldRefObjectFormat;
 
 mod animation;
+mod animframe;
 mod binary;
 mod bitmap;
 mod camera;
+mod camerazone;
 mod collisionvol;
 pub(crate) mod common;
-mod fonts;
+pub(crate) mod database;
+mod flare;
+mod flaredata;
+pub(crate) mod fonts;
 mod gameobj;
 mod genworld;
+mod gltf;
+mod graph;
 mod gwroad;
+mod hfog;
+mod hfogdata;
+mod light;
 mod lightdata;
 mod lod;
 mod loddata;
@@ -60,6 +82,7 @@ mod materialobj;
 mod mesh;
 mod meshdata;
 mod node;
+mod occluder;
 mod omni;
 mod particles;
 mod particlesdata;
@@ -74,16 +97,28 @@ mod splinegraph;
 mod surface;
 mod surfacedatas;
 mod userdefine;
+mod volume;
 mod warp;
 mod world;
 mod worldref;
 
+// Every version get_formats knows, newest first
+pub const FORMAT_VERSIONS: [&str; 7] = [
+    "v1.381.67.09 - Asobo Studio - Internal Cross Technology",
+    "v1.381.66.09 - Asobo Studio - Internal Cross Technology",
+    "v1.381.65.09 - Asobo Studio - Internal Cross Technology",
+    "v1.381.64.09 - Asobo Studio - Internal Cross Technology",
+    "v1.379.60.09 - Asobo Studio - Internal Cross Technology",
+    "v1.325.50.07 - Asobo Studio - Internal Cross Technology",
+    "v1.220.50.07 - Asobo Studio - Internal Cross Technology",
+];
+
 pub fn get_formats<'a>(version: &String) -> HashMap<u32, &'a dyn FUELObjectFormatTrait> {
     let mut formats: HashMap<u32, &'a dyn FUELObjectFormatTrait> = HashMap::new();
 
     formats.insert(1175485833, AnimationObjectFormat::new());
     formats.insert(4240844041, CameraObjectFormat::new());
-    formats.insert(2398393906, CollisionVolObjectType::new());
+    formats.insert(2398393906, CollisionVolObjectFormat::new());
     formats.insert(1536002910, FontsObjectFormat::new());
     formats.insert(4096629181, GameObjObjectFormat::new());
     formats.insert(838505646, GenWorldObjectFormat::new());
@@ -109,6 +144,15 @@ pub fn get_formats<'a>(version: &String) -> HashMap<u32, &'a dyn FUELObjectForma
     formats.insert(2906362741, WorldRefObjectFormat::new());
     formats.insert(2259852416, BinaryObjectFormat::new());
     formats.insert(849267944, SoundObjectFormat::new());
+    formats.insert(4117606081, AnimFrameObjectFormat::new());
+    formats.insert(3979333606, CameraZoneObjectFormat::new());
+    formats.insert(2203168663, FlareObjectFormat::new());
+    formats.insert(1393846573, FlareDataObjectFormat::new());
+    formats.insert(1390918523, GraphObjectFormat::new());
+    formats.insert(2735949084, HFogObjectFormat::new());
+    formats.insert(3210467954, HFogDataObjectFormat::new());
+    formats.insert(1918499807, LightObjectFormat::new());
+    formats.insert(72309972, OccluderObjectFormat::new());
 
     match version.as_str() {
         "v1.381.67.09 - Asobo Studio - Internal Cross Technology" => {
@@ -179,3 +223,42 @@ pub fn get_formats<'a>(version: &String) -> HashMap<u32, &'a dyn FUELObjectForma
 
     return formats;
 }
+
+pub fn export(
+    database: &ObjectDatabase,
+    export_type: &str,
+    crc32s: &[u32],
+    output_path: &Path,
+) -> Result<()> {
+    match (export_type, crc32s) {
+        ("skel", [crc32]) => skel::export(database, *crc32, output_path),
+        ("skin", [crc32]) => skin::export(database, *crc32, output_path),
+        ("animation", [crc32]) => animation::export(database, *crc32, None, output_path),
+        ("animation", [crc32, skel_crc32]) => {
+            animation::export(database, *crc32, Some(*skel_crc32), output_path)
+        }
+        ("font", [crc32]) => fonts::export(database, *crc32, output_path),
+        ("spline", [crc32]) => spline::export(database, *crc32, output_path),
+        ("road", [crc32]) => gwroad::export(database, *crc32, output_path),
+        ("regions", [crc32]) => genworld::export_regions(database, *crc32, output_path),
+        ("categories", [crc32]) => genworld::export_categories(database, *crc32, output_path),
+        ("world", [crc32]) => world::export(database, *crc32, output_path),
+        ("materialanim", [crc32]) => materialanim::export(database, *crc32, output_path),
+        ("volumes", [_, ..]) => volume::export(database, crc32s, output_path),
+        ("skel", _)
+        | ("skin", _)
+        | ("animation", _)
+        | ("font", _)
+        | ("spline", _)
+        | ("road", _)
+        | ("regions", _)
+        | ("categories", _)
+        | ("world", _)
+        | ("materialanim", _)
+        | ("volumes", _) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "wrong number of crc32s for export type",
+        )),
+        _ => Err(Error::new(ErrorKind::InvalidInput, "unsupported export type")),
+    }
+}
@@ -1,8 +1,28 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
 use binwrite::BinWrite;
+use glam::{Mat4, Quat as GlamQuat, Vec3};
+use gltf_json::extensions::scene::khr_lights_punctual::KhrLightsPunctual;
+use gltf_json::{Index, Mesh, Node};
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
 
-use crate::fuel_fmt::common::{FUELObjectFormat, FixedVec, HasReferences, Mat4f, ResourceObjectZ, Vec3f, Quat, SphereZ, Color, Rect};
+use crate::fuel_fmt::common::{FUELObjectFormat, FUELObjectFormatTrait, FixedVec, FormatOptions, HasReferences, Mat4f, ResourceObjectZ, Vec3f, Quat, SphereZ, Color, Rect};
+use crate::fuel_fmt::camera;
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf;
+use crate::fuel_fmt::gltf::GltfBuilder;
+use crate::fuel_fmt::lod;
+use crate::fuel_fmt::mesh;
+use crate::fuel_fmt::omni;
+use crate::fuel_fmt::skin;
+use crate::names::NameDatabase;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -90,5 +110,513 @@ impl HasReferences for NodeZAlt {
     }
 }
 
-pub type NodeObjectFormat = FUELObjectFormat<ResourceObjectZ, NodeZ>;
-pub type NodeObjectFormatAlt = FUELObjectFormat<ResourceObjectZ, NodeZAlt>;
+impl NodeZ {
+    fn local_rotation(&self) -> GlamQuat {
+        let rotation = GlamQuat::from_slice(&self.rotation.data);
+        if rotation.length_squared() > 0f32 {
+            rotation.normalize()
+        } else {
+            GlamQuat::IDENTITY
+        }
+    }
+
+    fn local_translation(&self) -> Vec3 {
+        Vec3::from_slice(&self.translation.data)
+    }
+
+    // world_transform is column-major, world = parent world * translation * rotation * scale
+    fn local_transform(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(
+            Vec3::splat(self.scale),
+            self.local_rotation(),
+            self.local_translation(),
+        )
+    }
+}
+
+// Objects from other DPCs aren't in the database, the scene is exported without them
+fn skip_missing<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(x) => Ok(Some(x)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// An object that can't be read is left out of the scene with a warning rather than ending the export
+fn skip_unreadable<T>(crc32: u32, result: Result<T>) -> Option<T> {
+    match result {
+        Ok(x) => Some(x),
+        Err(error) if error.kind() == ErrorKind::NotFound => None,
+        Err(error) => {
+            println!("Warn: skipping object {} in the scene: {}", crc32, error);
+            None
+        }
+    }
+}
+
+// The parts of a node the scene needs, whatever its version
+struct SceneNode {
+    head_child_crc32: u32,
+    next_node_crc32: u32,
+    // Only NodeZ's links are named, any of NodeZAlt's crc32s may be the object it places
+    object_crc32s: Vec<u32>,
+    // NodeZAlt's local transform isn't decoded
+    transform: Option<(GlamQuat, Vec3, f32)>,
+}
+
+fn read_scene_node(database: &ObjectDatabase, crc32: u32) -> Result<SceneNode> {
+    if let Ok((_, node)) = database.read::<ResourceObjectZ, NodeZ>(crc32) {
+        return Ok(SceneNode {
+            head_child_crc32: node.head_child_crc32,
+            next_node_crc32: node.next_node_crc32,
+            object_crc32s: vec![node.lod_crc32],
+            transform: Some((node.local_rotation(), node.local_translation(), node.scale)),
+        });
+    }
+
+    let (_, node) = database.read::<ResourceObjectZ, NodeZAlt>(crc32)?;
+    Ok(SceneNode {
+        head_child_crc32: 0,
+        next_node_crc32: 0,
+        object_crc32s: vec![node.some_crc320, node.some_crc321, node.some_crc322, node.some_crc323, node.some_crc324],
+        transform: None,
+    })
+}
+
+// Meshes are pushed once and shared by every node whose Lod_Z uses them
+pub struct GltfSceneBuilder<'a> {
+    database: &'a ObjectDatabase,
+    pub builder: GltfBuilder,
+    meshes: HashMap<u32, Index<Mesh>>,
+    visited: HashSet<u32>,
+    // Cameras name the node they are attached to rather than the other way around
+    cameras: HashMap<u32, Vec<u32>>,
+    // NodeZAlt's sibling links aren't named so its children are found from their parent_crc32
+    children: HashMap<u32, Vec<u32>>,
+}
+
+impl<'a> GltfSceneBuilder<'a> {
+    pub fn new(database: &'a ObjectDatabase) -> Result<GltfSceneBuilder<'a>> {
+        let mut cameras: HashMap<u32, Vec<u32>> = HashMap::new();
+        for camera_crc32 in database.crc32s_of_class("Camera_Z") {
+            if let Some(camera) = skip_unreadable(camera_crc32, camera::read(database, camera_crc32)) {
+                cameras.entry(camera.node_crc32).or_default().push(camera_crc32);
+            }
+        }
+
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for node_crc32 in database.crc32s_of_class("Node_Z") {
+            if let Ok((_, node)) = database.read::<ResourceObjectZ, NodeZAlt>(node_crc32) {
+                children.entry(node.parent_crc32).or_default().push(node_crc32);
+            }
+        }
+
+        Ok(GltfSceneBuilder {
+            database,
+            builder: GltfBuilder::new(),
+            meshes: HashMap::new(),
+            visited: HashSet::new(),
+            cameras,
+            children,
+        })
+    }
+
+    fn push_light_node(&mut self, omni_crc32: u32) -> Result<Index<Node>> {
+        let light = omni::push_gltf_light(&mut self.builder, self.database, omni_crc32)?;
+        let mut node = gltf::node(self.database.names().name(omni_crc32));
+        node.extensions = Some(gltf_json::extensions::scene::Node {
+            khr_lights_punctual: Some(KhrLightsPunctual { light }),
+        });
+        Ok(self.builder.root.push(node))
+    }
+
+    fn push_camera_node(&mut self, camera_crc32: u32) -> Result<Index<Node>> {
+        let camera = camera::push_gltf_camera(&mut self.builder, self.database, camera_crc32)?;
+        Ok(self.builder.root.push(Node {
+            camera: Some(camera),
+            ..gltf::node(self.database.names().name(camera_crc32))
+        }))
+    }
+
+    // The object a node places, its class tells what it is
+    fn push_object_nodes(&mut self, crc32: u32) -> Result<Vec<Index<Node>>> {
+        let nodes = match self.database.class(crc32) {
+            Some("Lod_Z") => self.push_mesh_nodes(crc32),
+            Some("Omni_Z") => self.push_light_node(crc32).map(|x| vec![x]),
+            Some("Camera_Z") => self.push_camera_node(crc32).map(|x| vec![x]),
+            _ => Ok(vec![]),
+        };
+        Ok(skip_unreadable(crc32, nodes).unwrap_or_default())
+    }
+
+    fn push_mesh_nodes(&mut self, lod_crc32: u32) -> Result<Vec<Index<Node>>> {
+        let mut nodes = Vec::new();
+
+        let skin_crc32s = match skip_unreadable(lod_crc32, lod::skin_crc32s(self.database, lod_crc32)) {
+            Some(skin_crc32s) => skin_crc32s,
+            None => return Ok(nodes),
+        };
+
+        for skin_crc32 in skin_crc32s {
+            let mesh_crc32s = match skip_unreadable(skin_crc32, skin::mesh_crc32s(self.database, skin_crc32)) {
+                Some(mesh_crc32s) => mesh_crc32s,
+                None => continue,
+            };
+
+            for mesh_crc32 in mesh_crc32s {
+                let name = self.database.names().name(mesh_crc32);
+                let gltf_mesh = match self.meshes.get(&mesh_crc32) {
+                    Some(gltf_mesh) => *gltf_mesh,
+                    None => {
+                        let mesh = match skip_unreadable(mesh_crc32, mesh::read(self.database, mesh_crc32)) {
+                            Some(mesh) => mesh,
+                            None => continue,
+                        };
+                        // Skinned meshes are placed in their bind pose
+                        let gltf_mesh = mesh.push_gltf_mesh(&mut self.builder, self.database, name.clone(), None)?;
+                        self.meshes.insert(mesh_crc32, gltf_mesh);
+                        gltf_mesh
+                    }
+                };
+
+                nodes.push(self.builder.root.push(Node {
+                    mesh: Some(gltf_mesh),
+                    ..gltf::node(name)
+                }));
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    // Pushes the node, its geometry and its children, which are linked from head_child_crc32 through next_node_crc32
+    pub fn push_node_tree(&mut self, crc32: u32) -> Result<Option<Index<Node>>> {
+        if crc32 == 0 || !self.visited.insert(crc32) {
+            return Ok(None);
+        }
+
+        let node = match skip_unreadable(crc32, read_scene_node(self.database, crc32)) {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+
+        let mut children = Vec::new();
+        for object_crc32 in node.object_crc32s.iter().filter(|x| **x != 0) {
+            children.append(&mut self.push_object_nodes(*object_crc32)?);
+        }
+        for camera_crc32 in self.cameras.remove(&crc32).unwrap_or_default() {
+            if !node.object_crc32s.contains(&camera_crc32) {
+                if let Some(camera) = skip_unreadable(camera_crc32, self.push_camera_node(camera_crc32)) {
+                    children.push(camera);
+                }
+            }
+        }
+
+        let mut child_crc32 = node.head_child_crc32;
+        while child_crc32 != 0 && !self.visited.contains(&child_crc32) {
+            let next_node_crc32 = match skip_missing(read_scene_node(self.database, child_crc32)) {
+                Ok(Some(child)) => child.next_node_crc32,
+                _ => 0,
+            };
+            if let Some(child) = self.push_node_tree(child_crc32)? {
+                children.push(child);
+            }
+            child_crc32 = next_node_crc32;
+        }
+        for child_crc32 in self.children.remove(&crc32).unwrap_or_default() {
+            if let Some(child) = self.push_node_tree(child_crc32)? {
+                children.push(child);
+            }
+        }
+
+        let mut gltf_node = Node {
+            children: if children.is_empty() { None } else { Some(children) },
+            ..gltf::node(self.database.names().name(crc32))
+        };
+        match node.transform {
+            Some((rotation, translation, scale)) => {
+                gltf_node.rotation = Some(gltf_json::scene::UnitQuaternion(rotation.to_array()));
+                gltf_node.translation = Some(translation.to_array());
+                gltf_node.scale = Some([scale, scale, scale]);
+            }
+            None => println!("Warn: node {} is placed at its parent's origin, its transform isn't decoded", crc32),
+        }
+        Ok(Some(self.builder.root.push(gltf_node)))
+    }
+}
+
+#[derive(Deserialize)]
+struct Object {
+    body: NodeZ,
+}
+
+// Nodes being packed are read from their object directory so edits to a parent reach its children
+fn read_node(database: &ObjectDatabase, crc32: u32) -> Result<NodeZ> {
+    let mut object_path: OsString = database.path(crc32)?.clone().into_os_string();
+    object_path.push(".d");
+    let json_path = PathBuf::from(object_path).join("object.json");
+
+    if json_path.exists() {
+        let object: Object = serde_json::from_reader(File::open(json_path)?)?;
+        Ok(object.body)
+    } else {
+        Ok(database.read::<ResourceObjectZ, NodeZ>(crc32)?.1)
+    }
+}
+
+// Derived values of a node, None when they depend on objects that aren't in the DPC
+struct NodeTransforms {
+    reciprocal_scale: f32,
+    world_transform: Option<Mat4>,
+    sphere: Option<(Vec3, f32)>,
+}
+
+fn world_transform(database: &ObjectDatabase, node: &NodeZ, visited: &mut HashSet<u32>) -> Result<Option<Mat4>> {
+    if node.parent_crc32 == 0 {
+        return Ok(Some(node.local_transform()));
+    }
+
+    if !visited.insert(node.parent_crc32) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Node {} is its own ancestor", node.parent_crc32),
+        ));
+    }
+
+    let parent = match skip_missing(read_node(database, node.parent_crc32))? {
+        Some(parent) => parent,
+        None => return Ok(None),
+    };
+
+    Ok(world_transform(database, &parent, visited)?.map(|x| x * node.local_transform()))
+}
+
+// The world transforms of the Node_Z placing each object, an object placed by several nodes has several.
+// NodeZAlt's transforms aren't decoded so the objects it places are left out.
+pub fn object_world_transforms(database: &ObjectDatabase) -> Result<HashMap<u32, Vec<Mat4>>> {
+    let mut transforms: HashMap<u32, Vec<Mat4>> = HashMap::new();
+    for crc32 in database.crc32s_of_class("Node_Z") {
+        let node = match database.read::<ResourceObjectZ, NodeZ>(crc32) {
+            Ok((_, node)) if node.lod_crc32 != 0 => node,
+            _ => continue,
+        };
+        match world_transform(database, &node, &mut HashSet::from([crc32]))? {
+            Some(transform) => transforms.entry(node.lod_crc32).or_default().push(transform),
+            None => println!("Warn: node {} has an ancestor outside of the DPC, its world transform isn't known", crc32),
+        }
+    }
+    Ok(transforms)
+}
+
+// World space bounds of the meshes under the node's Lod_Z
+fn bounding_sphere(database: &ObjectDatabase, node: &NodeZ, world_transform: &Mat4) -> Result<Option<(Vec3, f32)>> {
+    if database.class(node.lod_crc32) != Some("Lod_Z") {
+        return Ok(None);
+    }
+
+    let mut positions: Vec<Vec3> = Vec::new();
+    for skin_crc32 in skip_missing(lod::skin_crc32s(database, node.lod_crc32))?.unwrap_or_default() {
+        for mesh_crc32 in skip_missing(skin::mesh_crc32s(database, skin_crc32))?.unwrap_or_default() {
+            for position in skip_missing(mesh::read(database, mesh_crc32))?.map(|x| x.positions()).unwrap_or_default() {
+                positions.push(world_transform.transform_point3(position));
+            }
+        }
+    }
+
+    if positions.is_empty() {
+        return Ok(None);
+    }
+
+    let min = positions.iter().fold(Vec3::splat(f32::MAX), |a, b| a.min(*b));
+    let max = positions.iter().fold(Vec3::splat(f32::MIN), |a, b| a.max(*b));
+    let center = (min + max) / 2f32;
+    let radius = positions.iter().map(|x| x.distance(center)).fold(0f32, f32::max);
+
+    Ok(Some((center, radius)))
+}
+
+impl NodeTransforms {
+    fn compute(database: &ObjectDatabase, crc32: u32, node: &NodeZ, sphere: bool) -> Result<NodeTransforms> {
+        let world_transform = world_transform(database, node, &mut HashSet::from([crc32]))?;
+        let sphere = match (&world_transform, sphere) {
+            (Some(world_transform), true) => bounding_sphere(database, node, world_transform)?,
+            _ => None,
+        };
+
+        Ok(NodeTransforms {
+            reciprocal_scale: 1f32 / node.scale,
+            world_transform,
+            sphere,
+        })
+    }
+
+    fn report(&self, crc32: u32, node: &NodeZ) {
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() <= 1e-4 * a.abs().max(b.abs()).max(1f32));
+
+        if !close(&[node.reciprocal_scale], &[self.reciprocal_scale]) {
+            println!("Warn: node {} reciprocal_scale is {} but should be {}", crc32, node.reciprocal_scale, self.reciprocal_scale);
+        }
+
+        match &self.world_transform {
+            Some(world_transform) if !close(&node.world_transform.data, &world_transform.to_cols_array()) => {
+                println!("Warn: node {} world_transform is {:?} but should be {:?}", crc32, node.world_transform.data, world_transform.to_cols_array());
+            }
+            None => println!("Warn: node {} has an ancestor outside of the DPC, its world_transform can't be checked", crc32),
+            _ => (),
+        }
+
+        if let Some((center, radius)) = self.sphere {
+            let expected = [center.x, center.y, center.z, radius];
+            let actual = [&node.sphere.center.data[..], &[node.sphere.radius]].concat();
+            if !close(&actual, &expected) {
+                println!("Warn: node {} sphere is {:?} but should be {:?}", crc32, actual, expected);
+            }
+        }
+    }
+
+    fn apply(&self, node: &mut NodeZ, sphere: bool) {
+        node.reciprocal_scale = self.reciprocal_scale;
+
+        if let Some(world_transform) = &self.world_transform {
+            node.world_transform.data = world_transform.to_cols_array().to_vec();
+        }
+
+        if let (Some((center, radius)), true) = (self.sphere, sphere) {
+            node.sphere.center.data = center.to_array().to_vec();
+            node.sphere.radius = radius;
+        }
+    }
+}
+
+// Directories are named like the objects they pack, crc32.Class.d or crc32_name.Class.d
+fn directory_crc32(input_path: &Path) -> Result<u32> {
+    input_path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.split(['.', '_']).next())
+        .and_then(|x| x.parse::<u32>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Node directory isn't named crc32.Node_Z.d or crc32_name.Node_Z.d"))
+}
+
+thread_local! {
+    // Every node of a DPC looks up its parents in the same objects directory, it is indexed once
+    static DATABASES: RefCell<HashMap<PathBuf, Rc<ObjectDatabase>>> = RefCell::new(HashMap::new());
+}
+
+fn objects_database(objects_path: &Path) -> Result<Rc<ObjectDatabase>> {
+    DATABASES.with(|databases| {
+        if let Some(database) = databases.borrow().get(objects_path) {
+            return Ok(database.clone());
+        }
+
+        let database = Rc::new(ObjectDatabase::new(objects_path, NameDatabase::new())?);
+        databases.borrow_mut().insert(objects_path.to_path_buf(), database.clone());
+        Ok(database)
+    })
+}
+
+pub struct NodeObjectFormat;
+
+impl NodeObjectFormat {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for NodeObjectFormat {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        if !options.node_transforms && !options.node_sphere && !options.node_check {
+            return FUELObjectFormat::<ResourceObjectZ, NodeZ>::new()
+                .pack(input_path, header, body, options);
+        }
+
+        // Parents are looked up among the other objects of the extracted DPC
+        let crc32 = directory_crc32(input_path)?;
+        let objects_path = match input_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let database = objects_database(objects_path)?;
+
+        FUELObjectFormat::<ResourceObjectZ, NodeZ>::new().pack_with(input_path, header, body, |_, body| {
+            let transforms = NodeTransforms::compute(&database, crc32, body, options.node_sphere || options.node_check)?;
+
+            if options.node_check {
+                transforms.report(crc32, body);
+            }
+
+            if options.node_transforms || options.node_sphere {
+                transforms.apply(body, options.node_sphere);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ResourceObjectZ, NodeZ>::new().unpack(header, body, output_path, options)
+    }
+}
+
+pub struct NodeObjectFormatAlt;
+
+impl NodeObjectFormatAlt {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for NodeObjectFormatAlt {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // NodeZAlt's transforms and sphere aren't decoded so there is nothing to derive or check
+        if options.node_transforms || options.node_sphere || options.node_check {
+            println!("Warn: the node options don't apply to this version's Node_Z, {} is packed as is", input_path.display());
+        }
+
+        FUELObjectFormat::<ResourceObjectZ, NodeZAlt>::new().pack(input_path, header, body, options)
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ResourceObjectZ, NodeZAlt>::new().unpack(header, body, output_path, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::fuel_fmt::node::directory_crc32;
+
+    #[test]
+    fn test_node_directory_crc32() {
+        assert_eq!(directory_crc32(Path::new("objects/1234.Node_Z.d")).unwrap(), 1234);
+        assert_eq!(directory_crc32(Path::new("objects/1234_root.Node_Z.d")).unwrap(), 1234);
+        assert_eq!(directory_crc32(Path::new("1234.Node_Z.d")).unwrap(), 1234);
+        assert!(directory_crc32(Path::new("objects/root.Node_Z.d")).is_err());
+    }
+}
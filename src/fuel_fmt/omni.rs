@@ -1,8 +1,17 @@
+use std::io::Result;
+
 use binwrite::BinWrite;
+use gltf_json::extensions::scene::khr_lights_punctual::{Light, Type};
+use gltf_json::validation::Checked;
+use gltf_json::Index;
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::fuel_fmt::common::{FUELObjectFormat, FixedVec, HasReferences, ObjectZ};
+use crate::fuel_fmt::common::{FUELObjectFormat, FixedVec, HasReferences, ObjectZ, ResourceObjectZ};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf::GltfBuilder;
+use crate::fuel_fmt::lightdata::LightDataZ;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -23,4 +32,54 @@ impl HasReferences for OmniZ {
     }
 }
 
+// Only the colour of the linked LightData_Z is decoded. The colour, range, attenuation and light type in
+// Omni_Z's words haven't been located, so every omni is exported as a point light with no range and the
+// raw words are kept in the light's extras.
+pub fn push_gltf_light(builder: &mut GltfBuilder, database: &ObjectDatabase, crc32: u32) -> Result<Index<Light>> {
+    let (header, omni) = database.read::<ObjectZ, OmniZ>(crc32)?;
+
+    let light_data = match header
+        .soft_links()
+        .into_iter()
+        .find(|x| database.class(*x) == Some("LightData_Z"))
+    {
+        Some(light_data_crc32) => Some(database.read::<ResourceObjectZ, LightDataZ>(light_data_crc32)?.1),
+        None => None,
+    };
+
+    let extras = json!({
+        "omni": omni.data.data,
+        "light_data": light_data,
+    });
+
+    Ok(builder.push_light(Light {
+        color: light_data.as_ref().map_or([1f32; 3], |x| x.color()),
+        extensions: None,
+        extras: Some(serde_json::value::to_raw_value(&extras)?),
+        intensity: 1f32,
+        name: Some(database.names().name(crc32)),
+        range: None,
+        spot: None,
+        type_: Checked::Valid(Type::Point),
+    }))
+}
+
 pub type OmniObjectFormat = FUELObjectFormat<ObjectZ, OmniZ>;
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::omni::OmniZ;
+
+    #[test]
+    fn test_omni_json() {
+        let bytes: Vec<u8> = (0u32..50).flat_map(|x| (x * 0x01010101).to_le_bytes()).collect();
+        let (_, omni) = OmniZ::parse(&bytes).unwrap();
+        let omni: OmniZ = serde_json::from_str(&serde_json::to_string(&omni).unwrap()).unwrap();
+        let mut written = Vec::new();
+        omni.write(&mut written).unwrap();
+        assert_eq!(bytes, written);
+    }
+}
@@ -1,68 +1,33 @@
 use binwrite::BinWrite;
-use nom_derive::NomLE;
+use nom_derive::{NomLE, Parse};
 use serde::{Deserialize, Serialize};
 
 use crate::fuel_fmt::common::{
-    FUELObjectFormat, FixedVec, HasReferences, Mat4f, ObjectZ, PascalArray,
+    FUELObjectFormat, FixedVec, HasReferences, Mat4f, MaybeF32, ObjectZ, PascalArray,
 };
 
+// A flag and a list of keys. What each curve drives hasn't been matched against the game, the key
+// words are MaybeF32 so they read as floats where they are floats and still round trip otherwise.
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct ParticlesZUnknown1 {
-    unknown0: u32,
-    unknown1: u32,
-    unknown2: u32,
-}
-
-#[derive(BinWrite)]
-#[binwrite(little)]
-#[derive(Serialize, Deserialize, NomLE)]
-struct ParticlesZUnknown2 {
-    unknown0: u32,
-    unknown1: u32,
-    unknown2: u32,
-    unknown3: u32,
-    unknown4: u32,
-}
-
-#[derive(BinWrite)]
-#[binwrite(little)]
-#[derive(Serialize, Deserialize, NomLE)]
-struct ParticlesZUnknown4 {
-    unknown0: u32,
-    unknown1: u32,
-}
-
-#[derive(BinWrite)]
-#[binwrite(little)]
-#[derive(Serialize, Deserialize, NomLE)]
-struct ParticlesZUnknown5 {
-    unknown0: u32,
-    unknown1: u32,
-    unknown2: u32,
-    unknown3: u32,
+struct ParticlesZCurve<T: BinWrite> {
+    flag: u16,
+    keys: PascalArray<T>,
 }
 
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct ParticlesZUnknown0 {
-    data: FixedVec<u32, 19>,
-    unknown1flag: u16,
-    unknown1s: PascalArray<ParticlesZUnknown1>,
-    unknown2flag: u16,
-    unknown2s: PascalArray<ParticlesZUnknown2>,
-    unknown3flag: u16,
-    unknown3s: PascalArray<ParticlesZUnknown2>,
-    unknown4flag: u16,
-    unknown4s: PascalArray<ParticlesZUnknown4>,
-    unknown5flag: u16,
-    unknown5s: PascalArray<ParticlesZUnknown5>,
-    unknown6flag: u16,
-    unknown6s: PascalArray<ParticlesZUnknown5>,
-    unknown7flag: u16,
-    unknown7s: PascalArray<ParticlesZUnknown4>,
+struct ParticlesZEmitter {
+    data: FixedVec<MaybeF32, 19>,
+    unknown1s: ParticlesZCurve<FixedVec<MaybeF32, 3>>,
+    unknown2s: ParticlesZCurve<FixedVec<MaybeF32, 5>>,
+    unknown3s: ParticlesZCurve<FixedVec<MaybeF32, 5>>,
+    unknown4s: ParticlesZCurve<FixedVec<MaybeF32, 2>>,
+    unknown5s: ParticlesZCurve<FixedVec<MaybeF32, 4>>,
+    unknown6s: ParticlesZCurve<FixedVec<MaybeF32, 4>>,
+    unknown7s: ParticlesZCurve<FixedVec<MaybeF32, 2>>,
     unknown8: u32,
 }
 
@@ -71,16 +36,17 @@ struct ParticlesZUnknown0 {
 #[derive(Serialize, Deserialize, NomLE)]
 #[nom(Exact)]
 pub struct ParticlesZ {
-    unknown0s: PascalArray<ParticlesZUnknown0>,
+    emitters: PascalArray<ParticlesZEmitter>,
     mats: PascalArray<Mat4f>,
     unknown2: u32,
     unknown3: u16,
 }
 
+// The keys of these versions are smaller
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
-struct ParticlesZUnknown0Alt {
+struct ParticlesZEmitterAlt {
     data: FixedVec<u32, 19>,
     unknown1flag: u16,
     unknown1s: PascalArray<FixedVec<u32, 2>>,
@@ -99,12 +65,13 @@ struct ParticlesZUnknown0Alt {
     unknown8: u32,
 }
 
+// The matrices of these versions aren't always floats
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
 #[nom(Exact)]
 pub struct ParticlesZAlt {
-    unknown0s: PascalArray<ParticlesZUnknown0Alt>,
+    emitters: PascalArray<ParticlesZEmitterAlt>,
     mats: PascalArray<FixedVec<u32, 16>>,
     unknown2: u32,
     unknown3: u16,
@@ -132,3 +99,49 @@ impl HasReferences for ParticlesZAlt {
 
 pub type ParticlesObjectFormat = FUELObjectFormat<ObjectZ, ParticlesZ>;
 pub type ParticlesObjectFormatAlt = FUELObjectFormat<ObjectZ, ParticlesZAlt>;
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::particles::{ParticlesZ, ParticlesZAlt};
+
+    // One emitter with one key in each curve of the given size in words
+    fn particles_bytes(key_sizes: &[usize]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(&1u32.to_le_bytes());
+        bytes.extend((0u32..19).flat_map(|x| x.to_le_bytes()));
+        for (i, key_size) in key_sizes.iter().enumerate() {
+            bytes.extend(&(i as u16).to_le_bytes());
+            bytes.extend(&1u32.to_le_bytes());
+            bytes.extend((0..*key_size).flat_map(|x| (x as f32).to_le_bytes()));
+        }
+        bytes.extend(&8u32.to_le_bytes());
+        bytes.extend(&0u32.to_le_bytes());
+        bytes.extend(&2u32.to_le_bytes());
+        bytes.extend(&3u16.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_particles() {
+        let bytes = particles_bytes(&[3, 5, 5, 2, 4, 4, 2]);
+        let (_, particles) = ParticlesZ::parse(&bytes).unwrap();
+        let particles: ParticlesZ = serde_json::from_str(&serde_json::to_string(&particles).unwrap()).unwrap();
+        let mut written = Vec::new();
+        particles.write(&mut written).unwrap();
+        assert_eq!(bytes, written);
+    }
+
+    #[test]
+    fn test_particles_alt() {
+        let bytes = particles_bytes(&[2, 3, 3, 2, 4, 4, 2]);
+        assert!(ParticlesZ::parse(&bytes).is_err());
+        let (_, particles) = ParticlesZAlt::parse(&bytes).unwrap();
+        let particles: ParticlesZAlt = serde_json::from_str(&serde_json::to_string(&particles).unwrap()).unwrap();
+        let mut written = Vec::new();
+        particles.write(&mut written).unwrap();
+        assert_eq!(bytes, written);
+    }
+}
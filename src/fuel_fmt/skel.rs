@@ -1,10 +1,22 @@
+use std::fs::File;
+use std::io::{Result, Write};
+use std::path::Path;
+
 use binwrite::BinWrite;
+use glam::Mat4;
+use gltf_json::accessor::Type;
+use gltf_json::scene::UnitQuaternion;
+use gltf_json::{Index, Node, Skin};
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
 
 use crate::fuel_fmt::common::{
     FUELObjectFormat, HasReferences, Mat4f, PascalArray, Quat, ResourceObjectZ, Vec3f, Vec3i32,
 };
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf;
+use crate::fuel_fmt::gltf::GltfBuilder;
+use crate::names::NameDatabase;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -95,3 +107,213 @@ impl HasReferences for SkelZ {
 }
 
 pub type SkelObjectFormat = FUELObjectFormat<ResourceObjectZ, SkelZ>;
+
+impl SkelZBone {
+    // The bind pose is stored in object space, D3D's row-major layout is glTF's column-major layout
+    fn bind_matrix(&self) -> Mat4 {
+        Mat4::from_cols_slice(&self.transformation.data)
+    }
+}
+
+impl SkelZ {
+    fn parent(&self, bone_index: usize) -> Option<usize> {
+        let parent_index = self.bones.data[bone_index].parent_index;
+        if parent_index < 0 || parent_index as usize >= self.bones.data.len() {
+            None
+        } else {
+            Some(parent_index as usize)
+        }
+    }
+
+    fn children(&self, bone_index: Option<usize>) -> Vec<usize> {
+        (0..self.bones.data.len())
+            .filter(|x| self.parent(*x) == bone_index)
+            .collect()
+    }
+
+    // Pushes one node per bone, in bone order, and a skin using them as joints
+    pub fn push_gltf_skin(
+        &self,
+        builder: &mut GltfBuilder,
+        names: &NameDatabase,
+    ) -> (Index<Skin>, Vec<Index<Node>>) {
+        let first_joint = builder.root.nodes.len() as u32;
+        let joints: Vec<Index<Node>> = (0..self.bones.data.len() as u32)
+            .map(|x| Index::new(first_joint + x))
+            .collect();
+
+        let mut inverse_bind_matrices: Vec<f32> = Vec::with_capacity(self.bones.data.len() * 16);
+
+        for (i, bone) in self.bones.data.iter().enumerate() {
+            let bind_matrix = bone.bind_matrix();
+            let local = match self.parent(i) {
+                Some(parent) => {
+                    self.bones.data[parent].bind_matrix().inverse() * bind_matrix
+                }
+                None => bind_matrix,
+            };
+            let (scale, rotation, translation) = local.to_scale_rotation_translation();
+
+            let inverse_bind_matrix = if bind_matrix.determinant() != 0f32 {
+                bind_matrix.inverse()
+            } else {
+                Mat4::IDENTITY
+            };
+            inverse_bind_matrices.extend_from_slice(&inverse_bind_matrix.to_cols_array());
+
+            let children: Vec<Index<Node>> = self
+                .children(Some(i))
+                .iter()
+                .map(|x| joints[*x])
+                .collect();
+
+            builder.root.push(Node {
+                children: if children.is_empty() {
+                    None
+                } else {
+                    Some(children)
+                },
+                rotation: Some(UnitQuaternion(rotation.to_array())),
+                scale: Some(scale.to_array()),
+                translation: Some(translation.to_array()),
+                ..gltf::node(names.name(bone.bone_name))
+            });
+        }
+
+        let inverse_bind_matrices =
+            builder.push_accessor(&inverse_bind_matrices, Type::Mat4, None);
+
+        let roots: Vec<Index<Node>> = self.children(None).iter().map(|x| joints[*x]).collect();
+
+        let skin = builder.root.push(Skin {
+            extensions: None,
+            extras: Default::default(),
+            inverse_bind_matrices: Some(inverse_bind_matrices),
+            joints: joints.clone(),
+            name: None,
+            skeleton: if roots.len() == 1 { Some(roots[0]) } else { None },
+        });
+
+        (skin, roots)
+    }
+
+    pub fn joint(&self, bone_name: u32) -> Option<u16> {
+        self.bones
+            .data
+            .iter()
+            .position(|x| x.bone_name == bone_name)
+            .map(|x| x as u16)
+    }
+
+    fn write_tree(
+        &self,
+        output: &mut dyn Write,
+        names: &NameDatabase,
+        bone_index: usize,
+        depth: usize,
+    ) -> Result<()> {
+        let bone_name = self.bones.data[bone_index].bone_name;
+        writeln!(
+            output,
+            "{}{} {} ({})",
+            "  ".repeat(depth),
+            bone_index,
+            names.name(bone_name),
+            bone_name
+        )?;
+
+        for child in self.children(Some(bone_index)) {
+            self.write_tree(output, names, child, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn export(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let (_, skel): (ResourceObjectZ, SkelZ) = database.read(crc32)?;
+
+    if output_path.extension().and_then(|x| x.to_str()) == Some("txt") {
+        let mut output_file = File::create(output_path)?;
+        for root in skel.children(None) {
+            skel.write_tree(&mut output_file, database.names(), root, 0)?;
+        }
+        return Ok(());
+    }
+
+    let mut builder = GltfBuilder::new();
+    let (_, roots) = skel.push_gltf_skin(&mut builder, database.names());
+
+    let skeleton = builder.root.push(Node {
+        children: Some(roots),
+        ..gltf::node(database.names().name(crc32))
+    });
+    builder.push_scene(vec![skeleton]);
+
+    builder.write(output_path)
+}
+
+#[cfg(test)]
+mod test {
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::common::assert_round_trip;
+    use crate::fuel_fmt::gltf::GltfBuilder;
+    use crate::fuel_fmt::skel::SkelZ;
+    use crate::names::NameDatabase;
+
+    fn bone_bytes(parent_index: i32, bone_name: u32, translation: [f32; 3]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 164];
+        let mut transformation = [0f32; 16];
+        transformation[0] = 1.0;
+        transformation[5] = 1.0;
+        transformation[10] = 1.0;
+        transformation[15] = 1.0;
+        transformation[12..15].copy_from_slice(&translation);
+        for value in transformation.iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&parent_index.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 12]);
+        bytes.extend_from_slice(&bone_name.to_le_bytes());
+        bytes
+    }
+
+    fn skel_bytes() -> Vec<u8> {
+        let mut bytes = vec![0u8; 20];
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend(bone_bytes(-1, 10, [1.0, 0.0, 0.0]));
+        bytes.extend(bone_bytes(0, 11, [1.0, 2.0, 0.0]));
+        bytes.extend(bone_bytes(-1, 12, [0.0, 0.0, 3.0]));
+        bytes.extend_from_slice(&[0u8; 28]);
+        bytes
+    }
+
+    #[test]
+    fn test_skel_round_trip() {
+        assert_round_trip::<SkelZ>(&skel_bytes());
+    }
+
+    #[test]
+    fn test_skel_hierarchy() {
+        let (_, skel) = SkelZ::parse(&skel_bytes()).unwrap();
+        assert_eq!(skel.children(None), vec![0, 2]);
+        assert_eq!(skel.children(Some(0)), vec![1]);
+        assert_eq!(skel.joint(11), Some(1));
+        assert_eq!(skel.joint(13), None);
+
+        let mut names = NameDatabase::new();
+        names.insert(10, "root");
+        let mut tree = Vec::new();
+        skel.write_tree(&mut tree, &names, 0, 0).unwrap();
+        assert_eq!(String::from_utf8(tree).unwrap(), "0 root (10)\n  1 11 (11)\n");
+
+        let mut builder = GltfBuilder::new();
+        let (skin, roots) = skel.push_gltf_skin(&mut builder, &names);
+        assert_eq!(roots.iter().map(|x| x.value()).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(builder.root.skins[skin.value()].skeleton, None);
+        assert_eq!(builder.root.nodes[0].children.as_ref().unwrap()[0].value(), 1);
+        assert_eq!(builder.root.nodes[1].translation, Some([0.0, 2.0, 0.0]));
+        assert_eq!(builder.root.nodes[2].translation, Some([0.0, 0.0, 3.0]));
+    }
+}
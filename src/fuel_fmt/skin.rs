@@ -1,23 +1,37 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
 use binwrite::BinWrite;
-use nom_derive::NomLE;
+use gltf_json::Node;
+use nom::IResult;
+use nom_derive::{Nom, NomLE, Parse};
 use serde::{Deserialize, Serialize};
 
-use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ObjectZ, PascalArray};
-
-static mut SKIN_DATA_COUNT: u32 = 0;
+use crate::fuel_fmt::common::{FUELObjectFormat, HasReferences, ObjectZ, PascalArray, ResourceObjectZ};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf;
+use crate::fuel_fmt::gltf::GltfBuilder;
+use crate::fuel_fmt::mesh;
+use crate::fuel_fmt::skel::SkelZ;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
-#[derive(Serialize, Deserialize, NomLE)]
+// NomLE can't take ExtraArgs, parse_le is the entry point
+#[derive(Serialize, Deserialize, Nom)]
+#[nom(ExtraArgs(data_count: u32))]
 struct SkinZSkinSubsection {
     vertex_group_crc32: u32,
     unknown_crc320: u32,
     unknown_crc321: u32,
     unknown_crc322: u32,
-    #[nom(Count(unsafe { SKIN_DATA_COUNT as usize }))]
+    #[nom(Count(data_count as usize))]
     data: Vec<u32>,
 }
 
+fn parse_skin_sections(i: &[u8], data_count: u32) -> IResult<&[u8], PascalArray<PascalArray<SkinZSkinSubsection>>> {
+    PascalArray::parse_with(i, |i| PascalArray::parse_with(i, |i| SkinZSkinSubsection::parse_le(i, data_count)))
+}
+
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
@@ -30,9 +44,9 @@ pub struct SkinZ {
     u3: u32,
     #[nom(Verify(*one_and_a_half == 1.5))]
     one_and_a_half: f32,
-    #[nom(PostExec(unsafe { SKIN_DATA_COUNT = data_count }))]
     #[nom(Verify(*data_count == 21))]
     data_count: u32,
+    #[nom(Parse = "{ |i| parse_skin_sections(i, data_count) }")]
     skin_sections: PascalArray<PascalArray<SkinZSkinSubsection>>,
 }
 
@@ -46,8 +60,8 @@ pub struct SkinZAlt {
     u1: u32,
     u2: u8,
     one_and_a_half: f32,
-    #[nom(PostExec(unsafe { SKIN_DATA_COUNT = data_count }))]
     data_count: u32,
+    #[nom(Parse = "{ |i| parse_skin_sections(i, data_count) }")]
     skin_sections: PascalArray<PascalArray<SkinZSkinSubsection>>,
 }
 
@@ -73,5 +87,127 @@ impl HasReferences for SkinZAlt {
     }
 }
 
+// The parts of a skin the exporters use, whichever version it was read as
+struct Skin {
+    header: ObjectZ,
+    mesh_crc32s: Vec<u32>,
+    skin_sections: Vec<PascalArray<SkinZSkinSubsection>>,
+}
+
+// The Skin_Z versions differ in layout so at most one of them parses
+fn read(database: &ObjectDatabase, crc32: u32) -> Result<Skin> {
+    if let Ok((header, skin)) = database.read::<ObjectZ, SkinZ>(crc32) {
+        return Ok(Skin {
+            header,
+            mesh_crc32s: skin.mesh_crc32s.data,
+            skin_sections: skin.skin_sections.data,
+        });
+    }
+    let (header, skin) = database.read::<ObjectZ, SkinZAlt>(crc32)?;
+    Ok(Skin {
+        header,
+        mesh_crc32s: skin.mesh_crc32s.data,
+        skin_sections: skin.skin_sections.data,
+    })
+}
+
+impl Skin {
+    // Blend indices select a subsection of the mesh's skin section, whose vertex group is named
+    // after the bone it follows. Meshes without their own section share the last one.
+    fn palette(&self, mesh_index: usize, skel: &SkelZ) -> Vec<u16> {
+        match self
+            .skin_sections
+            .get(mesh_index)
+            .or(self.skin_sections.last())
+        {
+            Some(skin_section) => skin_section
+                .data
+                .iter()
+                .map(|x| skel.joint(x.vertex_group_crc32).unwrap_or(0))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+pub fn mesh_crc32s(database: &ObjectDatabase, crc32: u32) -> Result<Vec<u32>> {
+    Ok(read(database, crc32)?.mesh_crc32s)
+}
+
 pub type SkinObjectFormat = FUELObjectFormat<ObjectZ, SkinZ>;
 pub type SkinObjectFormatAlt = FUELObjectFormat<ObjectZ, SkinZAlt>;
+
+pub fn export(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let skin = read(database, crc32)?;
+
+    let skel_crc32 = match skin
+        .header
+        .soft_links()
+        .into_iter()
+        .find(|x| database.class(*x) == Some("Skel_Z"))
+    {
+        Some(skel_crc32) => skel_crc32,
+        None => {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No Skel_Z referenced by Skin_Z {}", crc32),
+            ))
+        }
+    };
+    let (_, skel): (ResourceObjectZ, SkelZ) = database.read(skel_crc32)?;
+
+    let mut builder = GltfBuilder::new();
+    let (gltf_skin, roots) = skel.push_gltf_skin(&mut builder, database.names());
+
+    let mut nodes = vec![builder.root.push(Node {
+        children: Some(roots),
+        ..gltf::node(database.names().name(skel_crc32))
+    })];
+
+    for (i, mesh_crc32) in skin.mesh_crc32s.iter().enumerate() {
+        let mesh = mesh::read(database, *mesh_crc32)?;
+        let palette = skin.palette(i, &skel);
+        let name = database.names().name(*mesh_crc32);
+        let gltf_mesh = mesh.push_gltf_mesh(&mut builder, database, name.clone(), Some(&palette))?;
+
+        // Skinned meshes ignore their node's transform so they can sit at the scene root
+        nodes.push(builder.root.push(Node {
+            mesh: Some(gltf_mesh),
+            skin: Some(gltf_skin),
+            ..gltf::node(name)
+        }));
+    }
+
+    builder.push_scene(nodes);
+
+    builder.write(output_path)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fuel_fmt::common::assert_round_trip;
+    use crate::fuel_fmt::skin::{SkinZ, SkinZAlt};
+
+    // One section of one subsection with data_count words
+    fn skin_sections_bytes(data_count: u32) -> Vec<u8> {
+        let mut bytes: Vec<u8> = data_count.to_le_bytes().to_vec();
+        bytes.extend(&1u32.to_le_bytes());
+        bytes.extend(&1u32.to_le_bytes());
+        bytes.extend((0..4 + data_count).flat_map(|x| x.to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn test_skin_data_count() {
+        let mut bytes: Vec<u8> = [1u32, 5, 0, 1, 2, 3].iter().flat_map(|x| x.to_le_bytes()).collect();
+        bytes.extend(&1.5f32.to_le_bytes());
+        bytes.extend(skin_sections_bytes(21));
+        assert_round_trip::<SkinZ>(&bytes);
+
+        let mut bytes: Vec<u8> = [1u32, 5, 0, 1].iter().flat_map(|x| x.to_le_bytes()).collect();
+        bytes.push(2);
+        bytes.extend(&1.5f32.to_le_bytes());
+        bytes.extend(skin_sections_bytes(3));
+        assert_round_trip::<SkinZAlt>(&bytes);
+    }
+}
@@ -5,11 +5,74 @@ use std::path::Path;
 use binwrite::BinWrite;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use nom_derive::{NomLE, Parse};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::fuel_fmt::common::{write_option, FUELObjectFormatTrait, HasReferences};
+use crate::fuel_fmt::common::{write_option, FUELObjectFormatTrait, FormatOptions, HasReferences};
 use crate::File;
 
+// Bit 0 is set in every sound_type seen (1, 3, 5 and 7) and is written back set. Bit 1 is looping.
+// Bit 2 hasn't been matched to a property. The channel count, streaming and compression aren't
+// decoded from any field, the data is read and written as 16-bit mono PCM.
+const SOUND_FLAG_SET: u16 = 0x1;
+const SOUND_FLAG_LOOPING: u16 = 0x2;
+const SOUND_FLAG_UNKNOWN4: u16 = 0x4;
+
+#[derive(BinWrite)]
+#[binwrite(little)]
+#[derive(NomLE)]
+struct SoundZFlags {
+    flags: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SoundZFlagsShadow {
+    looping: bool,
+    unknown4: bool,
+}
+
+// object.json files from before the flags were split out hold the raw sound_type
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SoundZFlagsJson {
+    Raw(u16),
+    Named(SoundZFlagsShadow),
+}
+
+impl Serialize for SoundZFlags {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        SoundZFlagsShadow {
+            looping: self.flags & SOUND_FLAG_LOOPING != 0,
+            unknown4: self.flags & SOUND_FLAG_UNKNOWN4 != 0,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SoundZFlags {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        let flags = match SoundZFlagsJson::deserialize(deserializer)? {
+            SoundZFlagsJson::Raw(flags) => flags & (SOUND_FLAG_LOOPING | SOUND_FLAG_UNKNOWN4),
+            SoundZFlagsJson::Named(shadow) => {
+                let mut flags = 0;
+                if shadow.looping {
+                    flags |= SOUND_FLAG_LOOPING;
+                }
+                if shadow.unknown4 {
+                    flags |= SOUND_FLAG_UNKNOWN4;
+                }
+                flags
+            }
+        };
+        Ok(SoundZFlags { flags: flags | SOUND_FLAG_SET })
+    }
+}
+
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
@@ -24,8 +87,8 @@ struct SoundZHeader {
     #[nom(Cond = "sample_rate != 0")]
     #[serde(skip_serializing_if = "Option::is_none")]
     #[binwrite(with(write_option))]
-    #[nom(Verify(*sound_type == 1 || *sound_type == 3 || *sound_type == 5 || *sound_type == 7))]
-    sound_type: Option<u16>,
+    #[nom(Verify(sound_type.flags & !(SOUND_FLAG_LOOPING | SOUND_FLAG_UNKNOWN4) == SOUND_FLAG_SET))]
+    sound_type: Option<SoundZFlags>,
     #[nom(Cond = "sample_rate != 0 && i.len() == 2")]
     #[serde(skip_serializing_if = "Option::is_none")]
     #[binwrite(with(write_option))]
@@ -42,6 +105,101 @@ impl HasReferences for SoundZHeader {
     }
 }
 
+// Samples are read as interleaved floats in [-1, 1) whatever the WAV's sample format
+fn read_wav(wav_path: &Path) -> Result<(hound::WavSpec, Vec<f32>), Error> {
+    let wav_error = |error: hound::Error| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Can't read {}: {}", wav_path.display(), error),
+        )
+    };
+
+    let mut reader = hound::WavReader::open(wav_path).map_err(wav_error)?;
+    let spec = reader.spec();
+
+    if spec.channels == 0 || spec.sample_rate == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} has no channels or no sample rate", wav_path.display()),
+        ));
+    }
+
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float if spec.bits_per_sample == 32 => reader
+            .samples::<f32>()
+            .collect::<Result<Vec<f32>, hound::Error>>()
+            .map_err(wav_error)?,
+        hound::SampleFormat::Int if spec.bits_per_sample <= 32 => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|x| x.map(|x| x as f32 / scale))
+                .collect::<Result<Vec<f32>, hound::Error>>()
+                .map_err(wav_error)?
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} uses {}-bit {:?} samples which can't be converted",
+                    wav_path.display(),
+                    spec.bits_per_sample,
+                    spec.sample_format
+                ),
+            ))
+        }
+    };
+
+    Ok((spec, samples))
+}
+
+// Mono targets get the average of every channel, wider targets the first channels or a duplicated mono
+fn map_channels(samples: Vec<f32>, from: u16, to: u16) -> Vec<f32> {
+    if from == to {
+        return samples;
+    }
+
+    let mut mapped: Vec<f32> = Vec::with_capacity(samples.len() / from as usize * to as usize);
+    for frame in samples.chunks_exact(from as usize) {
+        if to == 1 {
+            mapped.push(frame.iter().sum::<f32>() / from as f32);
+        } else {
+            for channel in 0..to as usize {
+                mapped.push(frame[channel.min(from as usize - 1)]);
+            }
+        }
+    }
+    mapped
+}
+
+// Linear interpolation is plenty for the game's sound effects
+fn resample(samples: Vec<f32>, channels: u16, from: u32, to: u32) -> Vec<f32> {
+    if from == to {
+        return samples;
+    }
+
+    let channels = channels as usize;
+    let frames = samples.len() / channels;
+    if frames == 0 {
+        return samples;
+    }
+
+    let resampled_frames = (frames as u64 * to as u64 / from as u64) as usize;
+    let mut resampled: Vec<f32> = Vec::with_capacity(resampled_frames * channels);
+    for frame in 0..resampled_frames {
+        let position = frame as f64 * from as f64 / to as f64;
+        let index = position as usize;
+        let next = (index + 1).min(frames - 1);
+        let t = (position - index as f64) as f32;
+        for channel in 0..channels {
+            let a = samples[index * channels + channel];
+            let b = samples[next * channels + channel];
+            resampled.push(a + (b - a) * t);
+        }
+    }
+    resampled
+}
+
 pub struct SoundObjectFormat;
 
 impl SoundObjectFormat {
@@ -56,26 +214,44 @@ impl FUELObjectFormatTrait for SoundObjectFormat {
         input_path: &Path,
         header: &mut Vec<u8>,
         body: &mut Vec<u8>,
+        options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = input_path.join("object.json");
         let json_file = File::open(json_path)?;
 
-        let wav_path = input_path.join("data.wav");
-        let mut reader = hound::WavReader::open(wav_path).unwrap();
-
         #[derive(Deserialize)]
         struct Object {
             sound_header: SoundZHeader,
         }
 
-        let object: Object = serde_json::from_reader(json_file)?;
+        let mut object: Object = serde_json::from_reader(json_file)?;
 
-        object.sound_header.write(header)?;
+        // No field is known to hold the channel count
+        let channels = 1;
+        let sample_rate = if object.sound_header.sample_rate != 0 {
+            object.sound_header.sample_rate
+        } else {
+            options.sound_sample_rate
+        };
+        if sample_rate == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Sound sample rate must not be 0"));
+        }
+
+        let (spec, samples) = read_wav(&input_path.join("data.wav"))?;
+        let samples = map_channels(samples, spec.channels, channels);
+        let samples = resample(samples, channels, spec.sample_rate, sample_rate);
+
+        for sample in samples.iter() {
+            let sample = (sample * 32768f32).round().clamp(-32768f32, 32767f32);
+            body.write_i16::<LittleEndian>(sample as i16)?;
+        }
 
-        for sample in reader.samples::<i16>() {
-            body.write_i16::<LittleEndian>(sample.unwrap())?;
+        if object.sound_header.data_size.is_some() {
+            object.sound_header.data_size = Some(body.len() as u32);
         }
 
+        object.sound_header.write(header)?;
+
         Ok((
             object.sound_header.hard_links(),
             object.sound_header.soft_links(),
@@ -87,6 +263,7 @@ impl FUELObjectFormatTrait for SoundObjectFormat {
         header: &[u8],
         body: &[u8],
         output_path: &Path,
+        options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = output_path.join("object.json");
         let mut output_file = File::create(json_path)?;
@@ -103,7 +280,7 @@ impl FUELObjectFormatTrait for SoundObjectFormat {
             sample_rate: if sound_header.sample_rate != 0 {
                 sound_header.sample_rate
             } else {
-                44100
+                options.sound_sample_rate
             },
             bits_per_sample: 16,
             sample_format: hound::SampleFormat::Int,
@@ -137,3 +314,82 @@ impl FUELObjectFormatTrait for SoundObjectFormat {
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+    use tempdir::TempDir;
+
+    use crate::fuel_fmt::sound::{map_channels, read_wav, resample, SoundZHeader};
+
+    fn header_bytes(sound_type: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&22050u32.to_le_bytes());
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(&sound_type.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_sound_flags_round_trip() {
+        for sound_type in [1u16, 3, 5, 7].iter() {
+            let (_, header) = SoundZHeader::parse(&header_bytes(*sound_type)).unwrap();
+            let json = serde_json::to_string(&header).unwrap();
+            let header: SoundZHeader = serde_json::from_str(&json).unwrap();
+            let mut written = Vec::new();
+            header.write(&mut written).unwrap();
+            assert_eq!(header_bytes(*sound_type), written);
+        }
+
+        assert!(SoundZHeader::parse(&header_bytes(2)).is_err());
+    }
+
+    #[test]
+    fn test_sound_flags_old_json() {
+        let json = r#"{"friendly_name_crc32":1,"sample_rate":22050,"data_size":4,"sound_type":3}"#;
+        let header: SoundZHeader = serde_json::from_str(json).unwrap();
+        let mut written = Vec::new();
+        header.write(&mut written).unwrap();
+        assert_eq!(header_bytes(3), written);
+    }
+
+    #[test]
+    fn test_sound_resample() {
+        let samples = vec![0.0, 1.0, 0.0, -1.0];
+        assert_eq!(resample(samples.clone(), 1, 100, 100), samples);
+        assert_eq!(resample(samples.clone(), 1, 100, 200), vec![0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -1.0]);
+        assert_eq!(resample(samples, 1, 200, 100), vec![0.0, 0.0]);
+        assert_eq!(resample(vec![0.0, 1.0, 1.0, 0.0], 2, 100, 200).len(), 8);
+    }
+
+    #[test]
+    fn test_sound_map_channels() {
+        assert_eq!(map_channels(vec![1.0, 0.0, 0.5, 0.5], 2, 1), vec![0.5, 0.5]);
+        assert_eq!(map_channels(vec![1.0, 0.5], 1, 2), vec![1.0, 1.0, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_sound_read_wav() {
+        let tmp_dir = TempDir::new("dpc").unwrap();
+        let wav_path = tmp_dir.path().join("data.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&wav_path, spec).unwrap();
+        for sample in [0i16, -32768, 16384, 32767].iter() {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (read_spec, samples) = read_wav(&wav_path).unwrap();
+        assert_eq!(read_spec, spec);
+        assert_eq!(samples, vec![0.0, -1.0, 0.5, 32767.0 / 32768.0]);
+
+        assert!(read_wav(&tmp_dir.path().join("missing.wav")).is_err());
+    }
+}
@@ -1,10 +1,21 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
 use binwrite::BinWrite;
+use glam::Vec3;
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
 
 use crate::fuel_fmt::common::{
-    FUELObjectFormat, FixedVec, HasReferences, ObjectZ, PascalArray, Vec3f,
+    FUELObjectFormat, FUELObjectFormatTrait, FixedVec, FormatOptions, HasReferences, ObjectZ,
+    PascalArray, Vec3f,
 };
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::File;
+
+const SPLINE_SUBSECTION_COUNT: usize = 8;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -15,6 +26,7 @@ struct SplineZSubsection {
     length: f32,
 }
 
+// A cubic Bezier from vertices[p1] to vertices[p2] with vertices[p1_t] and vertices[p2_t] as handles
 #[derive(BinWrite)]
 #[binwrite(little)]
 #[derive(Serialize, Deserialize, NomLE)]
@@ -25,7 +37,7 @@ struct SplineZSection {
     p2_t: u16,
     unknown0: u32,
     length: f32,
-    spline_subsections: FixedVec<SplineZSubsection, 8>,
+    spline_subsections: FixedVec<SplineZSubsection, SPLINE_SUBSECTION_COUNT>,
 }
 
 #[derive(BinWrite)]
@@ -52,4 +64,359 @@ impl HasReferences for SplineZ {
     }
 }
 
-pub type SplineObjectFormat = FUELObjectFormat<ObjectZ, SplineZ>;
+fn vec3f(v: Vec3) -> Vec3f {
+    FixedVec {
+        data: v.to_array().to_vec(),
+    }
+}
+
+fn bezier(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let u = 1f32 - t;
+    p0 * (u * u * u) + p1 * (3f32 * u * u * t) + p2 * (3f32 * u * t * t) + p3 * (t * t * t)
+}
+
+// A section as exported to control_points.json, the handles are kept so an unedited section packs unchanged
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct SplineZControlSection {
+    p1: [f32; 3],
+    p1_t: [f32; 3],
+    p2_t: [f32; 3],
+    p2: [f32; 3],
+    #[serde(default)]
+    unknown0: u32,
+}
+
+// The unknown floats aren't derived from the sections so they are carried along to be edited by hand
+#[derive(Serialize, Deserialize)]
+struct SplineZControlPoints {
+    sections: Vec<SplineZControlSection>,
+    unknown2: f32,
+    unknown3: f32,
+    unknown4: f32,
+    unknown5: f32,
+}
+
+fn subsections(section: &SplineZControlSection) -> Vec<SplineZSubsection> {
+    let [p0, p1, p2, p3] = [section.p1, section.p1_t, section.p2_t, section.p2].map(Vec3::from_array);
+    (0..SPLINE_SUBSECTION_COUNT)
+        .map(|j| {
+            let point1 = bezier(p0, p1, p2, p3, j as f32 / SPLINE_SUBSECTION_COUNT as f32);
+            let point2 = bezier(p0, p1, p2, p3, (j + 1) as f32 / SPLINE_SUBSECTION_COUNT as f32);
+            SplineZSubsection {
+                point1: vec3f(point1),
+                point2: vec3f(point2),
+                length: point1.distance(point2),
+            }
+        })
+        .collect()
+}
+
+impl SplineZ {
+    // The cached subsection samples, one polyline per section
+    fn polylines(&self) -> Vec<Vec<Vec3>> {
+        self.spline_sections
+            .data
+            .iter()
+            .map(|section| {
+                let subsections = &section.spline_subsections.data;
+                let mut polyline = vec![Vec3::from_slice(&subsections[0].point1.data)];
+                polyline.extend(subsections.iter().map(|x| Vec3::from_slice(&x.point2.data)));
+                polyline
+            })
+            .collect()
+    }
+
+    fn vertex(&self, i: u16) -> Result<[f32; 3]> {
+        match self.vertices.data.get(i as usize) {
+            Some(vertex) => Ok(Vec3::from_slice(&vertex.data).to_array()),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Spline section uses vertex {} of {}", i, self.vertices.data.len()),
+            )),
+        }
+    }
+
+    fn control_points(&self) -> Result<SplineZControlPoints> {
+        let sections = self
+            .spline_sections
+            .data
+            .iter()
+            .map(|section| {
+                Ok(SplineZControlSection {
+                    p1: self.vertex(section.p1)?,
+                    p1_t: self.vertex(section.p1_t)?,
+                    p2_t: self.vertex(section.p2_t)?,
+                    p2: self.vertex(section.p2)?,
+                    unknown0: section.unknown0,
+                })
+            })
+            .collect::<Result<Vec<SplineZControlSection>>>()?;
+
+        Ok(SplineZControlPoints {
+            sections,
+            unknown2: self.unknown2,
+            unknown3: self.unknown3,
+            unknown4: self.unknown4,
+            unknown5: self.unknown5,
+        })
+    }
+
+    // Unedited sections are kept as they are, edited ones reuse a vertex at the same position or add one
+    fn set_control_points(&mut self, control_points: SplineZControlPoints) -> Result<()> {
+        let old_control_sections = self.control_points()?.sections;
+        let edited = old_control_sections != control_points.sections;
+
+        let mut old_sections: Vec<Option<SplineZSection>> =
+            std::mem::take(&mut self.spline_sections.data).into_iter().map(Some).collect();
+        let mut vertices: Vec<[f32; 3]> = self
+            .vertices
+            .data
+            .iter()
+            .map(|x| Vec3::from_slice(&x.data).to_array())
+            .collect();
+        let mut index = |point: [f32; 3]| -> Result<u16> {
+            let bits = point.map(f32::to_bits);
+            let i = match vertices.iter().position(|x| x.map(f32::to_bits) == bits) {
+                Some(i) => i,
+                None => {
+                    vertices.push(point);
+                    vertices.len() - 1
+                }
+            };
+            u16::try_from(i).map_err(|_| Error::new(ErrorKind::InvalidData, "A spline has at most 65536 vertices"))
+        };
+
+        let mut sections: Vec<SplineZSection> = Vec::new();
+        for (i, section) in control_points.sections.iter().enumerate() {
+            if old_control_sections.get(i) == Some(section) {
+                if let Some(old_section) = old_sections[i].take() {
+                    sections.push(old_section);
+                    continue;
+                }
+            }
+
+            let spline_subsections = subsections(section);
+            sections.push(SplineZSection {
+                p1: index(section.p1)?,
+                p2: index(section.p2)?,
+                p1_t: index(section.p1_t)?,
+                p2_t: index(section.p2_t)?,
+                unknown0: section.unknown0,
+                length: spline_subsections.iter().map(|x| x.length).sum(),
+                spline_subsections: FixedVec { data: spline_subsections },
+            });
+        }
+
+        // Drop the vertices only the replaced sections used
+        let mut remap: Vec<Option<u16>> = vec![None; vertices.len()];
+        for section in sections.iter() {
+            for i in [section.p1, section.p2, section.p1_t, section.p2_t] {
+                remap[i as usize] = Some(0);
+            }
+        }
+        let mut used: Vec<[f32; 3]> = Vec::new();
+        for (i, vertex) in vertices.iter().enumerate() {
+            if remap[i].is_some() {
+                remap[i] = Some(used.len() as u16);
+                used.push(*vertex);
+            }
+        }
+        for section in sections.iter_mut() {
+            for i in [&mut section.p1, &mut section.p2, &mut section.p1_t, &mut section.p2_t] {
+                *i = remap[*i as usize].unwrap();
+            }
+        }
+
+        if edited {
+            self.length = sections.iter().map(|x| x.length).sum();
+        }
+        self.vertices.data = used.into_iter().map(|x| vec3f(Vec3::from_array(x))).collect();
+        self.spline_sections.data = sections;
+        self.unknown2 = control_points.unknown2;
+        self.unknown3 = control_points.unknown3;
+        self.unknown4 = control_points.unknown4;
+        self.unknown5 = control_points.unknown5;
+
+        Ok(())
+    }
+}
+
+fn write_obj(spline: &SplineZ, name: &str, output_path: &Path) -> Result<()> {
+    let mut obj = format!("o {}\n", name);
+    let mut index = 1;
+    for polyline in spline.polylines() {
+        for v in polyline.iter() {
+            obj.push_str(&format!("v {} {} {}\n", v.x, v.y, v.z));
+        }
+        let indices: Vec<String> = (index..index + polyline.len()).map(|x| x.to_string()).collect();
+        obj.push_str(&format!("l {}\n", indices.join(" ")));
+        index += polyline.len();
+    }
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(obj.as_bytes())?;
+    Ok(())
+}
+
+// Top-down view of the XZ plane, SVG's y axis points down so z is flipped
+fn write_svg(spline: &SplineZ, output_path: &Path) -> Result<()> {
+    let polylines = spline.polylines();
+
+    let mut min = Vec3::splat(f32::MAX);
+    let mut max = Vec3::splat(f32::MIN);
+    for v in polylines.iter().flatten() {
+        min = min.min(*v);
+        max = max.max(*v);
+    }
+    let size = (max - min).max(Vec3::ONE);
+    let stroke_width = size.x.max(size.z) / 500f32;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+        min.x, 0f32 - max.z, size.x, size.z
+    );
+    for polyline in polylines.iter() {
+        let points: Vec<String> = polyline.iter().map(|v| format!("{},{}", v.x, 0f32 - v.z)).collect();
+        svg.push_str(&format!(
+            "  <polyline points=\"{}\" fill=\"none\" stroke=\"black\" stroke-width=\"{}\"/>\n",
+            points.join(" "),
+            stroke_width
+        ));
+    }
+    svg.push_str("</svg>\n");
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(svg.as_bytes())?;
+    Ok(())
+}
+
+// .obj and .svg draw the cached samples, .json lists the sections read back on import
+pub fn export(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let (_, spline) = database.read::<ObjectZ, SplineZ>(crc32)?;
+
+    match output_path.extension().and_then(|x| x.to_str()) {
+        Some("obj") => write_obj(&spline, &database.names().name(crc32), output_path),
+        Some("svg") => write_svg(&spline, output_path),
+        Some("json") => {
+            let mut output_file = File::create(output_path)?;
+            output_file.write_all(serde_json::to_string_pretty(&spline.control_points()?)?.as_bytes())?;
+            Ok(())
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "spline exports are written as .obj, .svg or .json",
+        )),
+    }
+}
+
+pub struct SplineObjectFormat;
+
+impl SplineObjectFormat {
+    pub fn new<'a>() -> &'a Self {
+        &Self {}
+    }
+}
+
+impl FUELObjectFormatTrait for SplineObjectFormat {
+    fn pack(
+        &self,
+        input_path: &Path,
+        header: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        // control_points.json in the object directory replaces the sections in object.json
+        let points_path = input_path.join("control_points.json");
+        if !points_path.exists() {
+            return FUELObjectFormat::<ObjectZ, SplineZ>::new()
+                .pack(input_path, header, body, options);
+        }
+
+        FUELObjectFormat::<ObjectZ, SplineZ>::new().pack_with(input_path, header, body, |_, body| {
+            body.set_control_points(serde_json::from_str(&fs::read_to_string(points_path)?)?)
+        })
+    }
+
+    fn unpack(
+        &self,
+        header: &[u8],
+        body: &[u8],
+        output_path: &Path,
+        options: &FormatOptions,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        FUELObjectFormat::<ObjectZ, SplineZ>::new().unpack(header, body, output_path, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use binwrite::BinWrite;
+    use nom_derive::Parse;
+
+    use crate::fuel_fmt::spline::{SplineZ, SplineZControlPoints, SplineZControlSection};
+
+    fn spline() -> SplineZ {
+        let section = |p1: [f32; 3], p1_t: [f32; 3], p2_t: [f32; 3], p2: [f32; 3]| SplineZControlSection {
+            p1,
+            p1_t,
+            p2_t,
+            p2,
+            unknown0: 0,
+        };
+        let mut spline = SplineZ::parse(&[0u8; 28]).unwrap().1;
+        spline
+            .set_control_points(SplineZControlPoints {
+                sections: vec![
+                    section([0f32, 0f32, 0f32], [0.5f32, 0f32, 0f32], [0.5f32, -0.5f32, 0f32], [1f32, 0f32, 0f32]),
+                    section([1f32, 0f32, 0f32], [1.5f32, 0f32, 0f32], [2f32, 0.5f32, 0f32], [2f32, 1f32, 0f32]),
+                ],
+                unknown2: 1f32,
+                unknown3: 2f32,
+                unknown4: 3f32,
+                unknown5: 4f32,
+            })
+            .unwrap();
+        spline
+    }
+
+    fn bytes(spline: &SplineZ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        spline.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_spline_unedited_round_trip() {
+        let mut spline = spline();
+        assert_eq!(spline.vertices.data.len(), 7);
+        assert_eq!(spline.spline_sections.data[0].p2, spline.spline_sections.data[1].p1);
+
+        let original = bytes(&spline);
+        let control_points = spline.control_points().unwrap();
+        spline.set_control_points(control_points).unwrap();
+        assert_eq!(original, bytes(&spline));
+    }
+
+    #[test]
+    fn test_spline_edited_section() {
+        let mut spline = spline();
+        let mut control_points = spline.control_points().unwrap();
+        control_points.sections[1].p2_t = [3f32, 3f32, 0f32];
+        control_points.sections[1].p2 = [4f32, 3f32, 0f32];
+        control_points.unknown5 = 5f32;
+        spline.set_control_points(control_points).unwrap();
+
+        assert_eq!(spline.vertices.data.len(), 7);
+        assert_eq!(spline.spline_sections.data[0].p2, spline.spline_sections.data[1].p1);
+        assert_eq!(spline.spline_sections.data[1].spline_subsections.data[7].point2.data, vec![4f32, 3f32, 0f32]);
+        assert_eq!(spline.length, spline.spline_sections.data.iter().map(|x| x.length).sum::<f32>());
+        assert_eq!(spline.unknown5, 5f32);
+    }
+
+    #[test]
+    fn test_spline_bad_vertex() {
+        let mut spline = spline();
+        spline.spline_sections.data[1].p2_t = 7;
+        assert!(spline.control_points().is_err());
+    }
+}
@@ -3,7 +3,7 @@ use nom_derive::{NomLE, Parse};
 use serde::{Deserialize, Serialize};
 
 use crate::fuel_fmt::common::{
-    FUELObjectFormatTrait, HasReferences, PascalString, ResourceObjectZ,
+    FUELObjectFormatTrait, FormatOptions, HasReferences, PascalString, ResourceObjectZ,
 };
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::fs;
@@ -34,6 +34,7 @@ impl FUELObjectFormatTrait for UserDefineObjectFormat {
         input_path: &Path,
         header: &mut Vec<u8>,
         body: &mut Vec<u8>,
+        _options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = input_path.join("object.json");
         let json_file = File::open(json_path)?;
@@ -65,6 +66,7 @@ impl FUELObjectFormatTrait for UserDefineObjectFormat {
         header: &[u8],
         body: &[u8],
         output_path: &Path,
+        _options: &FormatOptions,
     ) -> Result<(Vec<u32>, Vec<u32>), Error> {
         let json_path = output_path.join("object.json");
         let mut output_file = File::create(json_path)?;
@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, HashMap};
+use std::f32::consts::PI;
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use glam::{Mat4, Quat, Vec3};
+use gltf_json::accessor::Type;
+use gltf_json::buffer::Target;
+use gltf_json::mesh::{Mode, Primitive, Semantic};
+use gltf_json::validation::Checked;
+use gltf_json::{Index, Mesh};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::fuel_fmt::common::{FixedVec, Mat4f, SphereZ};
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::gltf::{node, GltfBuilder};
+use crate::fuel_fmt::{collisionvol, lod, node};
+use crate::File;
+
+const CIRCLE_SEGMENTS: usize = 24;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeShape {
+    Box,
+    Sphere,
+}
+
+// A unit shape placed by its transform, the box spans -1 to 1 and the sphere has a radius of 1.
+// The transform is in the space of the node placing the object.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Volume {
+    pub name: String,
+    pub shape: VolumeShape,
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+    // CollisionVol_Z's raw type, what shape each value stands for isn't known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_type: Option<u32>,
+}
+
+impl Volume {
+    pub fn from_matrix(name: String, shape: VolumeShape, matrix: &Mat4f) -> Volume {
+        let (scale, rotation, translation) =
+            Mat4::from_cols_slice(&matrix.data).to_scale_rotation_translation();
+        Volume {
+            name,
+            shape,
+            translation: translation.to_array(),
+            rotation: rotation.to_array(),
+            scale: scale.to_array(),
+            volume_type: None,
+        }
+    }
+
+    pub fn from_sphere(name: String, sphere: &SphereZ) -> Volume {
+        Volume {
+            name,
+            shape: VolumeShape::Sphere,
+            translation: [sphere.center.data[0], sphere.center.data[1], sphere.center.data[2]],
+            rotation: Quat::IDENTITY.to_array(),
+            scale: [sphere.radius; 3],
+            volume_type: None,
+        }
+    }
+
+    fn same_transform(&self, other: &Volume) -> bool {
+        self.translation == other.translation && self.rotation == other.rotation && self.scale == other.scale
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(
+            Vec3::from(self.scale),
+            Quat::from_array(self.rotation).normalize(),
+            Vec3::from(self.translation),
+        )
+    }
+
+    // Decomposing and recomposing isn't exact so an unedited matrix is left alone,
+    // returns whether the matrix changed
+    pub fn apply_to_matrix(&self, matrix: &mut Mat4f) -> bool {
+        if self.same_transform(&Volume::from_matrix(self.name.clone(), self.shape, matrix)) {
+            return false;
+        }
+        matrix.data = self.matrix().to_cols_array().to_vec();
+        true
+    }
+
+    // Spheres only have a position and a radius, the largest scale is the radius
+    pub fn apply_to_sphere(&self, sphere: &mut SphereZ) {
+        if self.same_transform(&Volume::from_sphere(self.name.clone(), sphere)) {
+            return;
+        }
+        sphere.center = FixedVec {
+            data: self.translation.to_vec(),
+        };
+        sphere.radius = self.scale.iter().cloned().fold(0f32, f32::max);
+    }
+}
+
+// Appends a polyline along an arc of the circle spanned by u and v
+fn push_arc(
+    points: &mut Vec<Vec3>,
+    lines: &mut Vec<[u32; 2]>,
+    center: Vec3,
+    u: Vec3,
+    v: Vec3,
+    from: f32,
+    to: f32,
+) {
+    let segments = ((CIRCLE_SEGMENTS as f32 * (to - from).abs() / (2f32 * PI)).ceil() as usize).max(1);
+    let first = points.len() as u32;
+    for i in 0..=segments {
+        let angle = from + (to - from) * i as f32 / segments as f32;
+        points.push(center + u * angle.cos() + v * angle.sin());
+    }
+    for i in 0..segments as u32 {
+        lines.push([first + i, first + i + 1]);
+    }
+}
+
+// Line segments of the unit shape
+fn wireframe(shape: VolumeShape) -> (Vec<Vec3>, Vec<[u32; 2]>) {
+    let mut points: Vec<Vec3> = Vec::new();
+    let mut lines: Vec<[u32; 2]> = Vec::new();
+    match shape {
+        VolumeShape::Box => {
+            for i in 0..8 {
+                points.push(Vec3::new(
+                    if i & 1 != 0 { 1f32 } else { -1f32 },
+                    if i & 2 != 0 { 1f32 } else { -1f32 },
+                    if i & 4 != 0 { 1f32 } else { -1f32 },
+                ));
+            }
+            // Corners one bit apart share an edge
+            for i in 0..8u32 {
+                for bit in [1u32, 2, 4].iter() {
+                    if i & bit == 0 {
+                        lines.push([i, i | bit]);
+                    }
+                }
+            }
+        }
+        VolumeShape::Sphere => {
+            push_arc(&mut points, &mut lines, Vec3::ZERO, Vec3::X, Vec3::Y, 0f32, 2f32 * PI);
+            push_arc(&mut points, &mut lines, Vec3::ZERO, Vec3::Y, Vec3::Z, 0f32, 2f32 * PI);
+            push_arc(&mut points, &mut lines, Vec3::ZERO, Vec3::Z, Vec3::X, 0f32, 2f32 * PI);
+        }
+    }
+    (points, lines)
+}
+
+// An object's volumes placed in the world by one of its nodes
+struct PlacedVolumes {
+    name: String,
+    transform: Mat4,
+    volumes: Vec<Volume>,
+}
+
+fn write_obj(placed: &[PlacedVolumes], output_path: &Path) -> Result<()> {
+    let mut obj = String::new();
+    let mut index = 1;
+    for (placed, volume) in placed.iter().flat_map(|x| x.volumes.iter().map(move |y| (x, y))) {
+        let matrix = placed.transform * volume.matrix();
+        let (points, lines) = wireframe(volume.shape);
+        obj.push_str(&format!("o {}\n", volume.name));
+        if let Some(volume_type) = volume.volume_type {
+            obj.push_str(&format!("# volume_type {}\n", volume_type));
+        }
+        for point in points.iter() {
+            let v = matrix.transform_point3(*point);
+            obj.push_str(&format!("v {} {} {}\n", v.x, v.y, v.z));
+        }
+        for line in lines.iter() {
+            obj.push_str(&format!("l {} {}\n", index + line[0], index + line[1]));
+        }
+        index += points.len() as u32;
+    }
+
+    let mut output_file = File::create(output_path)?;
+    output_file.write_all(obj.as_bytes())?;
+    Ok(())
+}
+
+// One line mesh per shape, each volume is a node carrying its transform under a node placing its object
+fn write_gltf(placed: &[PlacedVolumes], output_path: &Path) -> Result<()> {
+    let mut builder = GltfBuilder::new();
+    let mut meshes: HashMap<VolumeShape, Index<Mesh>> = HashMap::new();
+    let mut object_nodes = Vec::new();
+    for placed in placed.iter() {
+        let mut nodes = Vec::new();
+        for volume in placed.volumes.iter() {
+            let mesh = match meshes.get(&volume.shape) {
+                Some(mesh) => *mesh,
+                None => {
+                    let (points, lines) = wireframe(volume.shape);
+                    let positions: Vec<f32> = points.iter().flat_map(|x| x.to_array()).collect();
+                    let indices: Vec<u32> = lines.iter().flatten().cloned().collect();
+
+                    let mut attributes = BTreeMap::new();
+                    attributes.insert(
+                        Checked::Valid(Semantic::Positions),
+                        builder.push_bounded_accessor(&positions, Type::Vec3, Some(Target::ArrayBuffer)),
+                    );
+                    let primitive = Primitive {
+                        attributes,
+                        extensions: None,
+                        extras: Default::default(),
+                        indices: Some(builder.push_accessor(
+                            &indices,
+                            Type::Scalar,
+                            Some(Target::ElementArrayBuffer),
+                        )),
+                        material: None,
+                        mode: Checked::Valid(Mode::Lines),
+                        targets: None,
+                    };
+                    let mesh = builder.root.push(Mesh {
+                        extensions: None,
+                        extras: Default::default(),
+                        name: Some(format!("{:?}", volume.shape).to_lowercase()),
+                        primitives: vec![primitive],
+                        weights: None,
+                    });
+                    meshes.insert(volume.shape, mesh);
+                    mesh
+                }
+            };
+
+            let (scale, rotation, translation) = volume.matrix().to_scale_rotation_translation();
+            let mut gltf_node = node(volume.name.clone());
+            gltf_node.mesh = Some(mesh);
+            gltf_node.translation = Some(translation.to_array());
+            gltf_node.rotation = Some(gltf_json::scene::UnitQuaternion(rotation.to_array()));
+            gltf_node.scale = Some(scale.to_array());
+            if let Some(volume_type) = volume.volume_type {
+                gltf_node.extras = Some(serde_json::value::to_raw_value(&json!({ "volume_type": volume_type }))?);
+            }
+            nodes.push(builder.root.push(gltf_node));
+        }
+
+        // Node world transforms have a uniform scale so they decompose
+        let (scale, rotation, translation) = placed.transform.to_scale_rotation_translation();
+        let mut object_node = node(placed.name.clone());
+        object_node.translation = Some(translation.to_array());
+        object_node.rotation = Some(gltf_json::scene::UnitQuaternion(rotation.to_array()));
+        object_node.scale = Some(scale.to_array());
+        object_node.children = Some(nodes);
+        object_nodes.push(builder.root.push(object_node));
+    }
+    builder.push_scene(object_nodes);
+    builder.write(output_path)
+}
+
+pub fn read_json(path: &Path) -> Result<Vec<Volume>> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn volumes(database: &ObjectDatabase, crc32: u32) -> Result<Vec<Volume>> {
+    match database.class(crc32) {
+        Some("CollisionVol_Z") => Ok(vec![collisionvol::volume(database, crc32)?]),
+        Some("Lod_Z") => lod::volumes(database, crc32),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a CollisionVol_Z or Lod_Z", crc32),
+        )),
+    }
+}
+
+// Every place the objects' nodes put them, an object no Node_Z places stays at the origin
+fn place(database: &ObjectDatabase, crc32s: &[u32]) -> Result<Vec<PlacedVolumes>> {
+    let transforms = node::object_world_transforms(database)?;
+    let mut placed = Vec::new();
+    for crc32 in crc32s.iter() {
+        let volumes = volumes(database, *crc32)?;
+        let object_transforms = match transforms.get(crc32) {
+            Some(object_transforms) => object_transforms.clone(),
+            None => {
+                println!("Warn: no Node_Z places {}, its volumes are written in its own space", crc32);
+                vec![Mat4::IDENTITY]
+            }
+        };
+        for transform in object_transforms {
+            placed.push(PlacedVolumes {
+                name: database.names().name(*crc32),
+                transform,
+                volumes: volumes.clone(),
+            });
+        }
+    }
+    Ok(placed)
+}
+
+// .obj and .gltf draw every object's volumes in world space, .json lists one object's volumes in its
+// node's space as read back on import
+pub fn export(database: &ObjectDatabase, crc32s: &[u32], output_path: &Path) -> Result<()> {
+    match output_path.extension().and_then(|x| x.to_str()) {
+        Some("obj") => write_obj(&place(database, crc32s)?, output_path),
+        Some("gltf") => write_gltf(&place(database, crc32s)?, output_path),
+        Some("json") if crc32s.len() == 1 => {
+            let mut output_file = File::create(output_path)?;
+            output_file.write_all(serde_json::to_string_pretty(&volumes(database, crc32s[0])?)?.as_bytes())?;
+            Ok(())
+        }
+        Some("json") => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "volume .json exports take a single crc32",
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "volume exports are written as .obj, .gltf or .json",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{Mat4, Quat, Vec3};
+
+    use crate::fuel_fmt::common::Mat4f;
+    use crate::fuel_fmt::volume::{Volume, VolumeShape};
+
+    #[test]
+    fn test_volume_apply_to_matrix() {
+        let matrix = Mat4::from_scale_rotation_translation(
+            Vec3::new(1f32, 2f32, 3f32),
+            Quat::from_rotation_y(0.3f32),
+            Vec3::new(4f32, 5f32, 6f32),
+        );
+        let mut mat = Mat4f { data: matrix.to_cols_array().to_vec() };
+        let volume = Volume {
+            volume_type: Some(7),
+            ..Volume::from_matrix("volume".to_string(), VolumeShape::Box, &mat)
+        };
+
+        let json = serde_json::to_string(&volume).unwrap();
+        let read: Volume = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.volume_type, Some(7));
+        assert!(!read.apply_to_matrix(&mut mat));
+
+        let moved = Volume { translation: [0f32; 3], ..read };
+        assert!(moved.apply_to_matrix(&mut mat));
+        assert_eq!(&mat.data[12..15], &[0f32; 3]);
+    }
+}
@@ -1,10 +1,16 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
 use binwrite::BinWrite;
 use nom_derive::NomLE;
 use serde::{Deserialize, Serialize};
 
 use crate::fuel_fmt::common::{
-    FUELObjectFormat, HasReferences, Mat4f, PascalArray, ResourceObjectZ,
+    FUELObjectFormat, HasReferences, Mat4f, ObjectZ, PascalArray, ResourceObjectZ,
 };
+use crate::fuel_fmt::database::ObjectDatabase;
+use crate::fuel_fmt::node::GltfSceneBuilder;
+use crate::fuel_fmt::worldref::WorldRefZ;
 
 #[derive(BinWrite)]
 #[binwrite(little)]
@@ -57,4 +63,37 @@ impl HasReferences for WorldZ {
     }
 }
 
+// Exports the node tree under a World_Z, a WorldRef_Z or a single Node_Z as a glTF scene
+pub fn export(database: &ObjectDatabase, crc32: u32, output_path: &Path) -> Result<()> {
+    let root_crc32s = match database.class(crc32) {
+        Some("World_Z") => {
+            let (_, world): (ResourceObjectZ, WorldZ) = database.read(crc32)?;
+            vec![world.node_crc32]
+        }
+        Some("WorldRef_Z") => {
+            let (_, world_ref): (ObjectZ, WorldRefZ) = database.read(crc32)?;
+            [&[world_ref.node_crc32][..], &world_ref.node_crc32s.data[..]].concat()
+        }
+        Some("Node_Z") => vec![crc32],
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} isn't a World_Z, WorldRef_Z or Node_Z", crc32),
+            ))
+        }
+    };
+
+    let mut scene_builder = GltfSceneBuilder::new(database)?;
+    let mut nodes = Vec::new();
+    for root_crc32 in root_crc32s {
+        if let Some(node) = scene_builder.push_node_tree(root_crc32)? {
+            nodes.push(node);
+        }
+    }
+
+    let mut builder = scene_builder.builder;
+    builder.push_scene(nodes);
+    builder.write(output_path)
+}
+
 pub type WorldObjectFormat = FUELObjectFormat<ResourceObjectZ, WorldZ>;
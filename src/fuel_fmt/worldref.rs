@@ -19,7 +19,7 @@ struct UUIDPair {
 #[derive(Serialize, Deserialize, NomLE)]
 #[nom(Exact)]
 pub struct WorldRefZ {
-    node_crc32: u32,
+    pub node_crc32: u32,
     warp_crc32: u32,
     game_obj_crc32: u32,
     unused14: u32,
@@ -32,7 +32,7 @@ pub struct WorldRefZ {
     point_b: Vec3f,
     uuid_pairs: PascalArray<UUIDPair>,
     init_script: PascalStringNULL,
-    node_crc32s: PascalArray<u32>,
+    pub node_crc32s: PascalArray<u32>,
     zero: u32,
 }
 
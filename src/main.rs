@@ -15,6 +15,7 @@ pub mod crc32;
 pub mod fuel_dpc;
 pub mod fuel_fmt;
 pub mod lz;
+pub mod names;
 
 #[allow(dead_code)]
 mod built_info {
@@ -121,6 +122,7 @@ fn main() -> Result<()> {
 		.arg(Arg::with_name("CUSTOM_ARGS")
 				.last(true)
 				.required(false)
+				.multiple(true)
 				.help("Supply arguments directly to the dpc backend"))
 		.subcommand(lz_subcommand.subcommand())
 		.subcommand(SubCommand::with_name("obj")
@@ -171,6 +173,83 @@ fn main() -> Result<()> {
 						.requires("INPUT")
 						.conflicts_with("CREATE")
 						.help("extract the file"))
+				.arg(Arg::with_name("CUSTOM_ARGS")
+						.last(true)
+						.required(false)
+						.multiple(true)
+						.help("Supply arguments directly to the dpc backend"))
+				.after_help("EXAMPLES:\n    fmt -e -g fuel -i 1234.Bitmap_Z -o 1234.Bitmap_Z.d -- --bitmap-png\n    fmt -c -g fuel -i BIKE.DPC.d/objects/1234_node.Node_Z.d -o BIKE.DPC.d/objects/1234_node.Node_Z -- --node-transforms\n    fmt -e -g fuel -i 1234.Mesh_Z -o 1234.Mesh_Z.d -- --probe-formats")
+				.settings(&[AppSettings::ArgRequiredElseHelp]))
+		.subcommand(SubCommand::with_name("export")
+				.about("Used to export objects from an extracted DPC")
+				.arg(Arg::with_name("GAME")
+						.short("g")
+						.long("game")
+						.takes_value(true)
+						.required(true)
+						.possible_values(&["fuel"])
+						.help("The game the extracted DPC is from"))
+				.arg(Arg::with_name("TYPE")
+						.short("t")
+						.long("type")
+						.takes_value(true)
+						.required(true)
+						.requires("INPUT")
+						.requires("OUTPUT")
+						.possible_values(&["skel", "skin", "animation", "font", "spline", "road", "regions", "categories", "world", "materialanim", "volumes"])
+						.help("The kind of export, the output extension picks the file format"))
+				.arg(Arg::with_name("CRC32")
+						.short("c")
+						.long("crc32")
+						.takes_value(true)
+						.required(true)
+						.multiple(true)
+						.number_of_values(1)
+						.help("The crc32 of the object to start from, animation .gltf exports also take the Skel_Z they target, volumes take any number of CollisionVol_Z and Lod_Z"))
+				.arg(Arg::with_name("NAMES")
+						.short("n")
+						.long("names")
+						.takes_value(true)
+						.multiple(true)
+						.number_of_values(1)
+						.help("Name file used to resolve crc32s"))
+				.after_help("EXAMPLES:\n    export -g fuel -t skel -c 1234 -i BIKE.DPC.d -o skel.gltf\n    export -g fuel -t skel -c 1234 -n names.txt -i BIKE.DPC.d -o skel.txt\n    export -g fuel -t skin -c 2345 -i BIKE.DPC.d -o character.gltf\n    export -g fuel -t animation -c 3456 -c 1234 -i BIKE.DPC.d -o animation.gltf\n    export -g fuel -t animation -c 3456 -i BIKE.DPC.d -o 3456.Animation_Z.d/keyframes.json\n    export -g fuel -t font -c 4567 -i BIKE.DPC.d -o 4567.Fonts_Z.d/font.fnt\n    export -g fuel -t spline -c 5678 -i BIKE.DPC.d -o 5678.Spline_Z.d/control_points.json\n    export -g fuel -t road -c 6789 -i BIKE.DPC.d -o roads.geojson\n    export -g fuel -t regions -c 7890 -i BIKE.DPC.d -o 7890.GenWorld_Z.d/regions.geojson\n    export -g fuel -t categories -c 7890 -n names.txt -i BIKE.DPC.d -o categories.json\n    export -g fuel -t world -c 8901 -n names.txt -i BIKE.DPC.d -o level.gltf\n    export -g fuel -t materialanim -c 9012 -i BIKE.DPC.d -o keyframes.csv\n    export -g fuel -t volumes -c 1357 -c 2468 -i BIKE.DPC.d -o volumes.obj\n    export -g fuel -t volumes -c 2468 -i BIKE.DPC.d -o 2468.Lod_Z.d/volumes.json\n\nNOTES:\n    A font.fnt in the Fonts_Z directory only updates the glyph rectangles when the font is packed, the\n    exported page PNGs are not read back. To replace a page save it as data.png in the directory of the\n    Bitmap_Z its material uses and delete the data.dds next to it.")
+				.settings(&[AppSettings::ArgRequiredElseHelp]))
+		.subcommand(SubCommand::with_name("font")
+				.about("Used to replace a font in an extracted DPC with one generated from a TrueType or OpenType font, adding its new material and bitmap to the manifest")
+				.arg(Arg::with_name("GAME")
+						.short("g")
+						.long("game")
+						.takes_value(true)
+						.required(true)
+						.possible_values(&["fuel"])
+						.help("The game the extracted DPC is from"))
+				.arg(Arg::with_name("CRC32")
+						.short("c")
+						.long("crc32")
+						.takes_value(true)
+						.required(true)
+						.requires("INPUT")
+						.requires("OUTPUT")
+						.help("The crc32 of the Fonts_Z to replace, its material and bitmap are used as templates"))
+				.arg(Arg::with_name("FONT")
+						.short("f")
+						.long("font")
+						.takes_value(true)
+						.required(true)
+						.help("The TrueType or OpenType font to rasterize, its file name names the new material and bitmap"))
+				.arg(Arg::with_name("SIZE")
+						.short("s")
+						.long("size")
+						.takes_value(true)
+						.default_value("32")
+						.help("The point size in pixels"))
+				.arg(Arg::with_name("CHARACTERS")
+						.short("C")
+						.long("characters")
+						.takes_value(true)
+						.help("UTF-8 text file with the characters to include, defaults to printable ASCII"))
+				.after_help("EXAMPLES:\n    font -g fuel -c 4567 -f DejaVuSans.ttf -C cyrillic.txt -i BIKE.DPC.d -o BIKE.DPC.d")
 				.settings(&[AppSettings::ArgRequiredElseHelp]))
 		.subcommand(crc32_subcommand.subcommand())
 		.after_help("EXAMPLES:\n    -g fuel -- -h\n    -cflO -g fuel -i BIKE.DPC.d -o BIKE.DPC\n    -ef -g fuel -i /FUEL/**/*.DPC")
@@ -238,6 +317,11 @@ fn main() -> Result<()> {
             }
         };
 
+        let custom_args: Vec<&OsStr> = match subcommand_matches.values_of_os("CUSTOM_ARGS") {
+            Some(args) => args.collect(),
+            None => vec![],
+        };
+
         let dpc = match subcommand_matches.value_of("GAME") {
             None => panic!("Game is required"), // default to fuel until other games are supported
             Some(game) => match game {
@@ -255,6 +339,76 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(subcommand_matches) = matches.subcommand_matches("export") {
+        let input_path = Path::new(matches.value_of_os("INPUT").unwrap());
+        let output_path = Path::new(matches.value_of_os("OUTPUT").unwrap());
+
+        let crc32s: Vec<u32> = subcommand_matches
+            .values_of("CRC32")
+            .unwrap()
+            .map(|x| match x.parse::<i64>() {
+                Ok(crc32) => crc32 as u32,
+                Err(_) => panic!("Bad crc32"),
+            })
+            .collect();
+
+        let name_paths: Vec<&OsStr> = match subcommand_matches.values_of_os("NAMES") {
+            Some(paths) => paths.collect(),
+            None => vec![],
+        };
+
+        let mut dpc = match subcommand_matches.value_of("GAME") {
+            None => panic!("Game is required"), // default to fuel until other games are supported
+            Some(game) => match game {
+                "fuel" => FuelDPC::new(&options, &custom_args),
+                _ => panic!("bad game"),
+            },
+        };
+
+        dpc.export(
+            &input_path,
+            &output_path,
+            subcommand_matches.value_of("TYPE").unwrap(),
+            &crc32s,
+            &name_paths,
+        )?;
+
+        return Ok(());
+    }
+
+    if let Some(subcommand_matches) = matches.subcommand_matches("font") {
+        let input_path = Path::new(matches.value_of_os("INPUT").unwrap());
+        let output_path = Path::new(matches.value_of_os("OUTPUT").unwrap());
+        let font_path = Path::new(subcommand_matches.value_of_os("FONT").unwrap());
+
+        let crc32 = match subcommand_matches.value_of("CRC32").unwrap().parse::<i64>() {
+            Ok(crc32) => crc32 as u32,
+            Err(_) => panic!("Bad crc32"),
+        };
+
+        let point_size = match subcommand_matches.value_of("SIZE").unwrap().parse::<f32>() {
+            Ok(point_size) if point_size > 0f32 => point_size,
+            _ => panic!("Bad size"),
+        };
+
+        let characters = match subcommand_matches.value_of_os("CHARACTERS") {
+            Some(characters_path) => std::fs::read_to_string(characters_path)?,
+            None => (' '..='~').collect(),
+        };
+
+        let mut dpc = match subcommand_matches.value_of("GAME") {
+            None => panic!("Game is required"), // default to fuel until other games are supported
+            Some(game) => match game {
+                "fuel" => FuelDPC::new(&options, &custom_args),
+                _ => panic!("bad game"),
+            },
+        };
+
+        dpc.font(&input_path, &output_path, crc32, &font_path, point_size, &characters)?;
+
+        return Ok(());
+    }
+
     let mut dpc = match matches.value_of("GAME") {
         None => panic!("Game is required"), // default to fuel until other games are supported
         Some(game) => match game {
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::path::Path;
+
+#[derive(Default)]
+pub struct NameDatabase {
+    names: HashMap<u32, String>,
+}
+
+impl NameDatabase {
+    pub fn new() -> NameDatabase {
+        NameDatabase::default()
+    }
+
+    // Name files use the same `crc32 "name"` lines the crc32 subcommand generates,
+    // with either signed or unsigned crc32s.
+    pub fn load<P: AsRef<Path>>(&mut self, path: &P) -> Result<()> {
+        let file = File::open(path)?;
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (hash, name) = match line.split_once(' ') {
+                Some(x) => x,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Bad name file line: {}", line),
+                    ))
+                }
+            };
+
+            let crc32 = match hash.parse::<i64>() {
+                Ok(x) => x as u32,
+                Err(_) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Bad name file line: {}", line),
+                    ))
+                }
+            };
+
+            self.insert(crc32, name.trim().trim_matches('"'));
+        }
+
+        Ok(())
+    }
+
+    pub fn insert(&mut self, crc32: u32, name: &str) {
+        self.names.insert(crc32, name.to_string());
+    }
+
+    pub fn get(&self, crc32: u32) -> Option<&str> {
+        self.names.get(&crc32).map(|x| x.as_str())
+    }
+
+    pub fn name(&self, crc32: u32) -> String {
+        match self.get(crc32) {
+            Some(name) => name.to_string(),
+            None => crc32.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use crate::names::NameDatabase;
+
+    #[test]
+    fn test_names_load() {
+        let tmp_dir = TempDir::new("dpc").unwrap();
+        let names_path = tmp_dir.path().join("names.txt");
+        fs::write(&names_path, "1 \"one\"\n\n-1 \"minus one\"\n4294967294 two\n").unwrap();
+
+        let mut names = NameDatabase::new();
+        names.load(&names_path).unwrap();
+        assert_eq!(names.get(1), Some("one"));
+        assert_eq!(names.get(u32::MAX), Some("minus one"));
+        assert_eq!(names.name(u32::MAX - 1), "two");
+        assert_eq!(names.name(2), "2");
+
+        fs::write(&names_path, "one \"one\"\n").unwrap();
+        assert!(names.load(&names_path).is_err());
+        fs::write(&names_path, "1\n").unwrap();
+        assert!(names.load(&names_path).is_err());
+    }
+}